@@ -0,0 +1,104 @@
+//! delays between attempts to establish the link
+//!
+//! Each failed attempt doubles the delay before the next one, up to a maximum,
+//! so that an end that is down for a while isn't flooded with connections.
+//! Each delay is also shortened by a random amount of up to half, so that the
+//! many ends that lost their links at once don't all try again in step. No
+//! delay is shorter than `MIN_DELAY`, even if configured as zero.
+
+use std::time::Duration;
+
+/// the shortest delay before an attempt, so that an end never retries in a
+/// tight loop
+pub const MIN_DELAY: Duration = Duration::from_millis(100);
+
+/// The delay before each attempt to establish a link.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        let initial = initial.max(MIN_DELAY);
+        let max = max.max(MIN_DELAY);
+        Self{ initial, max, next: initial.min(max) }
+    }
+
+    /// the delay before the next attempt, doubling the one after
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = self.next.checked_mul(2).unwrap_or(self.max).min(self.max);
+        delay.mul_f64(1.0 - jitter() / 2.0)
+    }
+
+    /// start over from the initial delay, once a link was established
+    pub fn reset(&mut self) {
+        self.next = self.initial.min(self.max);
+    }
+}
+
+/// a random fraction between 0 and 1, or 0 without a source of randomness
+fn jitter() -> f64 {
+    let mut bytes = [0u8; 4];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX),
+        Err(_) => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// the delays drawn one after the other, before jitter shortens them
+    fn undelayed(backoff: &mut Backoff, count: usize) -> Vec<Duration> {
+        (0..count).map(|_| {
+            let delay = backoff.next;
+            backoff.next_delay();
+            delay
+        }).collect()
+    }
+
+    #[test]
+    fn doubles_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(undelayed(&mut backoff, 6), [1, 2, 4, 8, 10, 10].iter().map(|&s| Duration::from_secs(s)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn never_overflows() {
+        let mut backoff = Backoff::new(Duration::MAX / 3, Duration::MAX);
+        assert_eq!(undelayed(&mut backoff, 3), [Duration::MAX / 3, Duration::MAX / 3 * 2, Duration::MAX]);
+    }
+
+    #[test]
+    fn starts_over_once_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        undelayed(&mut backoff, 3);
+        backoff.reset();
+        assert_eq!(undelayed(&mut backoff, 2), [Duration::from_secs(1), Duration::from_secs(2)]);
+    }
+
+    #[test]
+    fn waits_at_least_the_min_delay() {
+        let mut backoff = Backoff::new(Duration::ZERO, Duration::ZERO);
+        assert_eq!(undelayed(&mut backoff, 2), [MIN_DELAY, MIN_DELAY]);
+        let mut backoff = Backoff::new(Duration::ZERO, Duration::from_secs(1));
+        assert_eq!(undelayed(&mut backoff, 2), [MIN_DELAY, MIN_DELAY * 2]);
+    }
+
+    #[test]
+    fn jitter_shortens_by_up_to_half() {
+        let max = Duration::from_secs(8);
+        let mut backoff = Backoff::new(max, max);
+        for _ in 0..1000 {
+            let delay = backoff.next_delay();
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! compression of the proxy link
+//!
+//! Each end offers the codecs it is willing to use as capabilities, and when
+//! both share one, every frame sent over the link begins with a tag naming the
+//! codec its payload was compressed with. Frames that are too small to gain
+//! anything, or that don't shrink when compressed such as the content of
+//! `.crate` files, are sent as they are with [Codec::None]'s tag.
+//!
+//! Zstandard is preferred over deflate when both ends offer it.
+
+use std::{fmt, io::{self, Read, Write}, str::FromStr};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use crate::{hello::Capabilities, limits};
+
+/// The smallest payload worth compressing.
+const MIN_COMPRESSED_LENGTH: usize = 128;
+
+/// The compression level used with zstd, favoring speed.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The codecs available, in order of preference.
+const CODECS: &[Codec] = &[Codec::Zstd, Codec::Deflate];
+
+/// A way of compressing the payload of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Deflate,
+    Zstd,
+}
+
+/// unknown compression codec '{0}', expected zstd, deflate or none
+#[derive(Error, Display, Debug)]
+pub struct UnknownCodec(String);
+
+impl Codec {
+
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Deflate => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Deflate),
+            2 => Ok(Codec::Zstd),
+            tag => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression codec {}", tag))),
+        }
+    }
+
+    /// the capability offering the codec
+    pub fn capability(self) -> Capabilities {
+        match self {
+            Codec::None => Capabilities::NONE,
+            Codec::Deflate => Capabilities::DEFLATE,
+            Codec::Zstd => Capabilities::ZSTD,
+        }
+    }
+
+    /// the preferred codec among the agreed capabilities, if any
+    pub fn agreed(capabilities: Capabilities) -> Option<Self> {
+        CODECS.iter().copied().find(|codec| capabilities.contains(codec.capability()))
+    }
+
+    /// the capabilities offering every codec
+    pub fn all() -> Capabilities {
+        CODECS.iter().fold(Capabilities::NONE, |all, codec| all | codec.capability())
+    }
+
+    /// the capabilities offering the codecs in a comma separated list, such
+    /// as `zstd,deflate` or `none`
+    pub fn offered(names: &str) -> Result<Capabilities, UnknownCodec> {
+        names.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Capabilities::NONE, |offered, name| Ok(offered | name.parse::<Codec>()?.capability()))
+    }
+
+    fn compress(self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(payload.to_vec()),
+            Codec::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            },
+            Codec::Zstd => zstd::bulk::compress(payload, ZSTD_LEVEL),
+        }
+    }
+
+    /// decompress a payload of `T`, refusing to expand it beyond `limit`
+    fn decompress<T>(self, payload: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        let reader: Box<dyn Read + '_> = match self {
+            Codec::None => return Ok(payload.to_vec()),
+            Codec::Deflate => Box::new(flate2::read::DeflateDecoder::new(payload)),
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(payload)?),
+        };
+        reader.take(limit as u64 + 1).read_to_end(&mut decompressed)?;
+        limits::check::<T>(decompressed.len(), limit)?;
+        Ok(decompressed)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = UnknownCodec;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "none" => Ok(Codec::None),
+            "deflate" => Ok(Codec::Deflate),
+            "zstd" => Ok(Codec::Zstd),
+            name => Err(UnknownCodec(name.into())),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Deflate => write!(f, "deflate"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// tag a payload with the codec it was compressed with, leaving it as it is
+/// unless compressing it saves space
+pub(crate) fn encode(codec: Codec, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let compressed = match codec {
+        Codec::None => None,
+        _ if payload.len() < MIN_COMPRESSED_LENGTH => None,
+        codec => Some(codec.compress(payload)?).filter(|compressed| compressed.len() < payload.len() - payload.len() / 16),
+    };
+    let (codec, payload) = match &compressed {
+        Some(compressed) => (codec, compressed.as_slice()),
+        None => (Codec::None, payload),
+    };
+    let mut frame = Vec::with_capacity(1 + payload.len());
+    frame.push(codec.tag());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// restore a payload of `T` tagged by [encode], of up to `limit` bytes
+pub(crate) fn decode<T>(frame: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let (&tag, payload) = frame.split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty compressed frame"))?;
+    Codec::from_tag(tag)?.decompress::<T>(payload, limit)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::down_stream;
+
+    /// a payload that compresses well
+    fn text(length: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog. ".iter().copied().cycle().take(length).collect()
+    }
+
+    /// a payload that doesn't compress, like the content of a `.crate`
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..length).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn round_trip(codec: Codec, payload: &[u8]) -> (Codec, usize) {
+        let frame = encode(codec, payload).unwrap();
+        assert_eq!(decode::<down_stream::Message>(&frame, payload.len()).unwrap(), payload);
+        (Codec::from_tag(frame[0]).unwrap(), frame.len())
+    }
+
+    #[test]
+    fn round_trips_with_each_codec() {
+        let payload = text(64 * 1024);
+        for &codec in [Codec::Zstd, Codec::Deflate].iter() {
+            let (used, length) = round_trip(codec, &payload);
+            assert_eq!(used, codec);
+            assert!(length < payload.len() / 10, "{} compressed to {} bytes", codec, length);
+        }
+        assert_eq!(round_trip(Codec::None, &payload), (Codec::None, payload.len() + 1));
+    }
+
+    #[test]
+    fn sends_small_payloads_as_they_are() {
+        for &codec in [Codec::Zstd, Codec::Deflate].iter() {
+            assert_eq!(round_trip(codec, &text(MIN_COMPRESSED_LENGTH - 1)).0, Codec::None);
+            assert_eq!(round_trip(codec, &text(MIN_COMPRESSED_LENGTH)).0, codec);
+            assert_eq!(round_trip(codec, &[]).0, Codec::None);
+        }
+    }
+
+    #[test]
+    fn sends_incompressible_payloads_as_they_are() {
+        for &codec in [Codec::Zstd, Codec::Deflate].iter() {
+            let payload = noise(64 * 1024);
+            assert_eq!(round_trip(codec, &payload), (Codec::None, payload.len() + 1));
+        }
+    }
+
+    #[test]
+    fn refuses_to_expand_beyond_the_limit() {
+        let payload = vec![0u8; 1024 * 1024];
+        for &codec in [Codec::Zstd, Codec::Deflate].iter() {
+            let frame = encode(codec, &payload).unwrap();
+            assert!(frame.len() < payload.len() / 100);
+            let err = decode::<down_stream::Message>(&frame, payload.len() - 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.into_inner().unwrap().is::<limits::FrameTooLarge>());
+            assert_eq!(decode::<down_stream::Message>(&frame, payload.len()).unwrap().len(), payload.len());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(decode::<down_stream::Message>(&[], 1024).is_err());
+        assert!(decode::<down_stream::Message>(&[3, 0, 0], 1024).is_err());
+        assert!(decode::<down_stream::Message>(&[Codec::Zstd.tag(), 1, 2, 3], 1024).is_err());
+        assert!(decode::<down_stream::Message>(&[Codec::Deflate.tag(), 0xff, 0xff], 1024).is_err());
+    }
+
+    #[test]
+    fn negotiates_codecs() {
+        assert_eq!(Codec::offered("zstd, deflate").unwrap(), Capabilities::ZSTD | Capabilities::DEFLATE);
+        assert_eq!(Codec::offered("none").unwrap(), Capabilities::NONE);
+        assert_eq!(Codec::offered("").unwrap(), Capabilities::NONE);
+        assert!(Codec::offered("zstd,lz4").is_err());
+        assert_eq!(Codec::agreed(Codec::all()), Some(Codec::Zstd));
+        assert_eq!(Codec::agreed(Capabilities::DEFLATE | Capabilities::HEARTBEAT), Some(Codec::Deflate));
+        assert_eq!(Codec::agreed(Capabilities::HEARTBEAT), None);
+    }
+}
@@ -1,29 +1,37 @@
 //! PDU's for cpm tool talking to mirror
 
-use std::fmt;
 use serde::{Serialize,Deserialize};
 use thiserror::Error;
 use displaydoc::Display;
 
+use crate::package;
+
 
 #[derive(Error,Display,Serialize,Deserialize,Debug)]
 pub enum Error {
     /// The requested function is not implemented
     NotImplemented,
+    /// The checksum of {0} does not match the registry index
+    ChecksumMismatch(PackageId),
+    /// {0} is not listed in the registry index
+    NotInIndex(PackageId),
+    /// The mirror was unable to consult the registry index: {0}
+    IndexUnavailable(String),
+    /// The mirror failed to store the crate: {0}
+    StorageFailure(String),
+    /// {0} is not in the cache
+    NotCached(PackageId),
+    /// {0} belongs to the private registry
+    Reserved(PackageId),
+    /// {0}
+    InvalidPackage(package::Error),
+    /// No version of {0} is in the cache
+    NothingCached(String),
+    /// The mirror's publish token is required: {0}
+    Unauthorized(String),
 }
 
-/// Identifies a version of a package
-#[derive(Serialize,Deserialize,Debug)]
-pub struct PackageId{
-    pub name: String,
-    pub version: String,
-}
-
-impl fmt::Display for PackageId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}/{}", self.name, self.version)
-    }
-}
+pub use crate::package::PackageId;
 
 #[derive(Serialize,Deserialize,Debug)]
 pub enum Request {
@@ -32,6 +40,11 @@ pub enum Request {
 
     /// upload new crate version
     UploadCrate{package: PackageId, content: Vec<u8>},
+
+    /// pin or unpin a cached crate version, or every version of a crate if
+    /// no version is given, protecting it from eviction, presenting the
+    /// mirror's publish token
+    Pin{name: String, version: Option<String>, pinned: bool, token: Option<String>},
 }
 
 #[derive(Serialize,Deserialize,Debug)]
@@ -39,6 +52,7 @@ pub enum Response {
     /// the set of packages from the check request missing from the cache
     CheckMissing(Vec<PackageId>),
     UploadCrate,
+    Pin,
 }
 
 #[derive(Serialize,Deserialize,Debug)]
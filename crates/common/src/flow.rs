@@ -0,0 +1,17 @@
+//! flow control for the proxy link
+//!
+//! The proxy sends content in chunks of at most [MAX_CHUNK_LENGTH] bytes,
+//! taking turns between the sessions that have content waiting, so that a
+//! large download doesn't hold up the small ones sharing the link.
+//!
+//! When both ends agreed on [crate::hello::Capabilities::FLOW_CONTROL], each
+//! session may also only have [INITIAL_WINDOW] bytes of content in flight. The
+//! mirror grants more with [crate::up_stream::Message::Credit] as the content
+//! reaches its recipients, so a slow recipient holds up only its own session
+//! rather than the whole link.
+
+/// The bytes of content a session may send before it is granted any credit.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// The largest chunk of content sent at a time.
+pub const MAX_CHUNK_LENGTH: usize = 16 * 1024;
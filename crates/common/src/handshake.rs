@@ -0,0 +1,440 @@
+//! mutual authentication of the proxy link
+//!
+//! Before any PDU is exchanged, each end proves that it holds the pre-shared
+//! link key without revealing it. Both ends send a random challenge, then
+//! answer the other's challenge with an HMAC-SHA256 over both challenges keyed
+//! with the link key. Answers are labelled with the role of their sender so
+//! that one can't be reflected back at the end that sent it.
+//!
+//! Both challenges also yield a key for the session. Once the ends have
+//! greeted each other, each proves to the other that it saw the same
+//! greetings before acting on what they agreed, so that the protocol version
+//! and capabilities can't be altered on their way. Each end then derives a key
+//! for the frames it sends from the session key and the greetings. Every frame then
+//! carries an HMAC-SHA256 over its content and its position in the stream, so
+//! frames can't be forged, altered, reordered or replayed from another session
+//! by anyone without the link key, and an end whose greeting was altered on
+//! its way has its first frame refused.
+
+use std::io;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use crate::hello::Greetings;
+
+const CHALLENGE_LENGTH: usize = 32;
+const ANSWER_LENGTH: usize = 32;
+const CONFIRMATION_LENGTH: usize = 32;
+
+/// The length of the tag appended to each authenticated frame.
+pub const TAG_LENGTH: usize = 32;
+
+/// The end of the link performing the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Mirror,
+    Proxy,
+}
+
+impl Role {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Role::Mirror => b"cpm-link mirror",
+            Role::Proxy => b"cpm-link proxy",
+        }
+    }
+
+    fn confirmation_label(self) -> &'static [u8] {
+        match self {
+            Role::Mirror => b"cpm-link mirror greetings",
+            Role::Proxy => b"cpm-link proxy greetings",
+        }
+    }
+
+    fn frame_label(self) -> &'static [u8] {
+        match self {
+            Role::Mirror => b"cpm-link mirror frames",
+            Role::Proxy => b"cpm-link proxy frames",
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            Role::Mirror => Role::Proxy,
+            Role::Proxy => Role::Mirror,
+        }
+    }
+}
+
+/// An error that can occur while authenticating the link.
+#[derive(Error, Display, Debug)]
+pub enum Error {
+    /// the peer failed to prove it holds the link key
+    Rejected,
+    /// the peer does not authenticate frames, it is likely older than this end
+    UnauthenticatedFrames,
+    /// the peer saw different greetings, they were altered on their way
+    Tampered,
+    /// IO error during the handshake: {0}
+    Io(#[from] io::Error),
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC to accept keys of any length")
+}
+
+/// the answer of `role` to the challenge of its peer
+fn answer(key: &[u8], role: Role, challenge: &[u8], own_challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac = hmac(key);
+    mac.update(role.label());
+    mac.update(challenge);
+    mac.update(own_challenge);
+    mac
+}
+
+/// A secret shared by both ends of an authenticated link, and by no other
+/// link.
+pub struct SessionKey([u8; 32]);
+
+impl SessionKey {
+
+    fn derive(key: &[u8], mirror_challenge: &[u8], proxy_challenge: &[u8]) -> Self {
+        let mut mac = hmac(key);
+        mac.update(b"cpm-link session");
+        mac.update(mirror_challenge);
+        mac.update(proxy_challenge);
+        Self(mac.finalize().into_bytes().into())
+    }
+
+    /// the authenticators of the frames `role` sends and receives, in that
+    /// order, keyed with everything sent over the link since authenticating
+    pub fn frame_macs(&self, role: Role, greetings: &Greetings) -> (FrameMac, FrameMac) {
+        let transcript = self.transcript(role, greetings);
+        (FrameMac::new(&transcript, role), FrameMac::new(&transcript, role.peer()))
+    }
+
+    /// the key of the session once the greetings are folded into it, the
+    /// mirror's first
+    fn transcript(&self, role: Role, greetings: &Greetings) -> Self {
+        let (mirror, proxy) = match role {
+            Role::Mirror => (&greetings.sent, &greetings.received),
+            Role::Proxy => (&greetings.received, &greetings.sent),
+        };
+        let mut mac = hmac(&self.0);
+        mac.update(b"cpm-link transcript");
+        for greeting in [mirror, proxy] {
+            mac.update(&(greeting.len() as u64).to_be_bytes());
+            mac.update(greeting);
+        }
+        Self(mac.finalize().into_bytes().into())
+    }
+}
+
+/// Authenticates the frames sent by one end of the link, which must be
+/// sealed and opened in the same order.
+pub struct FrameMac {
+    key: [u8; 32],
+    /// the position of the next frame in the stream
+    sequence: u64,
+}
+
+impl FrameMac {
+
+    fn new(session: &SessionKey, sender: Role) -> Self {
+        let mut mac = hmac(&session.0);
+        mac.update(sender.frame_label());
+        Self{ key: mac.finalize().into_bytes().into(), sequence: 0 }
+    }
+
+    fn next(&mut self, frame: &[u8]) -> Hmac<Sha256> {
+        let mut mac = hmac(&self.key);
+        mac.update(&self.sequence.to_be_bytes());
+        mac.update(frame);
+        self.sequence += 1;
+        mac
+    }
+
+    /// the tag to send after the next frame
+    pub fn seal(&mut self, frame: &[u8]) -> [u8; TAG_LENGTH] {
+        self.next(frame).finalize().into_bytes().into()
+    }
+
+    /// check the tag received after the next frame
+    pub fn open(&mut self, frame: &[u8], tag: &[u8]) -> io::Result<()> {
+        self.next(frame).verify(tag)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "a frame failed authentication"))
+    }
+}
+
+/// Prove to the peer that this end holds the link key, and check that the
+/// peer does too, settling on a key for the session.
+pub async fn authenticate<S>(stream: &mut S, key: &[u8], role: Role) -> Result<SessionKey, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut challenge = [0u8; CHALLENGE_LENGTH];
+    getrandom::getrandom(&mut challenge).map_err(io::Error::other)?;
+
+    stream.write_all(&challenge).await?;
+    stream.flush().await?;
+
+    let mut peer_challenge = [0u8; CHALLENGE_LENGTH];
+    stream.read_exact(&mut peer_challenge).await?;
+
+    // a peer echoing our challenge could hope to echo our answer too
+    if peer_challenge == challenge {
+        return Err(Error::Rejected);
+    }
+
+    let our_answer = answer(key, role, &peer_challenge, &challenge).finalize().into_bytes();
+    stream.write_all(&our_answer).await?;
+    stream.flush().await?;
+
+    let mut peer_answer = [0u8; ANSWER_LENGTH];
+    stream.read_exact(&mut peer_answer).await?;
+
+    answer(key, role.peer(), &challenge, &peer_challenge)
+        .verify(&peer_answer)
+        .map_err(|_| Error::Rejected)?;
+
+    Ok(match role {
+        Role::Mirror => SessionKey::derive(key, &challenge, &peer_challenge),
+        Role::Proxy => SessionKey::derive(key, &peer_challenge, &challenge),
+    })
+}
+
+/// Prove to the peer that this end saw the same greetings as it did, and
+/// check that the peer did too, before acting on what they agreed.
+pub async fn confirm<S>(stream: &mut S, session: &SessionKey, role: Role, greetings: &Greetings) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let transcript = session.transcript(role, greetings);
+    let confirmation = |role: Role| {
+        let mut mac = hmac(&transcript.0);
+        mac.update(role.confirmation_label());
+        mac
+    };
+
+    stream.write_all(&confirmation(role).finalize().into_bytes()).await?;
+    stream.flush().await?;
+
+    let mut peer_confirmation = [0u8; CONFIRMATION_LENGTH];
+    stream.read_exact(&mut peer_confirmation).await?;
+
+    confirmation(role.peer())
+        .verify(&peer_confirmation)
+        .map_err(|_| Error::Tampered)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use tokio::io::duplex;
+
+    const KEY: &[u8] = b"correct horse battery staple";
+
+    /// the greetings as seen by the mirror, and the same as seen by the proxy
+    fn greetings() -> (Greetings, Greetings) {
+        let (mirror, proxy) = (b"mirror hello".to_vec(), b"proxy hello".to_vec());
+        (Greetings{ sent: mirror.clone(), received: proxy.clone() }, Greetings{ sent: proxy, received: mirror })
+    }
+
+    async fn authenticate_both(mirror: (&[u8], Role), proxy: (&[u8], Role)) -> (Result<SessionKey, Error>, Result<SessionKey, Error>) {
+        let (mut a, mut b) = duplex(1024);
+        tokio::join!(authenticate(&mut a, mirror.0, mirror.1), authenticate(&mut b, proxy.0, proxy.1))
+    }
+
+    #[tokio::test]
+    async fn accepts_the_right_key() {
+        let (mirror, proxy) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Proxy)).await;
+        let (mirror, proxy) = (mirror.unwrap(), proxy.unwrap());
+        assert_eq!(mirror.0, proxy.0);
+
+        let (mirror_greetings, proxy_greetings) = greetings();
+        let (mut mirror_sent, mut mirror_received) = mirror.frame_macs(Role::Mirror, &mirror_greetings);
+        let (mut proxy_sent, mut proxy_received) = proxy.frame_macs(Role::Proxy, &proxy_greetings);
+        for frame in [&b"request"[..], b"", b"another"].iter() {
+            proxy_received.open(frame, &mirror_sent.seal(frame)).unwrap();
+            mirror_received.open(frame, &proxy_sent.seal(frame)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_key() {
+        let (mirror, proxy) = authenticate_both((KEY, Role::Mirror), (b"wrong", Role::Proxy)).await;
+        assert!(matches!(mirror, Err(Error::Rejected)));
+        assert!(matches!(proxy, Err(Error::Rejected)));
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_role() {
+        let (a, b) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Mirror)).await;
+        assert!(matches!(a, Err(Error::Rejected)));
+        assert!(matches!(b, Err(Error::Rejected)));
+        let (a, b) = authenticate_both((KEY, Role::Proxy), (KEY, Role::Proxy)).await;
+        assert!(matches!(a, Err(Error::Rejected)));
+        assert!(matches!(b, Err(Error::Rejected)));
+    }
+
+    /// an impostor without the key, sending `challenge` and `answer` whatever
+    /// it's sent, returns what it was sent
+    async fn impostor(stream: &mut tokio::io::DuplexStream, challenge: impl FnOnce(&[u8]) -> Vec<u8>, answer: &[u8]) -> Vec<u8> {
+        let mut received = [0u8; CHALLENGE_LENGTH];
+        stream.read_exact(&mut received).await.unwrap();
+        stream.write_all(&challenge(&received)).await.unwrap();
+        // the end being impersonated may already have given up
+        let _ = stream.write_all(answer).await;
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest).await;
+        [&received[..], &rest].concat()
+    }
+
+    /// the mirror's end of a handshake, closed once it's done
+    async fn mirror(mut stream: tokio::io::DuplexStream) -> Result<SessionKey, Error> {
+        authenticate(&mut stream, KEY, Role::Mirror).await
+    }
+
+    #[tokio::test]
+    async fn rejects_a_replayed_answer() {
+        // eavesdrop on a successful handshake, recording what the proxy sends
+        let (mut proxy_end, relay_end) = duplex(1024);
+        let (tap_end, mirror_end) = duplex(1024);
+        let ((mut from_proxy, mut to_proxy), (mut from_mirror, mut to_mirror)) = (tokio::io::split(relay_end), tokio::io::split(tap_end));
+        let mut seen = [0u8; CHALLENGE_LENGTH + ANSWER_LENGTH];
+        let eavesdrop = async {
+            from_proxy.read_exact(&mut seen[..CHALLENGE_LENGTH]).await.unwrap();
+            to_mirror.write_all(&seen[..CHALLENGE_LENGTH]).await.unwrap();
+            from_proxy.read_exact(&mut seen[CHALLENGE_LENGTH..]).await.unwrap();
+            to_mirror.write_all(&seen[CHALLENGE_LENGTH..]).await.unwrap();
+        };
+        let relay = async {
+            let mut buffer = [0u8; CHALLENGE_LENGTH + ANSWER_LENGTH];
+            from_mirror.read_exact(&mut buffer).await.unwrap();
+            to_proxy.write_all(&buffer).await.unwrap();
+        };
+        let (mirror_result, proxy_result, _, _) = tokio::join!(mirror(mirror_end), authenticate(&mut proxy_end, KEY, Role::Proxy), eavesdrop, relay);
+        assert!(mirror_result.is_ok() && proxy_result.is_ok());
+
+        // and replay it to the mirror
+        let (mirror_end, mut impostor_end) = duplex(1024);
+        let (challenge, answer) = seen.split_at(CHALLENGE_LENGTH);
+        let (mirror_result, _) = tokio::join!(
+            mirror(mirror_end),
+            impostor(&mut impostor_end, |_| challenge.to_vec(), answer),
+        );
+        assert!(matches!(mirror_result, Err(Error::Rejected)));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_reflected_challenge() {
+        let (mirror_end, mut impostor_end) = duplex(1024);
+        let (mirror_result, sent) = tokio::join!(
+            mirror(mirror_end),
+            impostor(&mut impostor_end, |challenge| challenge.to_vec(), &[0; ANSWER_LENGTH]),
+        );
+        assert!(matches!(mirror_result, Err(Error::Rejected)));
+        // without revealing an answer to its own challenge
+        assert_eq!(sent.len(), CHALLENGE_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn rejects_altered_reordered_and_replayed_frames() {
+        let (mirror, proxy) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Proxy)).await;
+        let (mirror_greetings, greetings) = greetings();
+        let (mut sent, _) = mirror.unwrap().frame_macs(Role::Mirror, &mirror_greetings);
+        let proxy = proxy.unwrap();
+
+        let first = sent.seal(b"first");
+        let second = sent.seal(b"second");
+
+        let (_, mut received) = proxy.frame_macs(Role::Proxy, &greetings);
+        assert!(received.open(b"First", &first).is_err());
+
+        let (_, mut received) = proxy.frame_macs(Role::Proxy, &greetings);
+        assert!(received.open(b"second", &second).is_err());
+
+        let (_, mut received) = proxy.frame_macs(Role::Proxy, &greetings);
+        received.open(b"first", &first).unwrap();
+        assert!(received.open(b"first", &first).is_err());
+
+        // nor is a frame accepted from the end that sent it, or another session
+        let (_, mut reflected) = proxy.frame_macs(Role::Mirror, &mirror_greetings);
+        assert!(reflected.open(b"first", &first).is_err());
+        let (other, _) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Proxy)).await;
+        let (_, mut other) = other.unwrap().frame_macs(Role::Proxy, &greetings);
+        assert!(other.open(b"first", &first).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_after_altered_greetings() {
+        let (mirror, proxy) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Proxy)).await;
+        let (mirror, proxy) = (mirror.unwrap(), proxy.unwrap());
+        let (mirror_greetings, proxy_greetings) = greetings();
+        let (mut sent, _) = mirror.frame_macs(Role::Mirror, &mirror_greetings);
+        let tag = sent.seal(b"first");
+
+        // either greeting being altered on its way, the two being swapped, or
+        // the boundary between them moved
+        let altered = [
+            Greetings{ received: b"mirror hellO".to_vec(), ..proxy_greetings.clone() },
+            Greetings{ sent: b"proxy hellO".to_vec(), ..proxy_greetings.clone() },
+            Greetings{ sent: proxy_greetings.received.clone(), received: proxy_greetings.sent.clone() },
+            Greetings{ sent: b"roxy hello".to_vec(), received: b"mirror hellop".to_vec() },
+        ];
+        for greetings in altered.iter() {
+            let (_, mut received) = proxy.frame_macs(Role::Proxy, greetings);
+            assert!(received.open(b"first", &tag).is_err(), "{:?}", greetings);
+        }
+
+        let (_, mut received) = proxy.frame_macs(Role::Proxy, &proxy_greetings);
+        received.open(b"first", &tag).unwrap();
+    }
+
+    #[tokio::test]
+    async fn confirms_the_same_greetings() {
+        let (mirror, proxy) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Proxy)).await;
+        let (mirror, proxy) = (mirror.unwrap(), proxy.unwrap());
+        let (mirror_greetings, proxy_greetings) = greetings();
+
+        let (mut a, mut b) = duplex(1024);
+        let (mirror_result, proxy_result) = tokio::join!(
+            confirm(&mut a, &mirror, Role::Mirror, &mirror_greetings),
+            confirm(&mut b, &proxy, Role::Proxy, &proxy_greetings),
+        );
+        mirror_result.unwrap();
+        proxy_result.unwrap();
+
+        let altered = Greetings{ received: b"mirror hellO".to_vec(), ..proxy_greetings };
+        let (mut a, mut b) = duplex(1024);
+        let (mirror_result, proxy_result) = tokio::join!(
+            confirm(&mut a, &mirror, Role::Mirror, &mirror_greetings),
+            confirm(&mut b, &proxy, Role::Proxy, &altered),
+        );
+        assert!(matches!(mirror_result, Err(Error::Tampered)));
+        assert!(matches!(proxy_result, Err(Error::Tampered)));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_reflected_confirmation() {
+        let (mirror, _) = authenticate_both((KEY, Role::Mirror), (KEY, Role::Proxy)).await;
+        let mirror = mirror.unwrap();
+        let (mirror_greetings, _) = greetings();
+        let (mut a, b) = duplex(1024);
+        let (mut from_mirror, mut to_mirror) = tokio::io::split(b);
+        let reflect = async {
+            let mut confirmation = [0u8; CONFIRMATION_LENGTH];
+            from_mirror.read_exact(&mut confirmation).await.unwrap();
+            to_mirror.write_all(&confirmation).await.unwrap();
+        };
+        let (result, _) = tokio::join!(confirm(&mut a, &mirror, Role::Mirror, &mirror_greetings), reflect);
+        assert!(matches!(result, Err(Error::Tampered)));
+    }
+}
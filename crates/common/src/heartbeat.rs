@@ -0,0 +1,220 @@
+//! dead link detection for the proxy link
+//!
+//! Each end pings the other at a regular interval and answers the other's
+//! pings. Hearing anything at all from the peer shows that the link is alive,
+//! so a pong stuck behind a long download doesn't condemn a busy link. A link
+//! that has been silent for the configured number of intervals is dead, and
+//! is torn down so that it can be re-established.
+//!
+//! Heartbeats are only sent when both ends agreed on
+//! [crate::hello::Capabilities::HEARTBEAT].
+
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+/// The smallest change in round trip time worth reporting.
+const NOTABLE_CHANGE: Duration = Duration::from_millis(10);
+
+/// How often to ping the peer, and how many intervals of silence mean the
+/// link is dead.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub interval: Duration,
+    pub misses: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(15), misses: 3 }
+    }
+}
+
+/// An illegal heartbeat setting.
+#[derive(Error, Display, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// `{0}` is not a whole number
+    NotANumber(String),
+    /// the interval between pings must be at least one second
+    ZeroInterval,
+    /// the link can only be dead after at least one interval of silence
+    NoMisses,
+}
+
+impl Config {
+
+    /// the interval between pings, in whole seconds
+    pub fn parse_interval(seconds: &str) -> Result<Duration, Error> {
+        match seconds.parse() {
+            Ok(0) => Err(Error::ZeroInterval),
+            Ok(seconds) => Ok(Duration::from_secs(seconds)),
+            Err(_) => Err(Error::NotANumber(seconds.into())),
+        }
+    }
+
+    /// the number of silent intervals after which the link is dead
+    pub fn parse_misses(misses: &str) -> Result<u32, Error> {
+        match misses.parse() {
+            Ok(0) => Err(Error::NoMisses),
+            Ok(misses) => Ok(misses),
+            Err(_) => Err(Error::NotANumber(misses.into())),
+        }
+    }
+}
+
+/// The heartbeat of one end of a link.
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: Config,
+    last_ping: u64,
+    /// the most recent ping and when it was sent
+    pending: Option<(u64, Instant)>,
+    last_heard: Instant,
+    /// the round trip time last worth reporting
+    reported: Option<Duration>,
+}
+
+impl Heartbeat {
+
+    pub fn new(config: Config) -> Self {
+        Self { config, last_ping: 0, pending: None, last_heard: Instant::now(), reported: None }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// how long the link may be silent before it is considered dead
+    pub fn deadline(&self) -> Duration {
+        self.config.interval * self.config.misses
+    }
+
+    /// note that something was received from the peer
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    /// begin the next interval, returning the id of the ping to send, or
+    /// `None` if the peer has been silent for too long
+    pub fn tick(&mut self) -> Option<u64> {
+        if self.last_heard.elapsed() > self.deadline() {
+            return None;
+        }
+        self.last_ping += 1;
+        self.pending = Some((self.last_ping, Instant::now()));
+        Some(self.last_ping)
+    }
+
+    /// note a pong from the peer, returning the round trip time if it answers
+    /// the most recent ping, and whether it changed enough to be worth
+    /// reporting
+    pub fn pong(&mut self, id: u64) -> Option<(Duration, bool)> {
+        self.heard();
+        let (_, sent) = self.pending.take().filter(|(ping, _)| *ping == id)?;
+        let rtt = sent.elapsed();
+        let notable = match self.reported {
+            Some(reported) => rtt.abs_diff(reported) > NOTABLE_CHANGE && (rtt > reported * 2 || rtt * 2 < reported),
+            None => true,
+        };
+        if notable {
+            self.reported = Some(rtt);
+        }
+        Some((rtt, notable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const CONFIG: Config = Config { interval: Duration::from_secs(15), misses: 3 };
+
+    /// a heartbeat that last heard from the peer `ago`
+    fn silent_for(ago: Duration) -> Heartbeat {
+        let mut heartbeat = Heartbeat::new(CONFIG);
+        heartbeat.last_heard = Instant::now().checked_sub(ago).unwrap();
+        heartbeat
+    }
+
+    #[test]
+    fn pings_while_the_peer_is_heard() {
+        let mut heartbeat = silent_for(Duration::from_secs(44));
+        assert_eq!(heartbeat.deadline(), Duration::from_secs(45));
+        assert_eq!(heartbeat.tick(), Some(1));
+        assert_eq!(heartbeat.tick(), Some(2));
+    }
+
+    #[test]
+    fn gives_up_past_the_deadline() {
+        let mut heartbeat = silent_for(Duration::from_secs(46));
+        assert_eq!(heartbeat.tick(), None);
+        assert_eq!(heartbeat.tick(), None);
+        // anything heard from the peer revives the link
+        heartbeat.heard();
+        assert_eq!(heartbeat.tick(), Some(1));
+    }
+
+    #[test]
+    fn a_single_miss_gives_up_after_one_silent_interval() {
+        let config = Config{ misses: 1, ..CONFIG };
+        let mut heartbeat = Heartbeat::new(config);
+        assert_eq!(heartbeat.deadline(), CONFIG.interval);
+        heartbeat.last_heard = Instant::now().checked_sub(Duration::from_secs(14)).unwrap();
+        assert_eq!(heartbeat.tick(), Some(1));
+        heartbeat.last_heard = Instant::now().checked_sub(Duration::from_secs(16)).unwrap();
+        assert_eq!(heartbeat.tick(), None);
+    }
+
+    #[test]
+    fn refuses_zero_intervals_and_misses() {
+        assert_eq!(Config::parse_interval("15"), Ok(Duration::from_secs(15)));
+        assert_eq!(Config::parse_interval("1"), Ok(Duration::from_secs(1)));
+        assert_eq!(Config::parse_interval("0"), Err(Error::ZeroInterval));
+        assert_eq!(Config::parse_interval("00"), Err(Error::ZeroInterval));
+        assert_eq!(Config::parse_interval("-1"), Err(Error::NotANumber("-1".into())));
+        assert_eq!(Config::parse_interval("1.5"), Err(Error::NotANumber("1.5".into())));
+
+        assert_eq!(Config::parse_misses("3"), Ok(3));
+        assert_eq!(Config::parse_misses("1"), Ok(1));
+        assert_eq!(Config::parse_misses("0"), Err(Error::NoMisses));
+        assert_eq!(Config::parse_misses(""), Err(Error::NotANumber("".into())));
+    }
+
+    #[test]
+    fn measures_the_round_trip_of_the_latest_ping() {
+        let mut heartbeat = Heartbeat::new(CONFIG);
+        let id = heartbeat.tick().unwrap();
+        let (rtt, notable) = heartbeat.pong(id).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+        assert!(notable);
+        // answered already
+        assert_eq!(heartbeat.pong(id), None);
+    }
+
+    #[test]
+    fn ignores_stale_pongs_but_hears_them() {
+        let mut heartbeat = silent_for(Duration::from_secs(46));
+        heartbeat.pending = Some((2, Instant::now()));
+        assert_eq!(heartbeat.pong(1), None);
+        assert_eq!(heartbeat.pong(3), None);
+        assert!(heartbeat.tick().is_some());
+    }
+
+    #[test]
+    fn reports_notable_changes_only() {
+        let pong_after = |reported: Duration, rtt: Duration| {
+            let mut heartbeat = Heartbeat::new(CONFIG);
+            heartbeat.reported = Some(reported);
+            heartbeat.pending = Some((1, Instant::now().checked_sub(rtt).unwrap()));
+            heartbeat.pong(1).unwrap().1
+        };
+        let ms = Duration::from_millis;
+        assert!(!pong_after(ms(100), ms(150)));
+        assert!(pong_after(ms(100), ms(250)));
+        assert!(pong_after(ms(100), ms(40)));
+        // doubling a tiny round trip isn't worth reporting
+        assert!(!pong_after(ms(1), ms(5)));
+    }
+}
@@ -0,0 +1,376 @@
+//! protocol version negotiation for the proxy link
+//!
+//! Once the link is secured, each end sends a hello carrying the range of
+//! protocol versions it speaks, the optional capabilities it supports and its
+//! software version. Both ends then settle on the highest version and the
+//! capabilities they have in common, or drop the link if they share no
+//! version.
+//!
+//! The hello is written in a fixed layout rather than with [bincode], so that
+//! it stays readable by every version. It begins with a zero frame length,
+//! which ends the stream cleanly for a peer predating the negotiation instead
+//! of being misread as a PDU.
+
+use std::{convert::TryInto, fmt, io, ops::{BitAnd, BitOr}};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+/// The newest protocol version this build speaks.
+///
+/// 1. the initial protocol
+/// 2. sessions can be cancelled with [crate::up_stream::Message::Cancel]
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+const MAGIC: [u8; 8] = *b"\0\0\0\0CPMH";
+
+/// The length of the versions, capabilities and software length following the
+/// magic.
+const HEADER_LENGTH: usize = 2 + 2 + 8 + 1;
+
+/// The longest software version that is sent, longer ones are truncated.
+const MAX_SOFTWARE_LENGTH: usize = u8::MAX as usize;
+
+/// A set of optional protocol features.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// no optional features
+    pub const NONE: Self = Self(0);
+    /// fetching sparse index entries with [crate::up_stream::Resource::IndexEntry]
+    pub const INDEX_ENTRIES: Self = Self(1 << 0);
+    /// pinging the peer to detect a dead link, see [crate::heartbeat]
+    pub const HEARTBEAT: Self = Self(1 << 1);
+    /// reporting why a fetch failed with the specific variants of
+    /// [crate::down_stream::Error]
+    pub const TYPED_ERRORS: Self = Self(1 << 2);
+    /// resuming interrupted downloads with [crate::up_stream::Resource::CrateFrom]
+    pub const RESUME: Self = Self(1 << 3);
+    /// limiting the content in flight per session, see [crate::flow]
+    pub const FLOW_CONTROL: Self = Self(1 << 4);
+    /// compressing frames with zstd, see [crate::compression]
+    pub const ZSTD: Self = Self(1 << 5);
+    /// compressing frames with deflate, see [crate::compression]
+    pub const DEFLATE: Self = Self(1 << 6);
+    /// authenticating each frame when the link is keyed, see
+    /// [crate::handshake], ends holding a key refuse peers without it
+    pub const AUTHENTICATED_FRAMES: Self = Self(1 << 7);
+
+    const NAMES: &'static [(Self, &'static str)] = &[
+        (Self::INDEX_ENTRIES, "index-entries"),
+        (Self::HEARTBEAT, "heartbeat"),
+        (Self::TYPED_ERRORS, "typed-errors"),
+        (Self::RESUME, "resume"),
+        (Self::FLOW_CONTROL, "flow-control"),
+        (Self::ZSTD, "zstd"),
+        (Self::DEFLATE, "deflate"),
+        (Self::AUTHENTICATED_FRAMES, "authenticated-frames"),
+    ];
+
+    /// the features supported by this build
+    pub const fn supported() -> Self {
+        Self(Self::INDEX_ENTRIES.0 | Self::HEARTBEAT.0 | Self::TYPED_ERRORS.0 | Self::RESUME.0 | Self::FLOW_CONTROL.0 | Self::ZSTD.0 | Self::DEFLATE.0 | Self::AUTHENTICATED_FRAMES.0)
+    }
+
+    /// check if every feature of `other` is in this set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// the features of this set that are not in `other`
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES.iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// The greeting sent by each end when the link opens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: Capabilities,
+    /// the name and version of the software at this end, i.e. `proxy 0.1.0`
+    pub software: String,
+}
+
+/// What both ends of the link settled on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Agreement {
+    pub version: u16,
+    pub capabilities: Capabilities,
+    pub peer_software: String,
+}
+
+/// An error that can occur while negotiating the protocol.
+#[derive(Error, Display, Debug)]
+pub enum Error {
+    /// the peer did not send a hello, it is likely older than protocol negotiation
+    NoHello,
+    /// no common protocol version, this end speaks {0}..={1} while the peer ({2}) speaks {3}..={4}
+    Incompatible(u16, u16, String, u16, u16),
+    /// IO error during the protocol negotiation: {0}
+    Io(#[from] io::Error),
+}
+
+impl Hello {
+
+    /// the greeting of this build, identified as `software`
+    pub fn local(software: &str) -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            software: software.into(),
+        }
+    }
+
+    /// settle on the highest version and the capabilities shared with a peer
+    pub fn negotiate(&self, peer: &Hello) -> Result<Agreement, Error> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(Error::Incompatible(self.min_version, self.max_version, peer.software.clone(), peer.min_version, peer.max_version));
+        }
+        Ok(Agreement {
+            version,
+            capabilities: self.capabilities & peer.capabilities,
+            peer_software: peer.software.clone(),
+        })
+    }
+
+    /// the greeting as it is sent, its software name cut short to fit the
+    /// wire format
+    fn encode(&self) -> Vec<u8> {
+        let mut length = self.software.len().min(MAX_SOFTWARE_LENGTH);
+        while !self.software.is_char_boundary(length) {
+            length -= 1;
+        }
+        let mut bytes = Vec::with_capacity(MAGIC.len() + HEADER_LENGTH + length);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&self.min_version.to_be_bytes());
+        bytes.extend_from_slice(&self.max_version.to_be_bytes());
+        bytes.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        bytes.push(length as u8);
+        bytes.extend_from_slice(&self.software.as_bytes()[..length]);
+        bytes
+    }
+
+    /// send the greeting, its software name cut short to fit the wire format
+    pub async fn write<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> io::Result<()> {
+        stream.write_all(&self.encode()).await?;
+        stream.flush().await
+    }
+
+    /// receive the peer's greeting
+    pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Self, Error> {
+        Ok(Self::read_received(stream).await?.0)
+    }
+
+    /// receive the peer's greeting, along with the bytes it was received as
+    async fn read_received<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Self, Vec<u8>), Error> {
+        let mut bytes = vec![0u8; MAGIC.len() + HEADER_LENGTH];
+        match stream.read_exact(&mut bytes[..MAGIC.len()]).await {
+            Ok(_) if bytes[..MAGIC.len()] == MAGIC => (),
+            Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err.into()),
+            _ => return Err(Error::NoHello),
+        }
+        stream.read_exact(&mut bytes[MAGIC.len()..]).await?;
+        let header = &bytes[MAGIC.len()..];
+        let min_version = u16::from_be_bytes([header[0], header[1]]);
+        let max_version = u16::from_be_bytes([header[2], header[3]]);
+        let capabilities = Capabilities(u64::from_be_bytes(header[4..12].try_into().unwrap()));
+        let length = header[12] as usize;
+        bytes.resize(bytes.len() + length, 0);
+        let software = bytes.len() - length;
+        stream.read_exact(&mut bytes[software..]).await?;
+        let hello = Self {
+            min_version,
+            max_version,
+            capabilities,
+            software: String::from_utf8_lossy(&bytes[software..]).into_owned(),
+        };
+        Ok((hello, bytes))
+    }
+}
+
+/// The greetings exchanged when the link opened, as they were sent by this
+/// end and as they were received from its peer.
+///
+/// Keyed links bind them to the session, see
+/// [handshake](crate::handshake::SessionKey::frame_macs), so that they can't
+/// be altered on their way without the link being dropped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Greetings {
+    pub sent: Vec<u8>,
+    pub received: Vec<u8>,
+}
+
+/// Greet the peer and settle on the protocol to use with it, returning the
+/// greetings that were exchanged.
+pub async fn exchange<S>(stream: &mut S, hello: &Hello) -> Result<(Agreement, Greetings), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let sent = hello.encode();
+    stream.write_all(&sent).await?;
+    stream.flush().await?;
+    let (peer, received) = Hello::read_received(stream).await?;
+    Ok((hello.negotiate(&peer)?, Greetings{ sent, received }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn hello(min_version: u16, max_version: u16, capabilities: Capabilities) -> Hello {
+        Hello{ min_version, max_version, capabilities, software: "peer 1.0".into() }
+    }
+
+    async fn round_trip(hello: &Hello) -> Hello {
+        let mut buffer = Vec::new();
+        hello.write(&mut buffer).await.unwrap();
+        Hello::read(&mut buffer.as_slice()).await.unwrap()
+    }
+
+    #[test]
+    fn negotiates_the_highest_shared_version() {
+        let cases = [
+            // (ours, theirs, agreed)
+            ((1, 2), (1, 2), Some(2)),
+            ((1, 2), (2, 3), Some(2)),
+            ((2, 3), (1, 2), Some(2)),
+            ((1, 3), (2, 2), Some(2)),
+            ((1, 1), (1, 5), Some(1)),
+            ((1, 2), (3, 4), None),
+            ((3, 4), (1, 2), None),
+            ((2, 2), (1, 1), None),
+            // a peer whose range is empty shares nothing
+            ((1, 5), (3, 2), None),
+        ];
+        for &((min, max), (peer_min, peer_max), agreed) in cases.iter() {
+            let result = hello(min, max, Capabilities::NONE).negotiate(&hello(peer_min, peer_max, Capabilities::NONE));
+            match (result, agreed) {
+                (Ok(agreement), Some(version)) => assert_eq!(agreement.version, version),
+                (Err(Error::Incompatible(..)), None) => (),
+                (result, _) => panic!("{}..={} with {}..={}: {:?}", min, max, peer_min, peer_max, result),
+            }
+        }
+    }
+
+    #[test]
+    fn negotiates_shared_capabilities() {
+        use Capabilities as C;
+        let cases = [
+            (C::supported(), C::supported(), C::supported()),
+            (C::supported(), C::NONE, C::NONE),
+            (C::HEARTBEAT | C::ZSTD, C::ZSTD | C::DEFLATE, C::ZSTD),
+            (C::HEARTBEAT, C::RESUME, C::NONE),
+            // features unknown to this build are never agreed
+            (C::supported(), C(u64::MAX), C::supported()),
+        ];
+        for &(ours, theirs, shared) in cases.iter() {
+            let agreement = hello(1, 2, ours).negotiate(&hello(1, 2, theirs)).unwrap();
+            assert_eq!(agreement.capabilities, shared, "{} with {}", ours, theirs);
+            assert_eq!(agreement.peer_software, "peer 1.0");
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let cases = [
+            Hello::local("mirror 0.1.0"),
+            hello(0, u16::MAX, Capabilities(u64::MAX)),
+            Hello{ software: String::new(), ..hello(1, 1, Capabilities::NONE) },
+        ];
+        for hello in cases.iter() {
+            assert_eq!(&round_trip(hello).await, hello);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncates_long_software_versions_on_a_char_boundary() {
+        let long = Hello{ software: "é".repeat(200), ..Hello::local("") };
+        let read = round_trip(&long).await;
+        assert_eq!(read.software.len(), MAX_SOFTWARE_LENGTH - 1);
+        assert!(long.software.starts_with(&read.software));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_magic() {
+        let mut buffer = Vec::new();
+        Hello::local("proxy").write(&mut buffer).await.unwrap();
+
+        let cases: [&[u8]; 4] = [
+            b"",
+            &buffer[..4],
+            // a frame of an older peer that sent a PDU straight away
+            b"\0\0\0\x10\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
+            b"\0\0\0\0CPMX\0\x01\0\x02",
+        ];
+        for bytes in cases.iter() {
+            assert!(matches!(Hello::read(&mut &bytes[..]).await, Err(Error::NoHello)), "{:?}", bytes);
+        }
+
+        // a hello cut short after its magic
+        assert!(matches!(Hello::read(&mut &buffer[..10]).await, Err(Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn exchanges_greetings_as_they_were_sent() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let (short, long) = (Hello::local("mirror"), Hello{ software: "é".repeat(200), ..Hello::local("") });
+        let (ours, theirs) = tokio::join!(exchange(&mut a, &short), exchange(&mut b, &long));
+        let ((_, ours), (_, theirs)) = (ours.unwrap(), theirs.unwrap());
+        assert_eq!(ours.sent, theirs.received);
+        assert_eq!(ours.received, theirs.sent);
+        assert_ne!(ours.sent, ours.received);
+
+        let mut written = Vec::new();
+        long.write(&mut written).await.unwrap();
+        assert_eq!(theirs.sent, written);
+    }
+
+    #[test]
+    fn names_capabilities() {
+        assert_eq!(Capabilities::NONE.to_string(), "none");
+        assert_eq!((Capabilities::HEARTBEAT | Capabilities::ZSTD).to_string(), "heartbeat,zstd");
+        assert!(Capabilities::supported().contains(Capabilities::RESUME));
+        assert_eq!(Capabilities::supported().without(Capabilities::supported()), Capabilities::NONE);
+    }
+}
@@ -1,15 +1,90 @@
+use serde::{Serialize, Deserialize};
+
+/// The location of a crate's entry relative to the root of the registry index.
+///
+/// Follows the `crates.io-index` layout, shared by the git and sparse
+/// protocols: `1/a`, `2/ab`, `3/a/abc` and `ab/cd/abcd...`.
+pub fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    let part = |skip: usize, take: usize| name.chars().skip(skip).take(take).collect::<String>();
+    match name.chars().count() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", part(0, 1), name),
+        _ => format!("{}/{}/{}", part(0, 2), part(2, 2), name),
+    }
+}
+
+/// HTTP cache validators of a previously fetched resource.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
 
 /// PDU for mirror -> proxy communications
 pub mod up_stream
 {
     use serde::{Serialize, Deserialize};
 
+    use super::{Validators, package::{Name, PackageId}};
+
+    /// A resource the proxy can fetch on the mirror's behalf.
+    ///
+    /// Names and versions are checked as they're received, and are sent the
+    /// same as the pairs of strings of earlier versions.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Resource {
+        /// the `.crate` file of a package version
+        Crate{ package: PackageId },
+        /// a package's entry in the sparse registry index, fetched
+        /// conditionally if validators from a cached copy are provided
+        IndexEntry{ package: Name, validators: Validators },
+        /// the `.crate` file of a package version, from `offset` onwards, to
+        /// resume an interrupted download, its headers still describe the
+        /// whole file
+        CrateFrom{ package: PackageId, offset: u64 },
+    }
+
     /// Request package download
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Request {
         pub session_id: u32,
-        pub package: String,
-        pub version: String,
+        pub resource: Resource,
+    }
+
+    /// A message sent to the proxy.
+    ///
+    /// Protocol version 1 predates this enum and sends bare [Request]s.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Message {
+        Request(Request),
+        /// stop working on a session, its result is no longer wanted
+        Cancel{ session_id: u32 },
+        /// check that the link is alive, see [crate::heartbeat]
+        Ping(u64),
+        /// answer a [down_stream::Opcode::Ping](crate::down_stream::Opcode::Ping)
+        Pong(u64),
+        /// allow the proxy to send `bytes` more content for a session, see
+        /// [crate::flow]
+        Credit{ session_id: u32, bytes: u32 },
+    }
+
+    impl Message {
+        /// the message in the form of protocol version 1, which has no way to
+        /// cancel a session
+        pub fn into_v1(self) -> Option<Request> {
+            match self {
+                Message::Request(request) => Some(request),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<Request> for Message {
+        fn from(request: Request) -> Self {
+            Message::Request(request)
+        }
     }
 }
 
@@ -19,34 +94,79 @@ pub mod down_stream
     use serde::{Serialize, Deserialize};
     use std::fmt;
 
+    use super::Validators;
+
     /// Important headers received when downloading a package.
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Headers {
         pub content_type: String,
         pub content_length: usize,
+        pub validators: Validators,
     }
 
     /// Error that can occur while attempting to download a package.
-    #[derive(Serialize, Deserialize, Debug)]
+    ///
+    /// Only `Unspecified` and `Generic` are sent to a peer that didn't agree
+    /// on [crate::hello::Capabilities::TYPED_ERRORS].
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum Error {
         Unspecified,
-        Generic(String)
+        Generic(String),
+        /// the resource does not exist upstream
+        NotFound,
+        /// the upstream server responded with an error status
+        UpstreamStatus(u16),
+        /// the upstream server did not respond in time
+        UpstreamTimeout,
+        /// fetching the resource is refused by policy
+        Denied,
+        /// the content does not match the checksum in the registry index
+        ChecksumMismatch,
+        /// the link to the proxy was lost before the fetch completed
+        LinkReset,
+        /// the upstream server redirected the fetch more than allowed
+        TooManyRedirects,
+    }
+
+    impl Error {
+        /// the error in a form understood by peers without typed errors
+        pub fn untyped(self) -> Self {
+            match self {
+                Error::Unspecified | Error::Generic(_) => self,
+                // as it was reported before it had a variant of its own
+                Error::TooManyRedirects => Error::Generic("too many redirects".into()),
+                _ => Error::Unspecified,
+            }
+        }
     }
 
     /// A buffer containing a fragment of a downloading package.
-    #[derive(Serialize, Deserialize)]
+    #[derive(Serialize, Deserialize, Clone)]
     pub struct Buffer(Vec<u8>);
 
     /// An fragment of the package download process.
     ///
-    /// A state machine, `Init -> Chunk* -> Complete`
-    #[derive(Serialize, Deserialize, Debug)]
+    /// A state machine, `(Init -> Chunk* | NotModified) -> Complete`
+    ///
+    /// `Ping` and `Pong` concern the link rather than a download, and are sent
+    /// with [LINK_SESSION].
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum Opcode {
         Init(Headers),
         Chunk(Buffer),
         Complete(Result<(),Error>),
+        /// the conditionally requested resource matched the validators
+        NotModified,
+        /// check that the link is alive, see [crate::heartbeat]
+        Ping(u64),
+        /// answer an [up_stream::Message::Ping](crate::up_stream::Message::Ping)
+        Pong(u64),
     }
 
+    /// The session id of messages concerning the link itself, never used by
+    /// a download.
+    pub const LINK_SESSION: u32 = 0;
+
     /// A message received from the proxy containing an opcode assocated with a
     /// particular session.
     #[derive(Serialize, Deserialize, Debug)]
@@ -87,8 +207,28 @@ pub mod down_stream
     }
 }
 
+pub mod backoff;
+
+pub mod compression;
+
 pub mod cpm_api;
 
+pub mod flow;
+
+pub mod handshake;
+
+pub mod hello;
+
+pub mod limits;
+
+pub mod package;
+
+pub mod heartbeat;
+
+pub mod tls;
+
+pub mod transport;
+
 mod api_serde {
 
     use std::io;
@@ -107,44 +247,88 @@ mod api_serde {
 mod tcp_sender
 {
     use serde::Serialize;
-    use tokio::{io::{self, AsyncWriteExt},net::tcp::OwnedWriteHalf};
-    use futures::{
-        channel::mpsc,
-        stream::StreamExt,
-    };
+    use tokio::{io::{self, AsyncWrite, AsyncWriteExt},net::tcp::OwnedWriteHalf};
+    use futures::stream::{Stream, StreamExt};
 
     use super::api_serde::serialize;
+    use super::compression::{self, Codec};
+    use super::handshake::FrameMac;
 
-    /// Wraps an [OwnedWriteHalf] to allow sending a sequence of typed values.
+    /// Wraps an [OwnedWriteHalf], or any other byte stream such as one half of
+    /// a TLS connection, to allow sending a sequence of typed values.
     ///
     /// Encodes values using [bincode] and asynchronously sends the over a TPC
     /// stream with a simple framing protocol.
     pub struct TcpSender<T:Serialize> {
-        socket: OwnedWriteHalf,
+        socket: Box<dyn AsyncWrite + Send + Unpin>,
+        /// the codec to compress frames with, if the peer expects tagged frames
+        compression: Option<Codec>,
+        /// authenticates frames if the link is keyed
+        mac: Option<FrameMac>,
         _value: std::marker::PhantomData<T>
     }
 
 
     impl<T:Serialize> TcpSender<T> {
 
+        /// send values over an arbitrary byte stream
+        pub fn new(socket: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+            Self { socket: Box::new(socket), compression: None, mac: None, _value: Default::default() }
+        }
+
+        /// compress frames with the codec agreed with the peer, see
+        /// [compression]
+        pub fn with_compression(mut self, codec: Option<Codec>) -> Self {
+            self.compression = codec;
+            self
+        }
+
+        /// follow each frame with a tag authenticating it, see
+        /// [handshake](super::handshake)
+        pub fn with_authentication(mut self, mac: Option<FrameMac>) -> Self {
+            self.mac = mac;
+            self
+        }
+
+        /// write a frame, and its tag if authenticated
+        async fn write_frame(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+            let tag = self.mac.as_mut().map(|mac| mac.seal(bytes));
+            let tag = tag.as_ref().map_or(&[][..], |tag| &tag[..]);
+            let len = bytes.len() + tag.len();
+            assert!(len < (u32::MAX as usize));
+            // a single write keeps small frames in a single segment
+            let mut frame = Vec::with_capacity(4 + len);
+            frame.extend_from_slice(&(len as u32).to_be_bytes());
+            frame.extend_from_slice(bytes);
+            frame.extend_from_slice(tag);
+            self.socket.write_all(&frame).await?;
+            self.socket.flush().await
+        }
+
         pub async fn send(&mut self, value: &T) -> Result<(), io::Error> {
             let bytes = &serialize(value)?;
-            let len = bytes.len ();
-            assert!(len < (u32::MAX as usize));
-            self.socket.write_u32(len as u32).await?;
-            self.socket.write_all(&bytes).await?;
-            Ok(())
+            let compressed;
+            let bytes = match self.compression {
+                Some(codec) => {
+                    compressed = compression::encode(codec, bytes)?;
+                    &compressed
+                },
+                None => bytes,
+            };
+            self.write_frame(bytes).await
         }
 
+        /// end the stream with an empty frame, authenticated if the link is
+        /// so that it can't be cut short by anyone else
         pub async fn close(mut self) -> Result<(),io::Error> {
-            self.socket.write_u32(0).await?;
+            self.write_frame(&[]).await?;
             self.socket.shutdown().await?;
             Ok(())
         }
 
         pub async fn mp_process(
             mut self,
-            mut source: mpsc::Receiver<T>
+            mut source: impl Stream<Item = T> + Unpin
         ) -> Result<(), io::Error> {
             while let Some(event) = source.next().await {
                 self.send(&event).await?;
@@ -156,7 +340,7 @@ mod tcp_sender
 
     impl<T:Serialize> From<OwnedWriteHalf> for TcpSender<T> {
         fn from(socket: OwnedWriteHalf) -> Self {
-            Self { socket, _value: Default::default() }
+            Self::new(socket)
         }
     }
 }
@@ -164,26 +348,77 @@ mod tcp_sender
 mod tcp_receiver
 {
     use serde::de::DeserializeOwned;
-    use tokio::{io::{self, AsyncReadExt},net::tcp::OwnedReadHalf};
+    use tokio::{io::{self, AsyncRead, AsyncReadExt},net::tcp::OwnedReadHalf};
 
     use super::api_serde::deserialize;
+    use super::compression;
+    use super::handshake::{FrameMac, TAG_LENGTH};
+    use super::limits::{self, FrameLimit};
 
-    /// Wraps an [OwnedReadHalf] to allow receiving a sequence of typed values.
+    /// Wraps an [OwnedReadHalf], or any other byte stream such as one half of
+    /// a TLS connection, to allow receiving a sequence of typed values.
     ///
     /// Asynchronously receives value with a simple framing protocol from a TCP
     /// stream and decodes the with [bincode].
     pub struct TcpReceiver<T:DeserializeOwned> {
-        socket: OwnedReadHalf,
+        socket: Box<dyn AsyncRead + Send + Unpin>,
+        /// set if frames are tagged with the codec they were compressed with
+        compressed: bool,
+        /// the largest frame accepted, before and after decompression
+        max_frame_length: usize,
+        /// checks that frames were sent by the peer if the link is keyed
+        mac: Option<FrameMac>,
         _value: std::marker::PhantomData<T>
     }
 
-    impl<T:DeserializeOwned> TcpReceiver<T> {
+    impl<T:DeserializeOwned + FrameLimit> TcpReceiver<T> {
+
+        /// receive values from an arbitrary byte stream
+        pub fn new(socket: impl AsyncRead + Send + Unpin + 'static) -> Self {
+            Self { socket: Box::new(socket), compressed: false, max_frame_length: T::MAX_FRAME_LENGTH, mac: None, _value: Default::default() }
+        }
+
+        /// accept frames of up to `length` bytes instead of the default for
+        /// the type, see [limits]
+        pub fn with_max_frame_length(mut self, length: usize) -> Self {
+            self.max_frame_length = length;
+            self
+        }
+
+        /// expect frames tagged with the codec they were compressed with, as
+        /// sent when a codec was agreed with the peer, see [compression]
+        pub fn with_compression(mut self, compressed: bool) -> Self {
+            self.compressed = compressed;
+            self
+        }
+
+        /// expect each frame to be followed by a tag authenticating it, see
+        /// [handshake](super::handshake)
+        pub fn with_authentication(mut self, mac: Option<FrameMac>) -> Self {
+            self.mac = mac;
+            self
+        }
+
         pub async fn next(&mut self) -> Result<Option<T>,io::Error> {
             let mut bytes = Vec::<u8>::new();
-            let len = self.socket.read_u32().await?;
+            let tag_length = if self.mac.is_some() { TAG_LENGTH } else { 0 };
+            let len = (self.socket.read_u32().await? as usize).checked_sub(tag_length)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "a frame is missing its tag"))?;
+            limits::check::<T>(len, self.max_frame_length)?;
+            // the buffer grows as the frame arrives rather than up front,
+            // so a peer must send what it claims to make it grow
+            (&mut self.socket).take((len + tag_length) as u64).read_to_end(&mut bytes).await?;
+            if bytes.len() < len + tag_length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if let Some(mac) = &mut self.mac {
+                let tag = bytes.split_off(len);
+                mac.open(&bytes, &tag)?;
+            }
             if len > 0 {
-                bytes.resize(len as usize, 0);
-                self.socket.read_exact(&mut bytes).await?;
+                if self.compressed {
+                    bytes = compression::decode::<T>(&bytes, self.max_frame_length)?;
+                }
                 Ok(Some(deserialize(&bytes)?))
             } else {
                 Ok(None)
@@ -191,9 +426,9 @@ mod tcp_receiver
         }
     }
 
-    impl<T:DeserializeOwned> From<OwnedReadHalf> for TcpReceiver<T> {
+    impl<T:DeserializeOwned + FrameLimit> From<OwnedReadHalf> for TcpReceiver<T> {
         fn from(socket: OwnedReadHalf) -> Self {
-            Self { socket, _value: Default::default() }
+            Self::new(socket)
         }
     }
 }
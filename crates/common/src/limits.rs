@@ -0,0 +1,69 @@
+//! limits on the size of received frames
+//!
+//! Every frame begins with its length, which is checked against a limit for
+//! the type of PDU expected before anything is allocated for it, so that a
+//! corrupted or hostile peer can't exhaust the receiver's memory. Each PDU type
+//! has a default limit suited to its content, which a receiver may override.
+
+use std::io;
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use crate::{up_stream, down_stream, cpm_api, handshake};
+
+/// A PDU with a limit on the size of its frames.
+pub trait FrameLimit {
+    /// the largest frame accepted by default, in bytes
+    const MAX_FRAME_LENGTH: usize;
+}
+
+/// a frame of {length} bytes exceeds the limit of {limit} bytes for {pdu}
+#[derive(Error, Display, Debug)]
+pub struct FrameTooLarge {
+    pub pdu: &'static str,
+    pub length: usize,
+    pub limit: usize,
+}
+
+/// reject a frame of `T` longer than `limit` as a protocol error
+pub(crate) fn check<T>(length: usize, limit: usize) -> io::Result<()> {
+    if length > limit {
+        let pdu = std::any::type_name::<T>();
+        return Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge{ pdu, length, limit }));
+    }
+    Ok(())
+}
+
+/// Requests only name what to fetch.
+impl FrameLimit for up_stream::Request {
+    const MAX_FRAME_LENGTH: usize = 64 * 1024;
+}
+
+impl FrameLimit for up_stream::Message {
+    const MAX_FRAME_LENGTH: usize = 64 * 1024;
+}
+
+/// Proxies without flow control send index entries, which can run to a few
+/// megabytes, in a single chunk.
+impl FrameLimit for down_stream::Message {
+    const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+}
+
+/// The longest frame of the link sent by default in either direction, along
+/// with its length and authentication tag, which carries a message of its own
+/// over a WebSocket.
+pub const MAX_LINK_MESSAGE_LENGTH: usize = 4 + max(up_stream::Message::MAX_FRAME_LENGTH, down_stream::Message::MAX_FRAME_LENGTH) + handshake::TAG_LENGTH;
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+/// Uploads carry a whole `.crate` file.
+impl FrameLimit for cpm_api::SendMessage {
+    const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+}
+
+impl FrameLimit for cpm_api::RecvMessage {
+    const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+}
@@ -0,0 +1,158 @@
+//! validated crate names and versions
+//!
+//! Names and versions arrive from cargo, the `cpm` tool, lock files and the
+//! proxy link, and end up in URLs and file system paths, so they're checked
+//! against the rules of crates.io as soon as they're received: a name is at
+//! most 64 ASCII letters, digits, `-` or `_` starting with a letter, and a
+//! version is SemVer.
+
+use std::{convert::TryFrom, fmt, str::FromStr};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use displaydoc::Display;
+
+pub use semver::Version;
+
+/// the longest crate name crates.io accepts
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// the longest version accepted, which keeps `<name>-<version>.crate` within
+/// the file name limits of common file systems
+pub const MAX_VERSION_LENGTH: usize = 128;
+
+/// A reason a crate name or version was rejected.
+#[derive(Error, Display, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// '{0}' is not a legal crate name
+    InvalidName(String),
+    /// '{0}' is not a legal version: {1}
+    InvalidVersion(String, String),
+    /// '{0}' is not of the form `<name>/<version>`
+    InvalidPackageId(String),
+}
+
+/// check a crate name against the rules of crates.io
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let legal = name.len() <= MAX_NAME_LENGTH
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if legal {
+        Ok(())
+    } else {
+        Err(Error::InvalidName(name.into()))
+    }
+}
+
+/// parse a SemVer version, the whole string must be the version
+pub fn parse_version(version: &str) -> Result<Version, Error> {
+    if version.len() > MAX_VERSION_LENGTH {
+        return Err(Error::InvalidVersion(version.into(), format!("longer than {} characters", MAX_VERSION_LENGTH)));
+    }
+    Version::parse(version).map_err(|err| Error::InvalidVersion(version.into(), err.to_string()))
+}
+
+/// A legal crate name.
+///
+/// Can only hold a legal name, including when deserialized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Name(String);
+
+impl Name {
+
+    pub fn new(name: impl Into<String>) -> Result<Self, Error> {
+        let name = name.into();
+        check_name(&name)?;
+        Ok(Self(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for Name {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<Self, Error> {
+        Self::new(name)
+    }
+}
+
+impl From<Name> for String {
+    fn from(name: Name) -> Self {
+        name.0
+    }
+}
+
+/// Identifies a version of a package.
+///
+/// Can only hold a legal name and version, including when deserialized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "RawPackageId", into = "RawPackageId")]
+pub struct PackageId {
+    name: String,
+    version: Version,
+}
+
+/// the form a [PackageId] is sent in, a pair of strings
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "PackageId")]
+struct RawPackageId {
+    name: String,
+    version: String,
+}
+
+impl PackageId {
+
+    pub fn new(name: impl Into<String>, version: &str) -> Result<Self, Error> {
+        let name = name.into();
+        check_name(&name)?;
+        let version = parse_version(version)?;
+        Ok(Self{ name, version })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+}
+
+impl fmt::Display for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.name, self.version)
+    }
+}
+
+/// parses `<name>/<version>`, as listed by `cpm check`
+impl FromStr for PackageId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (name, version) = s.split_once('/').ok_or_else(|| Error::InvalidPackageId(s.into()))?;
+        Self::new(name, version)
+    }
+}
+
+impl TryFrom<RawPackageId> for PackageId {
+    type Error = Error;
+
+    fn try_from(raw: RawPackageId) -> Result<Self, Error> {
+        Self::new(raw.name, &raw.version)
+    }
+}
+
+impl From<PackageId> for RawPackageId {
+    fn from(id: PackageId) -> Self {
+        Self{ name: id.name, version: id.version.to_string() }
+    }
+}
@@ -7,20 +7,30 @@ use serde::{Serialize,de::DeserializeOwned};
 type be = byteorder::BigEndian;
 
 use super::api_serde::{serialize, deserialize};
+use super::limits::{self, FrameLimit};
 
 pub struct SyncTcpEndPoint<Req,Rsp> where Req:Serialize,Rsp:DeserializeOwned {
     stream: TcpStream,
+    /// the largest response frame accepted
+    max_frame_length: usize,
     _value: std::marker::PhantomData<(Req,Rsp)>
 }
 
-impl<Req:Serialize,Rsp:DeserializeOwned> From<TcpStream> for SyncTcpEndPoint<Req,Rsp> {
+impl<Req:Serialize,Rsp:DeserializeOwned + FrameLimit> From<TcpStream> for SyncTcpEndPoint<Req,Rsp> {
     fn from(stream: TcpStream) -> Self {
-        Self{stream,_value:Default::default()}
+        Self{stream,max_frame_length:Rsp::MAX_FRAME_LENGTH,_value:Default::default()}
     }
 }
 
 impl<Req,Rsp> SyncTcpEndPoint<Req,Rsp> where Req:Serialize,Rsp:DeserializeOwned {
 
+    /// accept responses of up to `length` bytes instead of the default for
+    /// the type, see [limits]
+    pub fn with_max_frame_length(mut self, length: usize) -> Self {
+        self.max_frame_length = length;
+        self
+    }
+
     pub fn transact(&mut self, request: &Req) -> io::Result<Rsp> {
 
         self.send_request(request)?;
@@ -29,7 +39,7 @@ impl<Req,Rsp> SyncTcpEndPoint<Req,Rsp> where Req:Serialize,Rsp:DeserializeOwned
     }
 
     pub fn close(mut self) -> io::Result<()> {
-        self.stream.write_u32::<be>(0 as u32)?;
+        self.stream.write_u32::<be>(0)?;
         self.stream.flush()?;
         self.stream.shutdown(Shutdown::Both)?;
         Ok(())
@@ -43,7 +53,7 @@ impl<Req,Rsp> SyncTcpEndPoint<Req,Rsp> where Req:Serialize,Rsp:DeserializeOwned
         assert!(len < (u32::MAX as usize));
 
         self.stream.write_u32::<be>(len as u32)?;
-        self.stream.write_all(&bytes)?;
+        self.stream.write_all(bytes)?;
 
         Ok(())
     }
@@ -53,10 +63,12 @@ impl<Req,Rsp> SyncTcpEndPoint<Req,Rsp> where Req:Serialize,Rsp:DeserializeOwned
         let mut bytes = Vec::<u8>::new();
         let len = self.stream.read_u32::<be>()?;
 
+        limits::check::<Rsp>(len as usize, self.max_frame_length)?;
+
         bytes.resize(len as usize, 0);
         self.stream.read_exact(&mut bytes)?;
 
-        Ok(deserialize(&bytes)?)
+        deserialize(&bytes)
 
     }
 }
@@ -0,0 +1,192 @@
+//! TLS for the proxy link
+//!
+//! Both ends load their configuration from PEM files: a certificate chain and
+//! private key identifying the end, and the certificate authorities trusted to
+//! identify the other end. The mirror only requires a certificate from the
+//! proxy when it is configured with a CA to verify it against.
+
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+use tokio_rustls::rustls::{
+    server::AllowAnyAuthenticatedClient,
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector, rustls::ServerName};
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+/// read a chain of certificates from a PEM file
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)?;
+    if certificates.is_empty() {
+        return Err(invalid_data(format!("{}: no certificates found", path.display())));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// read the first private key from a PEM file
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(invalid_data(format!("{}: no private key found", path.display()))),
+        }
+    }
+}
+
+/// read the certificate authorities to trust from a PEM file
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots.add(&certificate).map_err(invalid_data)?;
+    }
+    Ok(roots)
+}
+
+/// configure the mirror's end of the link, requiring the proxy to present a
+/// certificate signed by `client_ca` if provided
+pub fn acceptor(certificate: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let builder = match client_ca {
+        Some(client_ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(client_ca)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(load_certificates(certificate)?, load_private_key(key)?)
+        .map_err(invalid_data)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// configure the proxy's end of the link, trusting mirrors with certificates
+/// signed by `ca` and identifying itself with `identity` if provided
+pub fn connector(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<TlsConnector> {
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_roots(ca)?);
+
+    let config = match identity {
+        Some((certificate, key)) => builder
+            .with_client_auth_cert(load_certificates(certificate)?, load_private_key(key)?)
+            .map_err(invalid_data)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// the name to expect on the mirror's certificate
+pub fn server_name(name: &str) -> io::Result<ServerName> {
+    ServerName::try_from(name).map_err(invalid_data)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    /// a certificate or key from the test fixtures
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls").join(name)
+    }
+
+    fn mirror(client_ca: Option<&str>) -> TlsAcceptor {
+        acceptor(&fixture("mirror.pem"), &fixture("mirror.key"), client_ca.map(fixture).as_deref()).unwrap()
+    }
+
+    fn proxy(ca: &str, identity: Option<&str>) -> TlsConnector {
+        let identity = identity.map(|name| (fixture(&format!("{}.pem", name)), fixture(&format!("{}.key", name))));
+        connector(&fixture(ca), identity.as_ref().map(|(certificate, key)| (certificate.as_path(), key.as_path()))).unwrap()
+    }
+
+    /// connect the proxy to the mirror over an in-memory stream, and send a
+    /// message from one to the other, returning what each end made of it
+    async fn link(acceptor: TlsAcceptor, connector: TlsConnector, name: &str) -> (io::Result<Vec<u8>>, io::Result<()>) {
+        let (mirror_end, proxy_end) = duplex(16 * 1024);
+        let mirror = async {
+            let mut stream = acceptor.accept(mirror_end).await?;
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await?;
+            stream.shutdown().await?;
+            Ok(received)
+        };
+        let proxy = async {
+            let mut stream = connector.connect(server_name(name)?, proxy_end).await?;
+            stream.write_all(b"hello").await?;
+            stream.shutdown().await?;
+            // until the mirror is done with the link
+            stream.read_to_end(&mut Vec::new()).await?;
+            Ok(())
+        };
+        tokio::join!(mirror, proxy)
+    }
+
+    #[tokio::test]
+    async fn links_ends_trusting_each_other() {
+        let (received, sent) = link(mirror(None), proxy("ca.pem", None), "mirror").await;
+        sent.unwrap();
+        assert_eq!(received.unwrap(), b"hello");
+
+        let (received, sent) = link(mirror(Some("ca.pem")), proxy("ca.pem", Some("proxy")), "mirror").await;
+        sent.unwrap();
+        assert_eq!(received.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_mirror_signed_by_another_ca() {
+        let (received, sent) = link(mirror(None), proxy("other-ca.pem", None), "mirror").await;
+        assert_eq!(sent.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_mirror_by_another_name() {
+        let (received, sent) = link(mirror(None), proxy("ca.pem", None), "proxy").await;
+        assert_eq!(sent.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_proxies_without_a_certificate_from_the_client_ca() {
+        for identity in [None, Some("other-proxy")] {
+            let (received, _) = link(mirror(Some("ca.pem")), proxy("ca.pem", identity), "mirror").await;
+            assert!(received.is_err(), "{:?}", identity);
+        }
+    }
+
+    #[test]
+    fn refuses_files_without_what_they_should_hold() {
+        let err = acceptor(&fixture("mirror.key"), &fixture("mirror.key"), None).err().unwrap();
+        assert!(err.to_string().ends_with("no certificates found"), "{}", err);
+        let err = acceptor(&fixture("mirror.pem"), &fixture("mirror.pem"), None).err().unwrap();
+        assert!(err.to_string().ends_with("no private key found"), "{}", err);
+        assert_eq!(connector(&fixture("missing.pem"), None).err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+}
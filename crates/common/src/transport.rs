@@ -0,0 +1,328 @@
+//! transports the proxy link can run over
+//!
+//! The link is a byte stream in each direction, so it can run over anything
+//! providing one: a TCP connection, a Unix domain socket, or the standard
+//! input and output of a command such as `ssh mirror-host cpm-link`, which
+//! tunnels the link to the mirror through SSH. End points are written as
+//!
+//! * `host:port` for TCP,
+//! * `unix:<path>` for a Unix domain socket,
+//! * `exec:<command>` for a command run with the shell, proxies only.
+//! * `ws://` or `wss://` URLs for a WebSocket, proxies only, the mirror
+//!   accepting it on its http server.
+//!
+//! Over a WebSocket, each frame of the link is sent as a binary message, and
+//! messages longer than the longest frame of the link by default are refused.
+
+use std::{
+    fmt,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    process::Stdio,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use futures::{Sink, Stream as _};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use tokio_tungstenite::tungstenite::{self, protocol::{Role, WebSocketConfig}, Message};
+
+use crate::limits;
+
+/// the `Sec-WebSocket-Accept` response to a `Sec-WebSocket-Key`
+pub use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+use thiserror::Error;
+use displaydoc::Display;
+
+/// A byte stream the link can run over.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+/// Where the link is established.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndPoint {
+    /// a host name or address, and port
+    Tcp(String),
+    /// a Unix domain socket
+    Unix(PathBuf),
+    /// a command to run, talking over its standard input and output
+    Exec(String),
+    /// a `ws://` or `wss://` URL
+    WebSocket(String),
+}
+
+/// '{0}' is not a `host:port`, `unix:<path>`, `exec:<command>` or `ws(s)://` end point
+#[derive(Error, Display, Debug)]
+pub struct InvalidEndPoint(String);
+
+impl FromStr for EndPoint {
+    type Err = InvalidEndPoint;
+
+    fn from_str(end_point: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidEndPoint(end_point.into());
+        if let Some(path) = end_point.strip_prefix("unix:") {
+            return Some(EndPoint::Unix(path.into())).filter(|_| !path.is_empty()).ok_or_else(invalid);
+        }
+        if let Some(command) = end_point.strip_prefix("exec:") {
+            return Some(EndPoint::Exec(command.trim().into())).filter(|_| !command.trim().is_empty()).ok_or_else(invalid);
+        }
+        if end_point.starts_with("ws://") || end_point.starts_with("wss://") {
+            return Some(EndPoint::WebSocket(end_point.into())).filter(|_| websocket_host(end_point).is_some()).ok_or_else(invalid);
+        }
+        match end_point.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(EndPoint::Tcp(end_point.into())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for EndPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndPoint::Tcp(address) => write!(f, "{}", address),
+            EndPoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            EndPoint::Exec(command) => write!(f, "exec:{}", command),
+            EndPoint::WebSocket(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl EndPoint {
+
+    /// the host name or address of a TCP or WebSocket end point
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            EndPoint::Tcp(address) => address.rsplit_once(':')
+                .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']')),
+            EndPoint::WebSocket(url) => websocket_host(url),
+            _ => None,
+        }
+    }
+
+    /// establish a stream to the end point
+    pub async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            EndPoint::Tcp(address) => {
+                let socket = TcpStream::connect(address.as_str()).await?;
+                socket.set_nodelay(true)?;
+                Ok(Box::new(socket))
+            },
+            #[cfg(unix)]
+            EndPoint::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            EndPoint::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+            EndPoint::Exec(command) => Ok(Box::new(CommandStream::spawn(command)?)),
+            EndPoint::WebSocket(url) => {
+                let (socket, _) = tokio_tungstenite::connect_async_with_config(url.as_str(), Some(websocket_config()), true).await
+                    .map_err(websocket_error)?;
+                Ok(Box::new(WebSocket::new(socket)))
+            },
+        }
+    }
+
+    /// listen for streams on the end point
+    pub async fn listen(&self) -> io::Result<Listener> {
+        match self {
+            EndPoint::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address.as_str()).await?)),
+            #[cfg(unix)]
+            EndPoint::Unix(path) => {
+                // a socket left behind by a previous run would refuse the bind
+                match std::fs::remove_file(path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => (),
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            },
+            #[cfg(not(unix))]
+            EndPoint::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+            EndPoint::Exec(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "can't listen on a command")),
+            EndPoint::WebSocket(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "WebSockets are accepted by the http server")),
+        }
+    }
+}
+
+/// Accepts streams on an end point.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+
+    /// wait for the next stream, along with a description of where it is from
+    pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, from) = listener.accept().await?;
+                socket.set_nodelay(true)?;
+                Ok((Box::new(socket), from.to_string()))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (socket, _) = listener.accept().await?;
+                let from = match socket.peer_cred().ok().and_then(|credentials| credentials.pid()) {
+                    Some(pid) => format!("unix:{} (pid {})", path.display(), pid),
+                    None => format!("unix:{}", path.display()),
+                };
+                Ok((Box::new(socket), from))
+            },
+        }
+    }
+}
+
+/// The standard input and output of a command, killed once dropped.
+struct CommandStream {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl CommandStream {
+
+    fn spawn(command: &str) -> io::Result<Self> {
+        let mut child = shell(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("piped standard input");
+        let stdout = child.stdout.take().expect("piped standard output");
+        Ok(Self{ _child: child, stdin, stdout })
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    // replace the shell with the command, so it is the one killed once dropped
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(format!("exec {}", command));
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+/// the host of a WebSocket URL
+fn websocket_host(url: &str) -> Option<&str> {
+    let authority = url.split_once("://")?.1.split(&['/', '?', '#'][..]).next()?;
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.split_once(']')?.0,
+        None => host.split(':').next()?,
+    };
+    Some(host).filter(|host| !host.is_empty())
+}
+
+/// Messages, and the WebSocket frames carrying them, may be as large as the
+/// longest frame of the link, beyond the default limit on the size of a
+/// WebSocket frame, see [limits](crate::limits).
+fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig{
+        max_message_size: Some(limits::MAX_LINK_MESSAGE_LENGTH),
+        max_frame_size: Some(limits::MAX_LINK_MESSAGE_LENGTH),
+        ..Default::default()
+    }
+}
+
+fn websocket_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => io::ErrorKind::BrokenPipe.into(),
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// A byte stream carried by the binary messages of a WebSocket.
+pub struct WebSocket<S> {
+    socket: tokio_tungstenite::WebSocketStream<S>,
+    /// the part of the last message received not yet read
+    received: Vec<u8>,
+    offset: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+
+    fn new(socket: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        Self{ socket, received: Vec::new(), offset: 0 }
+    }
+
+    /// the server's end of a WebSocket, once the upgrade to it was accepted
+    pub async fn accepted(socket: S) -> Self {
+        Self::new(tokio_tungstenite::WebSocketStream::from_raw_socket(socket, Role::Server, Some(websocket_config())).await)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.offset == self.received.len() {
+            match futures::ready!(Pin::new(&mut self.socket).poll_next(cx)) {
+                Some(Ok(Message::Binary(message))) => {
+                    self.received = message;
+                    self.offset = 0;
+                },
+                // control messages are answered by the socket itself
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) | Some(Ok(Message::Frame(_))) => (),
+                Some(Ok(Message::Text(_))) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected text message"))),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(websocket_error(err))),
+            }
+        }
+        let length = buf.remaining().min(self.received.len() - self.offset);
+        buf.put_slice(&self.received[self.offset..self.offset + length]);
+        self.offset += length;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        futures::ready!(Pin::new(&mut self.socket).poll_ready(cx)).map_err(websocket_error)?;
+        Pin::new(&mut self.socket).start_send(Message::Binary(buf.to_vec())).map_err(websocket_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx).map_err(websocket_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_close(cx).map_err(websocket_error)
+    }
+}
@@ -0,0 +1,29 @@
+//! tests of the layout of crates in the registry index
+
+use common::index_path;
+
+#[test]
+fn short_names_are_grouped_by_length() {
+    assert_eq!(index_path("a"), "1/a");
+    assert_eq!(index_path("cc"), "2/cc");
+    assert_eq!(index_path("syn"), "3/s/syn");
+}
+
+#[test]
+fn longer_names_are_split_into_two_levels() {
+    assert_eq!(index_path("log_"), "lo/g_/log_");
+    assert_eq!(index_path("serde"), "se/rd/serde");
+    assert_eq!(index_path("tokio-util"), "to/ki/tokio-util");
+}
+
+#[test]
+fn names_are_lower_cased() {
+    assert_eq!(index_path("Serde_JSON"), "se/rd/serde_json");
+    assert_eq!(index_path("A"), "1/a");
+    assert_eq!(index_path("SYN"), "3/s/syn");
+}
+
+#[test]
+fn separators_are_kept_as_written() {
+    assert_ne!(index_path("serde-json"), index_path("serde_json"));
+}
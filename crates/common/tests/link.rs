@@ -0,0 +1,233 @@
+//! tests of a whole link between a mirror and a proxy over an in-memory
+//! stream: the key handshake, the hello and a download's messages, as each end
+//! sets them up, and a relay tampering with the hello
+
+use std::io;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use common::{
+    TcpSender, TcpReceiver, Validators,
+    compression::Codec,
+    down_stream::{self, Headers, Opcode},
+    handshake::{self, FrameMac, Role},
+    hello::{self, Capabilities, Hello},
+    limits::FrameLimit,
+    package::PackageId,
+    up_stream::{self, Resource},
+};
+
+const KEY: &[u8] = b"correct horse battery staple";
+
+/// How each end of a link is set up.
+#[derive(Clone, Copy)]
+struct Setup {
+    key: Option<&'static [u8]>,
+    compression: bool,
+}
+
+const SETUPS: [Setup; 4] = [
+    Setup{ key: None, compression: false },
+    Setup{ key: None, compression: true },
+    Setup{ key: Some(KEY), compression: false },
+    Setup{ key: Some(KEY), compression: true },
+];
+
+/// the greeting of an end, offering the codecs if compressing
+fn greeting(software: &str, setup: Setup) -> Hello {
+    let mut hello = Hello::local(software);
+    if !setup.compression {
+        hello.capabilities = hello.capabilities.without(Codec::all());
+    }
+    hello
+}
+
+fn software(role: Role) -> &'static str {
+    match role {
+        Role::Mirror => "mirror",
+        Role::Proxy => "proxy",
+    }
+}
+
+/// authenticate with and greet the peer, confirming they saw the same
+/// greetings, returning what they agreed and the authenticators of the frames
+/// sent and received
+async fn greet(stream: &mut DuplexStream, role: Role, setup: Setup) -> Result<(hello::Agreement, Option<(FrameMac, FrameMac)>), handshake::Error> {
+    let session = match setup.key {
+        Some(key) => Some(handshake::authenticate(stream, key, role).await?),
+        None => None,
+    };
+    let (agreement, greetings) = hello::exchange(stream, &greeting(software(role), setup)).await.unwrap();
+    if let Some(session) = &session {
+        handshake::confirm(stream, session, role, &greetings).await?;
+    }
+    Ok((agreement, session.map(|session| session.frame_macs(role, &greetings))))
+}
+
+/// authenticate with and greet the peer, then frame messages as agreed
+async fn establish<Tx: Serialize, Rx: DeserializeOwned + FrameLimit>(mut stream: DuplexStream, role: Role, setup: Setup) -> (TcpSender<Tx>, TcpReceiver<Rx>, hello::Agreement) {
+    let (agreement, macs) = greet(&mut stream, role, setup).await.unwrap();
+    let (sent, received) = macs.unzip();
+    let codec = Codec::agreed(agreement.capabilities);
+    let (rx, tx) = tokio::io::split(stream);
+    let sender = TcpSender::new(tx).with_compression(codec).with_authentication(sent);
+    let receiver = TcpReceiver::new(rx).with_compression(codec.is_some()).with_authentication(received);
+    (sender, receiver, agreement)
+}
+
+fn content() -> Vec<u8> {
+    (0..200_000u32).map(|i| (i / 1000) as u8).collect()
+}
+
+/// request a crate and collect what the proxy sends for it
+async fn mirror(stream: DuplexStream, setup: Setup) -> (hello::Agreement, Vec<down_stream::Message>) {
+    let (mut sender, mut receiver, agreement) = establish::<up_stream::Message, down_stream::Message>(stream, Role::Mirror, setup).await;
+    let resource = Resource::Crate{ package: PackageId::new("log", "0.4.14").unwrap() };
+    sender.send(&up_stream::Request{ session_id: 1, resource }.into()).await.unwrap();
+    let mut received = Vec::new();
+    while let Some(message) = receiver.next().await.unwrap() {
+        received.push(message);
+    }
+    sender.close().await.unwrap();
+    (agreement, received)
+}
+
+/// serve the mirror's request with the crate's headers and content
+async fn proxy(stream: DuplexStream, setup: Setup) -> hello::Agreement {
+    let (mut sender, mut receiver, agreement) = establish::<down_stream::Message, up_stream::Message>(stream, Role::Proxy, setup).await;
+    let request = match receiver.next().await.unwrap() {
+        Some(up_stream::Message::Request(request)) => request,
+        message => panic!("expected a request, got {:?}", message),
+    };
+    assert!(matches!(&request.resource, Resource::Crate{ package } if package.to_string() == "log/0.4.14"));
+    let content = content();
+    let headers = Headers{ content_type: "application/x-tar".into(), content_length: content.len(), validators: Validators::default() };
+    for opcode in [Opcode::Init(headers), Opcode::Chunk(content.into()), Opcode::Complete(Ok(()))] {
+        sender.send(&down_stream::Message{ session_id: request.session_id, opcode }).await.unwrap();
+    }
+    sender.close().await.unwrap();
+    assert!(receiver.next().await.unwrap().is_none());
+    agreement
+}
+
+#[tokio::test]
+async fn downloads_over_each_setup() {
+    for &setup in &SETUPS {
+        let (mirror_end, proxy_end) = tokio::io::duplex(64 * 1024);
+        let ((mirror_agreement, received), proxy_agreement) = tokio::join!(mirror(mirror_end, setup), proxy(proxy_end, setup));
+
+        assert_eq!(mirror_agreement.version, hello::PROTOCOL_VERSION);
+        assert_eq!(mirror_agreement.capabilities, proxy_agreement.capabilities);
+        assert_eq!(mirror_agreement.peer_software, "proxy");
+        assert_eq!(proxy_agreement.peer_software, "mirror");
+        assert_eq!(Codec::agreed(mirror_agreement.capabilities).is_some(), setup.compression);
+
+        match received.as_slice() {
+            [
+                down_stream::Message{ session_id: 1, opcode: Opcode::Init(headers) },
+                down_stream::Message{ session_id: 1, opcode: Opcode::Chunk(chunk) },
+                down_stream::Message{ session_id: 1, opcode: Opcode::Complete(Ok(())) },
+            ] => {
+                assert_eq!(headers.content_length, content().len());
+                assert_eq!(chunk.as_ref(), content().as_slice());
+            },
+            received => panic!("unexpected messages: {:?}", received),
+        }
+    }
+}
+
+#[tokio::test]
+async fn ends_agree_on_compression_only_if_both_offer_it() {
+    for &(mirror_setup, proxy_setup) in &[(SETUPS[0], SETUPS[1]), (SETUPS[1], SETUPS[0])] {
+        let (mirror_end, proxy_end) = tokio::io::duplex(64 * 1024);
+        let ((agreement, received), _) = tokio::join!(mirror(mirror_end, mirror_setup), proxy(proxy_end, proxy_setup));
+        assert!(Codec::agreed(agreement.capabilities).is_none());
+        assert_eq!(received.len(), 3);
+    }
+}
+
+#[tokio::test]
+async fn authenticated_links_agree_on_authenticated_frames() {
+    let (mirror_end, proxy_end) = tokio::io::duplex(64 * 1024);
+    let ((agreement, _), _) = tokio::join!(mirror(mirror_end, SETUPS[2]), proxy(proxy_end, SETUPS[2]));
+    assert!(agreement.capabilities.contains(Capabilities::AUTHENTICATED_FRAMES));
+}
+
+#[tokio::test]
+async fn frames_over_the_limit_are_refused() {
+    for &setup in &SETUPS {
+        let (mirror_end, proxy_end) = tokio::io::duplex(64 * 1024);
+        let mirror = async {
+            let (_sender, receiver, _) = establish::<up_stream::Message, down_stream::Message>(mirror_end, Role::Mirror, setup).await;
+            let mut receiver = receiver.with_max_frame_length(1024);
+            receiver.next().await.map(|_| ())
+        };
+        let proxy = async {
+            let (mut sender, _receiver, _) = establish::<down_stream::Message, up_stream::Message>(proxy_end, Role::Proxy, setup).await;
+            // random content, which compression can't bring under the limit
+            let chunk: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+            sender.send(&down_stream::Message{ session_id: 1, opcode: Opcode::Chunk(chunk.into()) }).await
+        };
+        let (received, sent) = tokio::join!(mirror, proxy);
+        sent.unwrap();
+        assert_eq!(received.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+/// Alters a hello up to the length of its software name.
+type Tamper = fn(&mut [u8]);
+
+/// relay a link from the proxy to the mirror, passing the proxy's hello
+/// through `tamper` on its way
+async fn relay(mirror_end: DuplexStream, proxy_end: DuplexStream, tamper: Tamper) {
+    let (mut from_mirror, mut to_mirror) = tokio::io::split(mirror_end);
+    let (mut from_proxy, mut to_proxy) = tokio::io::split(proxy_end);
+    let upstream = async {
+        // the challenge and answer of the key handshake, then the hello up to
+        // the length of its software name
+        let mut handshake = [0u8; 32 + 32];
+        from_proxy.read_exact(&mut handshake).await?;
+        to_mirror.write_all(&handshake).await?;
+        let mut hello = [0u8; 8 + 2 + 2 + 8 + 1];
+        from_proxy.read_exact(&mut hello).await?;
+        tamper(&mut hello);
+        to_mirror.write_all(&hello).await?;
+        tokio::io::copy(&mut from_proxy, &mut to_mirror).await
+    };
+    let downstream = tokio::io::copy(&mut from_mirror, &mut to_proxy);
+    // either end hanging up ends the relay
+    let _ = tokio::join!(upstream, downstream);
+}
+
+/// greet each other through a relay passing the proxy's hello through
+/// `tamper`, returning what each end agreed
+async fn greet_through_relay(tamper: Tamper) -> (Result<hello::Agreement, handshake::Error>, Result<hello::Agreement, handshake::Error>) {
+    let (mut mirror_end, relay_mirror_end) = tokio::io::duplex(64 * 1024);
+    let (relay_proxy_end, mut proxy_end) = tokio::io::duplex(64 * 1024);
+    let mirror = async move { greet(&mut mirror_end, Role::Mirror, SETUPS[3]).await.map(|(agreement, _)| agreement) };
+    let proxy = async move { greet(&mut proxy_end, Role::Proxy, SETUPS[3]).await.map(|(agreement, _)| agreement) };
+    let (mirror, proxy, _) = tokio::join!(mirror, proxy, relay(relay_mirror_end, relay_proxy_end, tamper));
+    (mirror, proxy)
+}
+
+#[tokio::test]
+async fn greetings_relayed_as_they_were_sent_are_accepted() {
+    let (mirror, proxy) = greet_through_relay(|_| ()).await;
+    assert_eq!(mirror.unwrap().capabilities, proxy.unwrap().capabilities);
+}
+
+#[tokio::test]
+async fn greetings_altered_on_their_way_are_refused() {
+    let tamperings: [(&str, Tamper); 3] = [
+        ("strip the capabilities", |hello| hello[12..20].copy_from_slice(&0u64.to_be_bytes())),
+        // zstd and deflate, in the last byte of the capabilities
+        ("strip compression", |hello| hello[19] &= !(1 << 5 | 1 << 6)),
+        ("downgrade the version", |hello| hello[10..12].copy_from_slice(&1u16.to_be_bytes())),
+    ];
+    for &(tampering, tamper) in &tamperings {
+        let (mirror, proxy) = greet_through_relay(tamper).await;
+        assert!(matches!(mirror, Err(handshake::Error::Tampered)), "{}: {:?}", tampering, mirror);
+        assert!(matches!(proxy, Err(handshake::Error::Tampered)), "{}: {:?}", tampering, proxy);
+    }
+}
@@ -0,0 +1,176 @@
+//! property tests of crate name and version validation against hostile input
+
+use std::path::{Component, Path};
+
+use proptest::prelude::*;
+use serde::Serialize;
+
+use common::{
+    Validators,
+    package::{self, Name, PackageId, MAX_NAME_LENGTH, MAX_VERSION_LENGTH},
+    up_stream::Resource,
+};
+
+/// the wire form of a [PackageId], which a peer can fill with anything
+#[derive(Serialize)]
+struct RawPackageId {
+    name: String,
+    version: String,
+}
+
+/// the wire form of a [Resource], as sent by earlier versions
+#[derive(Serialize)]
+enum RawResource {
+    Crate{ package: String, version: String },
+    IndexEntry{ package: String, validators: Validators },
+    CrateFrom{ package: String, version: String, offset: u64 },
+}
+
+/// fragments that could escape a directory, or confuse a URL or shell
+fn hostile() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("/".to_string()),
+        Just("..".to_string()),
+        Just("\\".to_string()),
+        Just("\0".to_string()),
+        Just("%2e%2e%2f".to_string()),
+        Just("?".to_string()),
+        Just("#".to_string()),
+        Just(" ".to_string()),
+        Just("\n".to_string()),
+        Just("~".to_string()),
+        Just("$(id)".to_string()),
+        Just("\u{2215}".to_string()),
+        // `.` and `+` are left out as they can splice into a legal version
+        "[^a-zA-Z0-9_.+-]",
+    ]
+}
+
+/// a name or version with something hostile spliced into it
+fn spliced(base: impl Strategy<Value = String>) -> impl Strategy<Value = String> {
+    (base, hostile(), any::<prop::sample::Index>()).prop_map(|(base, hostile, at)| {
+        let at = at.index(base.len() + 1);
+        format!("{}{}{}", &base[..at], hostile, &base[at..])
+    })
+}
+
+fn legal_name() -> impl Strategy<Value = String> {
+    "[a-zA-Z][a-zA-Z0-9_-]{0,63}"
+}
+
+fn legal_version() -> impl Strategy<Value = String> {
+    (any::<u32>(), any::<u32>(), any::<u32>(), prop::option::of("[a-z][a-z0-9]{0,8}"), prop::option::of("[a-z0-9]{1,8}"))
+        .prop_map(|(major, minor, patch, pre, build)| {
+            let mut version = format!("{}.{}.{}", major, minor, patch);
+            if let Some(pre) = pre {
+                version = format!("{}-{}", version, pre);
+            }
+            if let Some(build) = build {
+                version = format!("{}+{}", version, build);
+            }
+            version
+        })
+}
+
+/// a string that names a single file when used as a path component
+fn single_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+proptest! {
+
+    #[test]
+    fn legal_names_are_accepted(name in legal_name()) {
+        prop_assert_eq!(package::check_name(&name), Ok(()));
+    }
+
+    #[test]
+    fn legal_versions_are_accepted_and_kept_as_written(version in legal_version()) {
+        let parsed = package::parse_version(&version).unwrap();
+        prop_assert_eq!(parsed.to_string(), version);
+    }
+
+    #[test]
+    fn accepted_names_follow_the_grammar(name in any::<String>()) {
+        if package::check_name(&name).is_ok() {
+            prop_assert!(!name.is_empty() && name.len() <= MAX_NAME_LENGTH);
+            prop_assert!(name.starts_with(|c: char| c.is_ascii_alphabetic()));
+            prop_assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        }
+    }
+
+    #[test]
+    fn hostile_names_are_rejected(name in spliced(legal_name())) {
+        prop_assert!(matches!(package::check_name(&name), Err(package::Error::InvalidName(_))));
+    }
+
+    #[test]
+    fn hostile_versions_are_rejected(version in spliced(legal_version())) {
+        prop_assert!(matches!(package::parse_version(&version), Err(package::Error::InvalidVersion(..))));
+    }
+
+    #[test]
+    fn overlong_input_is_rejected(name in "[a-z]{65,200}", version in "0\\.0\\.0-[a-z]{126,300}") {
+        prop_assert!(package::check_name(&name).is_err());
+        prop_assert!(version.len() > MAX_VERSION_LENGTH && package::parse_version(&version).is_err());
+    }
+
+    #[test]
+    fn accepted_ids_stay_in_one_file(name in prop_oneof![legal_name(), any::<String>()], version in prop_oneof![legal_version(), any::<String>()]) {
+        if let Ok(id) = PackageId::new(name, &version) {
+            let file_name = format!("{}-{}.crate", id.name(), id.version());
+            prop_assert!(single_file_name(id.name()));
+            prop_assert!(single_file_name(&file_name));
+        }
+    }
+
+    #[test]
+    fn ids_round_trip_through_text(name in legal_name(), version in legal_version()) {
+        let id = PackageId::new(name, &version).unwrap();
+        prop_assert_eq!(id.to_string().parse::<PackageId>(), Ok(id));
+    }
+
+    #[test]
+    fn ids_round_trip_through_the_wire(name in legal_name(), version in legal_version()) {
+        let id = PackageId::new(name.as_str(), &version).unwrap();
+        let bytes = bincode::serialize(&id).unwrap();
+        prop_assert_eq!(&bytes, &bincode::serialize(&RawPackageId{ name, version }).unwrap());
+        prop_assert_eq!(bincode::deserialize::<PackageId>(&bytes).unwrap(), id);
+    }
+
+    #[test]
+    fn hostile_ids_are_refused_from_the_wire(name in prop_oneof![legal_name(), spliced(legal_name())], version in prop_oneof![legal_version(), spliced(legal_version())]) {
+        let legal = PackageId::new(name.as_str(), &version).is_ok();
+        let bytes = bincode::serialize(&RawPackageId{ name, version }).unwrap();
+        prop_assert_eq!(bincode::deserialize::<PackageId>(&bytes).is_ok(), legal);
+    }
+
+    #[test]
+    fn resources_are_sent_as_before(name in legal_name(), version in legal_version(), offset in any::<u64>()) {
+        let id = PackageId::new(name.as_str(), &version).unwrap();
+        let resources = [
+            (Resource::Crate{ package: id.clone() }, RawResource::Crate{ package: name.clone(), version: version.clone() }),
+            (Resource::IndexEntry{ package: Name::new(name.as_str()).unwrap(), validators: Validators::default() }, RawResource::IndexEntry{ package: name.clone(), validators: Validators::default() }),
+            (Resource::CrateFrom{ package: id, offset }, RawResource::CrateFrom{ package: name, version, offset }),
+        ];
+        for (resource, raw) in &resources {
+            prop_assert_eq!(bincode::serialize(resource).unwrap(), bincode::serialize(raw).unwrap());
+        }
+    }
+
+    #[test]
+    fn hostile_resources_are_refused_from_the_wire(name in prop_oneof![legal_name(), spliced(legal_name())], version in prop_oneof![legal_version(), spliced(legal_version())]) {
+        let legal_name = package::check_name(&name).is_ok();
+        let legal = PackageId::new(name.as_str(), &version).is_ok();
+        let resources = [
+            (RawResource::Crate{ package: name.clone(), version: version.clone() }, legal),
+            (RawResource::IndexEntry{ package: name.clone(), validators: Validators::default() }, legal_name),
+            (RawResource::CrateFrom{ package: name, version, offset: 0 }, legal),
+        ];
+        for (raw, legal) in &resources {
+            let bytes = bincode::serialize(raw).unwrap();
+            prop_assert_eq!(bincode::deserialize::<Resource>(&bytes).is_ok(), *legal);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! # Relay of the proxy link through standard input and output
+//!
+//! Runs on the mirror's host, started by a proxy that reaches the mirror
+//! through a command such as `ssh mirror-host cpm-link`. Connects to the end
+//! point the mirror accepts proxies on, given as the first argument or by
+//! `CPM_MIRROR_PROXY_LOCAL_END_POINT`, and relays the link between it and the
+//! standard input and output until either side closes.
+
+use std::{env, str::FromStr};
+
+use tokio::io::{self, AsyncWriteExt};
+
+use common::transport::EndPoint;
+
+/// relay the link between standard input and output and the mirror
+async fn relay(end_point: &EndPoint) -> io::Result<()> {
+
+    let (mut from_mirror, mut to_mirror) = io::split(end_point.connect().await?);
+    let (mut stdin, mut stdout) = (io::stdin(), io::stdout());
+
+    let to_mirror_fut = async {
+        io::copy(&mut stdin, &mut to_mirror).await?;
+        to_mirror.shutdown().await
+    };
+    let from_mirror_fut = async {
+        io::copy(&mut from_mirror, &mut stdout).await?;
+        stdout.flush().await
+    };
+
+    tokio::select! {
+        r = to_mirror_fut => r,
+        r = from_mirror_fut => r,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+
+    // standard output carries the link
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let end_point = env::args().nth(1)
+        .or_else(|| env::var("CPM_MIRROR_PROXY_LOCAL_END_POINT").ok())
+        .expect("end point of the mirror as an argument or `CPM_MIRROR_PROXY_LOCAL_END_POINT`");
+
+    let end_point = EndPoint::from_str(&end_point).unwrap_or_else(|err| panic!("legal end point of the mirror: {}", err));
+
+    if let Err(err) = relay(&end_point).await {
+        tracing::error!("link to {} failed with: {}", end_point, err);
+        std::process::exit(1);
+    }
+}
@@ -9,6 +9,7 @@ use serde::Deserialize;
 
 #[derive(Deserialize,Debug)]
 pub struct LockFile {
+    #[allow(dead_code)]
     pub version: usize,
     pub package: Vec<Package>
 }
@@ -19,6 +20,7 @@ pub struct Package{
     pub version: String,
     pub source: Option<String>,
     pub checksum: Option<String>,
+    #[allow(dead_code)]
     pub dependancies: Option<Vec<String>>,
 }
 
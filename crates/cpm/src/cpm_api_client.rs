@@ -13,10 +13,10 @@ impl CpmApiClient {
         Ok(Self(EndPoint::from(TcpStream::connect(addr)?),0))
     }
 
-    pub(crate) fn upload(&mut self, name: impl Into<String>, version: impl Into<String>, file_bytes: Vec<u8>) -> Result<()> {
+    pub(crate) fn upload(&mut self, package: PackageId, file_bytes: Vec<u8>) -> Result<()> {
 
         let response = self.transact(Request::UploadCrate{
-            package: PackageId{name: name.into(), version: version.into()},
+            package,
             content: file_bytes,
         })?;
 
@@ -40,6 +40,17 @@ impl CpmApiClient {
         }
     }
 
+    pub(crate) fn pin(&mut self, name: impl Into<String>, version: Option<String>, pinned: bool, token: Option<String>) -> Result<()> {
+
+        let response = self.transact(Request::Pin{ name: name.into(), version, pinned, token })?;
+
+        if let Response::Pin = response {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedResponse)
+        }
+    }
+
     pub(crate) fn close(self) -> Result<()> {
         Ok(self.0.close()?)
     }
@@ -9,7 +9,8 @@ use displaydoc::Display;
 use thiserror::Error;
 
 use common::{
-    cpm_api::{self,PackageId},
+    cpm_api,
+    package::{self,PackageId},
 };
 
 /// Manage acquiring missing crates from a disconnected network partition.
@@ -21,6 +22,10 @@ struct Options {
     #[structopt(short, long, env = "CPM_API_SERVER_END_POINT")]
     server_end_point: SocketAddr,
 
+    /// The mirror's publish token, required to pin and unpin crates.
+    #[structopt(long, env = "CPM_PUBLISH_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
     #[structopt(flatten)]
     command: Command
 }
@@ -46,11 +51,28 @@ enum Command {
     Upload{
         #[structopt(parse(from_os_str))]
         tarball: PathBuf
-    }
+    },
+
+    /// Protect cached crates from eviction.
+    ///
+    /// Accepts `<name>/<version>` for a single version, or `<name>` for every
+    /// version of a crate.
+    Pin{
+        packages: Vec<String>
+    },
+
+    /// Allow previously pinned crates to be evicted.
+    ///
+    /// Accepts `<name>/<version>` for a single version, or `<name>` for every
+    /// version of a crate.
+    Unpin{
+        packages: Vec<String>
+    },
 }
 
 /// An error that can occur while exectuting a command.
 #[derive(Error, Display, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     /// An unexpected response was received from the mirror.
     UnexpectedResponse,
@@ -64,6 +86,9 @@ enum Error {
     /// Protocol error: unexpected sequence recieved.
     SequenceError,
 
+    /// {0}
+    InvalidPackage(#[from] package::Error),
+
     /** A filename in the provided tar was not as expeceted. This
     probably means the tar file was not produced by the download tool.
     */
@@ -87,7 +112,7 @@ fn check(server_end_point: SocketAddr, lock_file: PathBuf) -> Result<()> {
 
     let lock_file = cargo_lock::load(lock_file)?;
 
-    const SOURCE_CRATES_IO: &'static str = "registry+https://github.com/rust-lang/crates.io-index";
+    const SOURCE_CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
 
     let mut packages : Vec<PackageId> = Vec::new();
 
@@ -100,10 +125,7 @@ fn check(server_end_point: SocketAddr, lock_file: PathBuf) -> Result<()> {
         if let Some(source) = &package.source {
 
             if source == SOURCE_CRATES_IO {
-                packages.push(PackageId{
-                    name:package.name.clone(),
-                    version:package.version.clone()
-                });
+                packages.push(PackageId::new(package.name.as_str(), &package.version)?);
             } else {
                 eprintln!("ignoring package with alternate source: {}", source)
             }
@@ -120,7 +142,7 @@ fn check(server_end_point: SocketAddr, lock_file: PathBuf) -> Result<()> {
     client.close()?;
 
     for package in packages {
-        println!("{}/{}", package.name, package.version);
+        println!("{}", package);
     }
 
     Ok(())
@@ -142,17 +164,38 @@ fn upload(server_end_point: SocketAddr, tarball: PathBuf) -> Result<()> {
 
         let path : &str = path.to_str().ok_or(Error::BadTarFileName)?;
 
-        let (name,version) = path.split_once('/').ok_or(Error::BadTarFileName)?;
+        let package : PackageId = path.parse()?;
 
         let size = entry.size() as usize;
 
-        let mut file_bytes = Vec::new();
-
-        file_bytes.resize(size, 0);
+        let mut file_bytes = vec![0; size];
 
         entry.read_exact(&mut file_bytes)?;
 
-        client.upload(name, version, file_bytes)?;
+        client.upload(package, file_bytes)?;
+    }
+
+    client.close()?;
+
+    Ok(())
+}
+
+/// execute the `pin` and `unpin` sub-commands
+fn pin(server_end_point: SocketAddr, token: Option<String>, packages: Vec<String>, pinned: bool) -> Result<()> {
+
+    let mut client = CpmApiClient::new(server_end_point)?;
+
+    for package in packages {
+        match package.split_once('/') {
+            Some(_) => {
+                let package : PackageId = package.parse()?;
+                client.pin(package.name(), Some(package.version().to_string()), pinned, token.clone())?
+            },
+            None => {
+                package::check_name(&package)?;
+                client.pin(package, None, pinned, token.clone())?
+            },
+        }
     }
 
     client.close()?;
@@ -169,6 +212,12 @@ fn main() {
         },
         Upload{tarball} => if let Err(err) = upload(options.server_end_point, tarball) {
             eprintln!("error occured: {}", err);
+        },
+        Pin{packages} => if let Err(err) = pin(options.server_end_point, options.token, packages, true) {
+            eprintln!("error occured: {}", err);
+        },
+        Unpin{packages} => if let Err(err) = pin(options.server_end_point, options.token, packages, false) {
+            eprintln!("error occured: {}", err);
         }
     }
 }
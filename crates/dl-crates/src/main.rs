@@ -12,6 +12,8 @@ use tempfile::tempfile;
 use thiserror::Error;
 use displaydoc::Display;
 
+use common::package::{self,PackageId};
+
 /// Download a set crates into an archive.
 #[derive(StructOpt)]
 struct Opts {
@@ -30,11 +32,15 @@ struct Opts {
 
 /// An error that could occur while attempting execute the command.
 #[derive(Error,Display,Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
 
     /// the format of the crate list file is illegal
     IllegalCrateListFormat,
 
+    /// the crate list names an illegal crate: {0}
+    IllegalCrate(#[from] package::Error),
+
     /// A download error occured: {0}
     DownloadError(reqwest::StatusCode),
 
@@ -71,10 +77,9 @@ fn execute(input: PathBuf, output: PathBuf) -> Result<(),Error> {
         }
 
         let (name, version)=path.split_once('/').ok_or(IllegalCrateListFormat)?;
-        let name = name.trim();
-        let version = version.trim();
+        let package = PackageId::new(name.trim(), version.trim())?;
 
-        let url = format!("https://crates.io/api/v1/crates/{}/{}/download", name, version);
+        let url = format!("https://crates.io/api/v1/crates/{}/{}/download", package.name(), package.version());
 
         eprintln!("downloading: {}", url);
 
@@ -92,7 +97,7 @@ fn execute(input: PathBuf, output: PathBuf) -> Result<(),Error> {
 
         temp.seek(Start(0))?;
 
-        output.append_file(path, &mut temp)?;
+        output.append_file(package.to_string(), &mut temp)?;
     }
 
     output.finish()?;
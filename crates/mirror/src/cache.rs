@@ -0,0 +1,167 @@
+//! admission of packages into the crate cache
+
+use std::io;
+
+use sha2::{Digest,Sha256};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use crate::{index::Checksum, store::{CrateStore,PendingPut}};
+
+/// An error that prevented a package from being admitted to the cache.
+#[derive(Error,Display,Debug)]
+pub enum Error {
+    /// received {received} bytes, expected {expected}
+    Truncated{ received: usize, expected: usize },
+    /// the package content does not match the registry index checksum
+    ChecksumMismatch,
+    /// IO error: {0}
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T,Error>;
+
+/// Stashes a package into the cache as it is received.
+///
+/// The package is only committed to the store once it has the expected length
+/// and, unless it was verified elsewhere, its SHA-256 matches the registry
+/// index. It is discarded if the writer is dropped without being committed.
+pub struct CacheWriter {
+    put: Box<dyn PendingPut>,
+    expected_length: usize,
+    length: usize,
+    /// the expected checksum and the hash so far, if verified by the writer
+    verification: Option<(Checksum,Sha256)>,
+}
+
+impl CacheWriter {
+
+    /// begin storing a package version, checking it against the provided
+    /// checksum if any
+    pub async fn create(store: &dyn CrateStore, name: &str, version: &str, expected_length: usize, expected_checksum: Option<Checksum>) -> io::Result<Self> {
+        Ok(Self{
+            put: store.put(name, version).await?,
+            expected_length,
+            length: 0,
+            verification: expected_checksum.map(|checksum| (checksum, Sha256::new())),
+        })
+    }
+
+    /// append a fragment of the package
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.put.write(bytes).await?;
+        if let Some((_, hasher)) = &mut self.verification {
+            hasher.update(bytes);
+        }
+        self.length += bytes.len();
+        Ok(())
+    }
+
+    /// commit the completed package to the store if it is intact, returning
+    /// its size
+    pub async fn commit(mut self) -> Result<usize> {
+
+        if self.length != self.expected_length {
+            return Err(Error::Truncated{ received: self.length, expected: self.expected_length });
+        }
+
+        if let Some((checksum, hasher)) = self.verification.take() {
+            if hasher.finalize()[..] != checksum[..] {
+                return Err(Error::ChecksumMismatch);
+            }
+        }
+
+        self.put.commit().await?;
+
+        Ok(self.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::path::{Path, PathBuf};
+
+    use crate::store::FsStore;
+
+    const CONTENT: &[u8] = b"the content of a crate";
+
+    fn checksum(content: &[u8]) -> Checksum {
+        Sha256::digest(content).into()
+    }
+
+    /// every file under a directory, including partially written ones
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(files(&path));
+            } else {
+                found.push(path);
+            }
+        }
+        found
+    }
+
+    /// a store in a temporary directory, and a writer of `log` 0.4.14 to it
+    async fn writer(expected_length: usize, expected_checksum: Option<Checksum>) -> (tempfile::TempDir, FsStore, CacheWriter) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FsStore::new(dir.path());
+        let writer = CacheWriter::create(&store, "log", "0.4.14", expected_length, expected_checksum).await.unwrap();
+        (dir, store, writer)
+    }
+
+    async fn assert_nothing_stored(dir: &Path, store: &FsStore) {
+        assert!(!store.exists("log", "0.4.14").await.unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+        assert_eq!(files(dir), Vec::<PathBuf>::new());
+    }
+
+    #[tokio::test]
+    async fn commits_intact_packages() {
+        let (dir, store, mut writer) = writer(CONTENT.len(), Some(checksum(CONTENT))).await;
+        let (first, second) = CONTENT.split_at(5);
+        writer.write(first).await.unwrap();
+        writer.write(second).await.unwrap();
+        assert_eq!(writer.commit().await.unwrap(), CONTENT.len());
+        assert!(store.exists("log", "0.4.14").await.unwrap());
+        assert_eq!(files(dir.path()), [dir.path().join("log/0.4.14")]);
+    }
+
+    #[tokio::test]
+    async fn short_transfers_leave_nothing() {
+        let (dir, store, mut writer) = writer(CONTENT.len(), None).await;
+        writer.write(&CONTENT[..10]).await.unwrap();
+        assert!(matches!(writer.commit().await, Err(Error::Truncated{ received: 10, .. })));
+        assert_nothing_stored(dir.path(), &store).await;
+    }
+
+    #[tokio::test]
+    async fn overlong_transfers_leave_nothing() {
+        let (dir, store, mut writer) = writer(CONTENT.len() - 1, None).await;
+        writer.write(CONTENT).await.unwrap();
+        assert!(matches!(writer.commit().await, Err(Error::Truncated{ .. })));
+        assert_nothing_stored(dir.path(), &store).await;
+    }
+
+    #[tokio::test]
+    async fn failed_transfers_leave_nothing() {
+        // a download that completes with an error drops its writer uncommitted
+        let (dir, store, mut writer) = writer(CONTENT.len(), Some(checksum(CONTENT))).await;
+        writer.write(CONTENT).await.unwrap();
+        drop(writer);
+        assert_nothing_stored(dir.path(), &store).await;
+    }
+
+    #[tokio::test]
+    async fn mismatched_checksums_leave_nothing() {
+        let (dir, store, mut writer) = writer(CONTENT.len(), Some(checksum(b"other content"))).await;
+        writer.write(CONTENT).await.unwrap();
+        assert!(matches!(writer.commit().await, Err(Error::ChecksumMismatch)));
+        assert_nothing_stored(dir.path(), &store).await;
+    }
+}
@@ -1,38 +1,61 @@
 
-use std::{io,io::Write,fs::File,net::SocketAddr,path::{Path,PathBuf}};
+use std::{io,net::SocketAddr,sync::Arc};
 use tokio::net::{TcpStream,TcpListener};
 
 use common::{
     TcpSender, TcpReceiver,
-    cpm_api::{PackageId,Request,Response,Overlapped,SendMessage,RecvMessage},
+    cpm_api::{self,PackageId,Request,Response,Overlapped,SendMessage,RecvMessage},
+    package,
 };
 
+use crate::{cache::{self,CacheWriter},index::{self,CrateIndex},registry::Registry,usage::CacheUsage,store::CrateStore};
+
+fn storage_failure(err: io::Error) -> cpm_api::Error {
+    cpm_api::Error::StorageFailure(err.to_string())
+}
+
 /// checks the provided package list for missing entries in the cache
-fn check_missing(cache_path: &Path, packages: &mut Vec<PackageId>) {
-    packages.retain(|id| {
-        let mut cache_path : PathBuf = cache_path.into();
-        cache_path.push(&id.name);
-        cache_path.push(&id.version);
-        !cache_path.exists()
-    });
+async fn check_missing(store: &dyn CrateStore, packages: Vec<PackageId>) -> Result<Vec<PackageId>,cpm_api::Error> {
+    let mut missing = Vec::new();
+    for id in packages {
+        if !store.exists(id.name(), &id.version().to_string()).await.map_err(storage_failure)? {
+            missing.push(id);
+        }
+    }
+    Ok(missing)
 }
 
 /// place the provided package into the cache
-async fn upload_crate(mut cache_path: PathBuf, package: PackageId, file_bytes: Vec<u8>) -> io::Result<()> {
+///
+/// The package is only admitted if its checksum matches the registry index,
+/// and it doesn't belong to the private registry.
+async fn upload_crate(store: &dyn CrateStore, index: &CrateIndex, registry: &Registry, usage: &CacheUsage, package: PackageId, file_bytes: Vec<u8>) -> Result<(),cpm_api::Error> {
 
     tracing::trace!("adding new crate version {:?}, {} bytes", package, file_bytes.len());
 
-    cache_path.push(&package.name);
+    let (name, version) = (package.name(), &package.version().to_string());
 
-    if !cache_path.exists() {
-        std::fs::create_dir(&cache_path)?;
+    if registry.is_private(name).await.map_err(|err| cpm_api::Error::IndexUnavailable(err.to_string()))? {
+        tracing::warn!(target: "security", "refused upload of {}, which belongs to the private registry", package);
+        return Err(cpm_api::Error::Reserved(package));
     }
 
-    cache_path.push(&package.version);
+    let checksum = index.checksum(name, version).await.map_err(|err| match err {
+        index::Error::NotFound(..) => cpm_api::Error::NotInIndex(package.clone()),
+        err => cpm_api::Error::IndexUnavailable(err.to_string()),
+    })?;
+
+    if !store.exists(name, version).await.map_err(storage_failure)? {
+        let mut writer = CacheWriter::create(store, name, version, file_bytes.len(), Some(checksum)).await.map_err(storage_failure)?;
+        writer.write(&file_bytes).await.map_err(storage_failure)?;
+        let size = writer.commit().await.map_err(|err| match err {
+            cache::Error::ChecksumMismatch => cpm_api::Error::ChecksumMismatch(package.clone()),
+            err => cpm_api::Error::StorageFailure(err.to_string()),
+        })?;
 
-    if !cache_path.exists() {
-        let mut file = File::create(cache_path)?;
-        file.write_all(&file_bytes)?;
+        // uploads are how the cache is populated while the proxy is offline,
+        // so they're pinned rather than risk them being evicted
+        usage.record_admission(name, version, size as u64, true).await;
 
         tracing::info!("added new crate version {}, {} bytes", package, file_bytes.len());
     } else {
@@ -42,25 +65,71 @@ async fn upload_crate(mut cache_path: PathBuf, package: PackageId, file_bytes: V
     Ok(())
 }
 
-/// process commands from an accepted TCP connection
-pub async fn handle_connection(stream: TcpStream, cache_path: PathBuf) -> io::Result<()>
+/// pin or unpin a cached crate version, or every cached version of a crate
+///
+/// As pinning decides what the cache holds onto, it is only done for those
+/// presenting the registry's publish token.
+async fn pin(registry: &Registry, usage: &CacheUsage, name: String, version: Option<String>, pinned: bool, token: Option<String>) -> Result<(),cpm_api::Error> {
+    registry.check_token(token.as_ref().map(|token| token.as_bytes()))
+        .map_err(|err| cpm_api::Error::Unauthorized(err.to_string()))?;
+    match version {
+        Some(version) => {
+            let package = PackageId::new(name, &version).map_err(cpm_api::Error::InvalidPackage)?;
+            let version = package.version().to_string();
+            if usage.pin(package.name(), Some(&version), pinned).await {
+                Ok(())
+            } else {
+                Err(cpm_api::Error::NotCached(package))
+            }
+        },
+        None => {
+            package::check_name(&name).map_err(cpm_api::Error::InvalidPackage)?;
+            if usage.pin(&name, None, pinned).await {
+                Ok(())
+            } else {
+                Err(cpm_api::Error::NothingCached(name))
+            }
+        },
+    }
+}
+
+/// process commands from an accepted TCP connection, refusing messages longer
+/// than `max_frame_length`
+pub async fn handle_connection(stream: TcpStream, max_frame_length: usize, store: Arc<dyn CrateStore>, index: Arc<CrateIndex>, registry: Arc<Registry>, usage: Arc<CacheUsage>) -> io::Result<()>
 {
     let (rx_stream, tx_stream) = stream.into_split();
 
-    let mut rx_stream = TcpReceiver::<SendMessage>::from(rx_stream);
+    let mut rx_stream = TcpReceiver::<SendMessage>::from(rx_stream).with_max_frame_length(max_frame_length);
     let mut tx_stream = TcpSender::<RecvMessage>::from(tx_stream);
 
     while let Some(Overlapped::<Request>{sequence, payload: request}) = rx_stream.next().await? {
         match request {
 
-            Request::CheckMissing(mut packages) => {
-                check_missing(&cache_path, &mut packages);
-                tx_stream.send(&Overlapped{sequence, payload:Ok(Response::CheckMissing(packages))}).await?;
+            Request::CheckMissing(packages) => {
+                let payload = check_missing(&*store, packages).await.map(Response::CheckMissing);
+                tx_stream.send(&Overlapped{sequence, payload}).await?;
             },
 
             Request::UploadCrate{package,content} => {
-                upload_crate(cache_path.clone(), package, content).await?;
-                tx_stream.send(&Overlapped{sequence, payload:Ok(Response::UploadCrate)}).await?;
+                let payload = match upload_crate(&*store, &index, &registry, &usage, package, content).await {
+                    Ok(()) => Ok(Response::UploadCrate),
+                    Err(err) => {
+                        tracing::error!("rejected upload: {}", err);
+                        Err(err)
+                    }
+                };
+                tx_stream.send(&Overlapped{sequence, payload}).await?;
+            }
+
+            Request::Pin{name, version, pinned, token} => {
+                let payload = match pin(&registry, &usage, name, version, pinned, token).await {
+                    Ok(()) => Ok(Response::Pin),
+                    Err(err) => {
+                        tracing::error!("rejected pin: {}", err);
+                        Err(err)
+                    }
+                };
+                tx_stream.send(&Overlapped{sequence, payload}).await?;
             }
 
             //_ => {
@@ -73,17 +142,63 @@ pub async fn handle_connection(stream: TcpStream, cache_path: PathBuf) -> io::Re
 }
 
 /// listen on a TCP port, handling connection via [handle_connection]
-pub async fn service(local_end_point: SocketAddr, cache_path: PathBuf) -> io::Result<()> {
+pub async fn service(local_end_point: SocketAddr, max_frame_length: usize, store: Arc<dyn CrateStore>, index: Arc<CrateIndex>, registry: Arc<Registry>, usage: Arc<CacheUsage>) -> io::Result<()> {
     let listener = TcpListener::bind(local_end_point).await?;
     loop {
         let (stream, from) = listener.accept().await?;
-        let cache_path = cache_path.clone();
+        let store = store.clone();
+        let index = index.clone();
+        let registry = registry.clone();
+        let usage = usage.clone();
         tracing::debug!("accepted cpm api connection from: {}", from);
         tokio::spawn(async move {
-            match handle_connection(stream, cache_path).await {
+            match handle_connection(stream, max_frame_length, store, index, registry, usage).await {
                 Ok(_) => tracing::debug!("cpm api connection from {} shutdown gracefully", from),
                 Err(err) => tracing::error!("cpm api connection from {} terminated with: {}", from, err),
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::{reserved::ReservedNames, store::FsStore, usage::Policy};
+
+    async fn fixture(dir: &std::path::Path, token: Option<&str>) -> (Registry, CacheUsage) {
+        let store: Arc<dyn CrateStore> = Arc::new(FsStore::new(dir));
+        let registry = Registry::new(CrateIndex::new(dir.join(".index")), token.map(String::from), Arc::new(ReservedNames::parse("")), "http://mirror".into());
+        let usage = CacheUsage::load(store, dir.join(".usage.json"), None, Policy::Lru).await.unwrap();
+        usage.record_admission("log", "0.4.14", 100, false).await;
+        (registry, usage)
+    }
+
+    #[tokio::test]
+    async fn pins_with_the_publish_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (registry, usage) = fixture(dir.path(), Some("secret")).await;
+        pin(&registry, &usage, "log".into(), Some("0.4.14".into()), true, Some("secret".into())).await.unwrap();
+        pin(&registry, &usage, "log".into(), None, false, Some("secret".into())).await.unwrap();
+        assert!(matches!(pin(&registry, &usage, "log".into(), Some("1.0.0".into()), true, Some("secret".into())).await, Err(cpm_api::Error::NotCached(..))));
+    }
+
+    #[tokio::test]
+    async fn refuses_pins_without_the_publish_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (registry, usage) = fixture(dir.path(), Some("secret")).await;
+        for token in [None, Some(""), Some("secreT")] {
+            let result = pin(&registry, &usage, "log".into(), None, true, token.map(String::from)).await;
+            assert!(matches!(result, Err(cpm_api::Error::Unauthorized(..))));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_pins_when_no_token_is_configured() {
+        let dir = tempfile::tempdir().unwrap();
+        let (registry, usage) = fixture(dir.path(), None).await;
+        let result = pin(&registry, &usage, "log".into(), None, true, Some("secret".into())).await;
+        assert!(matches!(result, Err(cpm_api::Error::Unauthorized(..))));
+    }
+}
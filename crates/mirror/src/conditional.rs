@@ -0,0 +1,237 @@
+//! conditional and range requests for cached crates
+//!
+//! Implements enough of RFC 7232 and RFC 7233 for clients to revalidate a
+//! cached crate and to resume an interrupted download of one: `If-None-Match`,
+//! `If-Modified-Since`, `If-Range` and a single byte range. Requests for
+//! several ranges are answered with the whole crate.
+
+use std::time::SystemTime;
+
+use hyper::HeaderMap;
+use hyper::header::{self, HeaderName};
+
+/// How to respond to a request for a stored crate.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// the requester's copy is current
+    NotModified,
+    /// the whole crate
+    Full,
+    /// the bytes from the first offset to the second, inclusive
+    Partial(u64, u64),
+    /// the requested range lies outside the crate
+    Unsatisfiable,
+}
+
+/// A stored crate's validators, as sent with a response.
+pub struct Validators<'a> {
+    pub etag: Option<&'a str>,
+    pub last_modified: Option<SystemTime>,
+}
+
+fn header(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// the opaque part of an entity tag, and whether it is weak
+fn opaque_tag(tag: &str) -> (&str, bool) {
+    let tag = tag.trim();
+    match tag.strip_prefix("W/") {
+        Some(tag) => (tag, true),
+        None => (tag, false),
+    }
+}
+
+/// check an `If-None-Match` list against an entity tag, by weak comparison
+fn none_match(list: &str, etag: Option<&str>) -> bool {
+    list.trim() == "*" || etag.is_some_and(|etag| {
+        let etag = opaque_tag(etag).0;
+        list.split(',').any(|tag| opaque_tag(tag).0 == etag)
+    })
+}
+
+/// whole seconds, the precision of an HTTP date
+fn to_seconds(time: SystemTime) -> Option<u64> {
+    time.duration_since(SystemTime::UNIX_EPOCH).ok().map(|time| time.as_secs())
+}
+
+/// check if `If-Range` holds, an entity tag by strong comparison or the exact
+/// modification date
+fn range_condition(condition: &str, validators: &Validators) -> bool {
+    let condition = condition.trim();
+    if condition.starts_with('"') || condition.starts_with("W/") {
+        match (opaque_tag(condition), validators.etag.map(opaque_tag)) {
+            ((condition, false), Some((etag, false))) => condition == etag,
+            _ => false,
+        }
+    } else {
+        match (httpdate::parse_http_date(condition).ok().and_then(to_seconds), validators.last_modified.and_then(to_seconds)) {
+            (Some(condition), Some(modified)) => condition == modified,
+            _ => false,
+        }
+    }
+}
+
+/// parse a `Range` header for a single range of bytes, `None` if it should be
+/// ignored
+fn byte_range(range: &str, size: u64) -> Option<Outcome> {
+
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    let parse = |n: &str| n.parse::<u64>().ok().filter(|_| n.bytes().all(|b| b.is_ascii_digit()));
+
+    let (first, last) = if first.is_empty() {
+        // the last `suffix` bytes
+        let suffix = parse(last)?;
+        if suffix == 0 || size == 0 {
+            return Some(Outcome::Unsatisfiable);
+        }
+        (size.saturating_sub(suffix), size - 1)
+    } else {
+        let first = parse(first)?;
+        let last = match last {
+            "" => u64::MAX,
+            last => parse(last).filter(|&last| last >= first)?,
+        };
+        if first >= size {
+            return Some(Outcome::Unsatisfiable);
+        }
+        (first, last.min(size - 1))
+    };
+
+    Some(Outcome::Partial(first, last))
+}
+
+/// decide how to respond to a request for a stored crate of the provided size
+pub fn evaluate(headers: &HeaderMap, size: u64, validators: &Validators) -> Outcome {
+
+    let not_modified = match header(headers, header::IF_NONE_MATCH) {
+        Some(list) => none_match(list, validators.etag),
+        None => {
+            let since = header(headers, header::IF_MODIFIED_SINCE).and_then(|since| httpdate::parse_http_date(since).ok());
+            match (since.and_then(to_seconds), validators.last_modified.and_then(to_seconds)) {
+                (Some(since), Some(modified)) => modified <= since,
+                _ => false,
+            }
+        },
+    };
+
+    if not_modified {
+        return Outcome::NotModified;
+    }
+
+    let range = match header(headers, header::RANGE) {
+        Some(range) => range,
+        None => return Outcome::Full,
+    };
+
+    if let Some(condition) = header(headers, header::IF_RANGE) {
+        if !range_condition(condition, validators) {
+            return Outcome::Full;
+        }
+    }
+
+    byte_range(range, size).unwrap_or(Outcome::Full)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::time::Duration;
+
+    use hyper::header::HeaderValue;
+
+    const ETAG: &str = "\"abc\"";
+
+    /// Fri, 24 May 2013 00:00:00 GMT
+    fn modified() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1369353600)
+    }
+
+    fn evaluate_with(headers: &[(HeaderName, &str)], size: u64) -> Outcome {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        evaluate(&map, size, &Validators{ etag: Some(ETAG), last_modified: Some(modified()) })
+    }
+
+    fn range(range: &str, size: u64) -> Outcome {
+        evaluate_with(&[(header::RANGE, range)], size)
+    }
+
+    #[test]
+    fn serves_the_whole_crate_unconditionally() {
+        assert_eq!(evaluate_with(&[], 100), Outcome::Full);
+    }
+
+    #[test]
+    fn matching_entity_tags_are_not_modified() {
+        assert_eq!(evaluate_with(&[(header::IF_NONE_MATCH, ETAG)], 100), Outcome::NotModified);
+        assert_eq!(evaluate_with(&[(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")], 100), Outcome::NotModified);
+        assert_eq!(evaluate_with(&[(header::IF_NONE_MATCH, "*")], 100), Outcome::NotModified);
+        assert_eq!(evaluate_with(&[(header::IF_NONE_MATCH, "\"abcd\"")], 100), Outcome::Full);
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_dates() {
+        let headers = [(header::IF_NONE_MATCH, "\"other\""), (header::IF_MODIFIED_SINCE, "Sat, 25 May 2013 00:00:00 GMT")];
+        assert_eq!(evaluate_with(&headers, 100), Outcome::Full);
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        assert_eq!(evaluate_with(&[(header::IF_MODIFIED_SINCE, "Fri, 24 May 2013 00:00:00 GMT")], 100), Outcome::NotModified);
+        assert_eq!(evaluate_with(&[(header::IF_MODIFIED_SINCE, "Sat, 25 May 2013 00:00:00 GMT")], 100), Outcome::NotModified);
+        assert_eq!(evaluate_with(&[(header::IF_MODIFIED_SINCE, "Thu, 23 May 2013 23:59:59 GMT")], 100), Outcome::Full);
+        assert_eq!(evaluate_with(&[(header::IF_MODIFIED_SINCE, "yesterday")], 100), Outcome::Full);
+    }
+
+    #[test]
+    fn serves_single_byte_ranges() {
+        assert_eq!(range("bytes=0-9", 100), Outcome::Partial(0, 9));
+        assert_eq!(range("bytes=90-", 100), Outcome::Partial(90, 99));
+        assert_eq!(range("bytes=90-500", 100), Outcome::Partial(90, 99));
+        assert_eq!(range("bytes=-10", 100), Outcome::Partial(90, 99));
+        assert_eq!(range("bytes=-500", 100), Outcome::Partial(0, 99));
+        assert_eq!(range("bytes=99-99", 100), Outcome::Partial(99, 99));
+    }
+
+    #[test]
+    fn refuses_ranges_outside_the_crate() {
+        assert_eq!(range("bytes=100-", 100), Outcome::Unsatisfiable);
+        assert_eq!(range("bytes=100-200", 100), Outcome::Unsatisfiable);
+        assert_eq!(range("bytes=-0", 100), Outcome::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), Outcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_ranges_it_does_not_support() {
+        assert_eq!(range("bytes=0-9,20-29", 100), Outcome::Full);
+        assert_eq!(range("items=0-9", 100), Outcome::Full);
+        assert_eq!(range("bytes=9-0", 100), Outcome::Full);
+        assert_eq!(range("bytes=+1-9", 100), Outcome::Full);
+        assert_eq!(range("bytes=a-b", 100), Outcome::Full);
+        assert_eq!(range("bytes=-", 100), Outcome::Full);
+    }
+
+    #[test]
+    fn ranges_depend_on_if_range() {
+        let with = |condition| evaluate_with(&[(header::RANGE, "bytes=10-"), (header::IF_RANGE, condition)], 100);
+        assert_eq!(with(ETAG), Outcome::Partial(10, 99));
+        assert_eq!(with("\"stale\""), Outcome::Full);
+        // weak tags never match by strong comparison
+        assert_eq!(with("W/\"abc\""), Outcome::Full);
+        assert_eq!(with("Fri, 24 May 2013 00:00:00 GMT"), Outcome::Partial(10, 99));
+        assert_eq!(with("Sat, 25 May 2013 00:00:00 GMT"), Outcome::Full);
+    }
+}
@@ -0,0 +1,302 @@
+//! access to the crate registry index
+//!
+//! The index is a directory laid out like the `crates.io-index` repository,
+//! one file per crate containing a line of JSON per published version. It may
+//! be a checkout of the git index, or populated on demand from the sparse
+//! index via the proxy, in which case the HTTP cache validators of each entry
+//! are kept under `.validators`.
+
+use std::{
+    io,
+    path::{Path,PathBuf},
+    sync::atomic::{AtomicU32,Ordering},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use common::{index_path, Validators};
+use thiserror::Error;
+use displaydoc::Display;
+
+/// An error that can occur while consulting the index.
+#[derive(Error,Display,Debug)]
+pub enum Error {
+    /// {0}/{1} is not listed in the registry index
+    NotFound(String,String),
+    /// the index entry for {0} is malformed: {1}
+    Malformed(String,serde_json::Error),
+    /// the index entry for {0} has an illegal checksum
+    BadChecksum(String),
+    /// IO error reading the index: {0}
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T,Error>;
+
+/// A SHA-256 digest of a `.crate` file.
+pub type Checksum = [u8;32];
+
+/// The fields of an index line needed by the mirror.
+#[derive(Deserialize)]
+struct Entry {
+    vers: String,
+    cksum: String,
+}
+
+/// decode a hex encoded checksum
+fn parse_checksum(hex: &str) -> Option<Checksum> {
+    let mut checksum = Checksum::default();
+    // `from_str_radix` would also accept a sign
+    if hex.len() != checksum.len() * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    for (byte, pair) in checksum.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(checksum)
+}
+
+/// A local copy of the crate registry index.
+pub struct CrateIndex {
+    root: PathBuf,
+    /// how long a fetched entry is served without revalidating it
+    ttl: Duration,
+}
+
+impl CrateIndex {
+
+    /// use the index rooted at the provided directory
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self{ root: root.into(), ttl: Duration::ZERO }
+    }
+
+    /// serve entries fetched from the sparse index for the provided time
+    /// before revalidating them
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        Self{ ttl, ..self }
+    }
+
+    /// the directory containing the index
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// read a crate's entry along with the validators it was fetched with
+    pub async fn read_entry(&self, name: &str) -> io::Result<Option<(Vec<u8>,Validators)>> {
+
+        let content = match tokio::fs::read(self.root.join(index_path(name))).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let validators = match tokio::fs::read(self.validators_path(name)).await {
+            Ok(validators) => serde_json::from_slice(&validators).unwrap_or_default(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Some((content, validators)))
+    }
+
+    /// replace a crate's entry with one freshly fetched from the sparse index
+    pub async fn store_entry(&self, name: &str, content: &[u8], validators: &Validators) -> io::Result<()> {
+        let validators = serde_json::to_vec(validators)?;
+        self.write_entry(name, content).await?;
+        replace_file(&self.validators_path(name), &validators).await
+    }
+
+    /// replace a crate's entry
+    pub async fn write_entry(&self, name: &str, content: &[u8]) -> io::Result<()> {
+        replace_file(&self.root.join(index_path(name)), content).await
+    }
+
+    /// check if a crate's entry was fetched or revalidated within the time
+    /// to live
+    pub async fn is_fresh(&self, name: &str) -> bool {
+        match tokio::fs::metadata(self.validators_path(name)).await.and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified.elapsed().map(|age| age < self.ttl).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    /// record that a crate's entry was found to be up to date
+    pub async fn mark_fresh(&self, name: &str, validators: &Validators) -> io::Result<()> {
+        replace_file(&self.validators_path(name), &serde_json::to_vec(validators)?).await
+    }
+
+    /// the names of the crates with an entry, lowercase as in their file
+    /// names, passing over dot directories and partially written files
+    pub async fn names(&self) -> io::Result<Vec<String>> {
+
+        let mut names = Vec::new();
+        let mut directories = vec![self.root.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = match tokio::fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+                if entry.file_type().await?.is_dir() {
+                    directories.push(entry.path());
+                } else {
+                    names.push(name);
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn validators_path(&self, name: &str) -> PathBuf {
+        self.root.join(".validators").join(index_path(name))
+    }
+
+    /// look up the published checksum of a crate version
+    pub async fn checksum(&self, name: &str, version: &str) -> Result<Checksum> {
+
+        let content = match tokio::fs::read_to_string(self.root.join(index_path(name))).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::NotFound(name.into(), version.into()));
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let entry: Entry = serde_json::from_str(line).map_err(|err|Error::Malformed(name.into(), err))?;
+            if entry.vers == version {
+                return parse_checksum(&entry.cksum).ok_or_else(||Error::BadChecksum(name.into()));
+            }
+        }
+
+        Err(Error::NotFound(name.into(), version.into()))
+    }
+}
+
+/// atomically replace the content of a file via a temporary file
+async fn replace_file(path: &Path, content: &[u8]) -> io::Result<()> {
+
+    static NEXT_TEMP_ID: AtomicU32 = AtomicU32::new(0);
+
+    let dir = path.parent().expect("index path to have a parent");
+    let file_name = path.file_name().expect("index path to have a file name");
+
+    tokio::fs::create_dir_all(dir).await?;
+
+    let temp_path = dir.join(format!(
+        ".{}.{}-{}.partial",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed),
+    ));
+
+    tokio::fs::write(&temp_path, content).await?;
+
+    if let Err(err) = tokio::fs::rename(&temp_path, path).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const CKSUM: &str = "51b9bbe6f0b8a1a9d8cc5f5c1ea01ad3f7e3f4f7bde5b3e4b0a3c32e0e1b0c2d";
+
+    fn index_with(name: &str, content: &str) -> (tempfile::TempDir, CrateIndex) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(index_path(name));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+        let index = CrateIndex::new(dir.path());
+        (dir, index)
+    }
+
+    #[test]
+    fn parses_a_checksum() {
+        let checksum = parse_checksum(CKSUM).unwrap();
+        assert_eq!(checksum[0], 0x51);
+        assert_eq!(checksum[31], 0x2d);
+        assert_eq!(parse_checksum(&CKSUM.to_uppercase()), Some(checksum));
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        assert_eq!(parse_checksum(&CKSUM.replace('5', "g")), None);
+        assert_eq!(parse_checksum(&format!("+{}", &CKSUM[1..])), None);
+        assert_eq!(parse_checksum(&format!("é{}", &CKSUM[2..])), None);
+    }
+
+    #[test]
+    fn rejects_short_or_long_hex() {
+        assert_eq!(parse_checksum(""), None);
+        assert_eq!(parse_checksum(&CKSUM[1..]), None);
+        assert_eq!(parse_checksum(&CKSUM[2..]), None);
+        assert_eq!(parse_checksum(&format!("{}00", CKSUM)), None);
+    }
+
+    #[tokio::test]
+    async fn finds_the_checksum_of_a_version() {
+        let content = format!("{{\"vers\":\"0.1.0\",\"cksum\":\"{}\"}}\n\n{{\"vers\":\"0.2.0\",\"cksum\":\"{}\"}}\n", "00".repeat(32), CKSUM);
+        let (_dir, index) = index_with("Serde_Json", &content);
+        assert_eq!(index.checksum("serde_json", "0.2.0").await.unwrap(), parse_checksum(CKSUM).unwrap());
+        assert_eq!(index.checksum("serde_json", "0.1.0").await.unwrap(), [0; 32]);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_versions_and_crates() {
+        let content = format!("{{\"vers\":\"0.1.0\",\"cksum\":\"{}\"}}\n", CKSUM);
+        let (_dir, index) = index_with("log", &content);
+        assert!(matches!(index.checksum("log", "0.1.1").await, Err(Error::NotFound(..))));
+        assert!(matches!(index.checksum("log", "0.1").await, Err(Error::NotFound(..))));
+        assert!(matches!(index.checksum("rand", "0.1.0").await, Err(Error::NotFound(..))));
+    }
+
+    #[tokio::test]
+    async fn entries_are_fresh_within_the_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = CrateIndex::new(dir.path()).with_ttl(Duration::from_secs(60));
+        assert!(!index.is_fresh("log").await);
+        index.store_entry("log", b"{}", &Validators::default()).await.unwrap();
+        assert!(index.is_fresh("log").await);
+
+        let index = CrateIndex::new(dir.path());
+        assert!(!index.is_fresh("log").await);
+        index.mark_fresh("log", &Validators::default()).await.unwrap();
+        assert!(!index.is_fresh("log").await);
+    }
+
+    #[tokio::test]
+    async fn lists_the_names_of_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = CrateIndex::new(dir.path());
+        assert!(index.names().await.unwrap().is_empty());
+        for name in &["a", "ab", "abc", "Serde_Json"] {
+            index.store_entry(name, b"{}", &Validators::default()).await.unwrap();
+        }
+        std::fs::write(dir.path().join("3/a/.abd.1-0.partial"), b"").unwrap();
+        let mut names = index.names().await.unwrap();
+        names.sort();
+        assert_eq!(names, ["a", "ab", "abc", "serde_json"]);
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_entries() {
+        let (_dir, index) = index_with("log", "{\"vers\":\"0.1.0\",\"cksum\":\"51b9\"}\n");
+        assert!(matches!(index.checksum("log", "0.1.0").await, Err(Error::BadChecksum(..))));
+        let (_dir, index) = index_with("log", "{\"vers\":\"0.1.0\"}\n");
+        assert!(matches!(index.checksum("log", "0.1.0").await, Err(Error::Malformed(..))));
+    }
+}
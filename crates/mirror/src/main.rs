@@ -10,6 +10,8 @@ use std::{
     convert::Infallible,
     net::SocketAddr,
     path::PathBuf,
+    convert::TryFrom,
+    time::Duration,
     env
 };
 
@@ -17,13 +19,12 @@ use futures::{select, FutureExt};
 
 use tokio::pin;
 
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, HeaderMap, Request, Response, Server, server::conn::AddrStream};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::http::{Uri, Method,StatusCode};
-use hyper_staticfile::FileResponseBuilder;
 
-use common::down_stream;
-use futures::StreamExt;
+use common::{cpm_api::SendMessage, down_stream, limits::FrameLimit, package::PackageId};
+use futures::{StreamExt, channel::mpsc};
 
 use thiserror::Error;
 use displaydoc::Display;
@@ -31,13 +32,44 @@ use displaydoc::Display;
 /// server for command line access via the `cpm` tool
 mod cli_server;
 mod proxy_connection;
+/// access to the crate registry index
+mod index;
+/// admission of packages into the crate cache
+mod cache;
+/// the cargo sparse registry protocol
+mod sparse_index;
+/// tracks usage of the crate cache to enforce its quota
+mod usage;
+/// storage of cached crates
+mod store;
+/// crates published directly to the mirror
+mod registry;
+/// crate names reserved for the private registry
+mod reserved;
+/// proxies connecting over a WebSocket
+mod websocket;
+/// conditional and range requests for cached crates
+mod conditional;
 
 use proxy_connection::ProxyConnection;
+use index::{Checksum, CrateIndex};
+use cache::CacheWriter;
+use usage::CacheUsage;
+use store::CrateStore;
+use registry::Registry;
+use reserved::ReservedNames;
 
 type ProxyRef = Arc<ProxyConnection>;
+type IndexRef = Arc<CrateIndex>;
+type UsageRef = Arc<CacheUsage>;
+type StoreRef = Arc<dyn CrateStore>;
+type RegistryRef = Arc<Registry>;
 
 /// Parse a download request URL breaking into its components.
-fn parse_download_request(uri: &Uri) -> Result<(&str, &str), u16> {
+///
+/// A crate name or version that isn't legal is refused with 400, before it
+/// can reach a file system path or the proxy.
+fn parse_download_request(uri: &Uri) -> Result<PackageId, u16> {
     if let Some(pnq) = uri.path_and_query() {
         if pnq.query().is_none() {
             let path = pnq.path();
@@ -45,7 +77,10 @@ fn parse_download_request(uri: &Uri) -> Result<(&str, &str), u16> {
                 if let Some(path) = path.strip_suffix("/download") {
                     let mut parts = path.split('/');
                     match (parts.next(), parts.next(), parts.next()) {
-                        (Some(package), Some(version), None) => Ok((package, version)),
+                        (Some(package), Some(version), None) => PackageId::new(package, version).map_err(|err| {
+                            tracing::warn!(target: "security", "refused download request for {}: {}", path, err);
+                            400
+                        }),
                         _ => Err(404)
                     }
                 } else {
@@ -71,92 +106,380 @@ fn error_response(code: u16) -> Response<Body> {
         .unwrap()
 }
 
-/// Respond to a download request fulfilled by the cache.
-async fn download_cached(req: &Request<Body>, cache_path: PathBuf) -> Result<Response<Body>,u16> {
-    let file = tokio::fs::File::open(cache_path).await.map_err(|_|500u16)?;
-    let metadata = file.metadata().await.map_err(|_|500u16)?;
-    FileResponseBuilder::new()
-        .request(req)
-        .build(file, metadata)
-        .map_err(|_|500u16)
+/// A reason a download could not be served, worded for the cargo user.
+#[derive(Error,Display,Debug)]
+enum DownloadError {
+    /// {0} was not found upstream
+    NotFound(String),
+    /// the upstream server failed to provide {0} with status {1}
+    UpstreamStatus(String, u16),
+    /// the upstream server timed out providing {0}
+    UpstreamTimeout(String),
+    /// {0} is refused by the mirror's policy
+    Denied(String),
+    /// {0} does not match the checksum published in the registry index
+    ChecksumMismatch(String),
+    /// {0} is not listed in the registry index, so it can't be verified
+    Unlisted(String),
+    /// the connection to the proxy was lost while fetching {0}, please retry
+    LinkReset(String),
+    /// the upstream server redirected {0} too many times
+    TooManyRedirects(String),
+    /// {0} is not cached and no proxy is connected to fetch it
+    NoUplink(String),
+    /// the proxy failed to fetch {0}: {1}
+    Proxy(String, String),
+    /// request failed with status {0}
+    Status(u16),
 }
 
-/// An error the a occur while downloading from the proxy.
-#[derive(Error,Display,Debug)]
-enum StreamError{
-    /// an unexpected error occurred
-    Unexpected
+impl DownloadError {
+
+    /// interpret the reason the proxy gave for failing to fetch a package
+    fn from_proxy(package_id: String, error: down_stream::Error) -> Self {
+        use down_stream::Error::*;
+        match error {
+            NotFound => DownloadError::NotFound(package_id),
+            UpstreamStatus(status) => DownloadError::UpstreamStatus(package_id, status),
+            UpstreamTimeout => DownloadError::UpstreamTimeout(package_id),
+            Denied => DownloadError::Denied(package_id),
+            ChecksumMismatch => DownloadError::ChecksumMismatch(package_id),
+            LinkReset => DownloadError::LinkReset(package_id),
+            TooManyRedirects => DownloadError::TooManyRedirects(package_id),
+            Generic(reason) => DownloadError::Proxy(package_id, reason),
+            Unspecified => DownloadError::Proxy(package_id, "unspecified error".into()),
+        }
+    }
+
+    /// the HTTP status the error is reported with
+    ///
+    /// Cargo retries downloads that fail with a 5xx status, so those are kept
+    /// for failures that may be transient.
+    fn status(&self) -> u16 {
+        use DownloadError::*;
+        match self {
+            NotFound(_) | Unlisted(_) => 404,
+            Denied(_) => 403,
+            UpstreamStatus(..) | ChecksumMismatch(_) | TooManyRedirects(_) | Proxy(..) => 502,
+            LinkReset(_) | NoUplink(_) => 503,
+            UpstreamTimeout(_) => 504,
+            Status(status) => *status,
+        }
+    }
+
+    /// generate the response to the download request
+    fn response(&self) -> Response<Body> {
+        match self {
+            DownloadError::Status(status) => error_response(*status),
+            err => {
+                tracing::warn!("sending error code: {}, {}", err.status(), err);
+                Response::builder()
+                    .status(StatusCode::from_u16(err.status()).unwrap())
+                    .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(format!("{}\n", err).into())
+                    .unwrap()
+            },
+        }
+    }
+}
+
+impl From<u16> for DownloadError {
+    fn from(status: u16) -> Self {
+        DownloadError::Status(status)
+    }
 }
 
-/// Respond to a download request fulfilled by the cache.
+/// end a stream of content after the provided number of bytes
+fn limit(content: store::ByteStream, length: u64) -> impl futures::Stream<Item = std::io::Result<hyper::body::Bytes>> {
+    content.scan(length, |remaining, chunk| {
+        if *remaining == 0 {
+            return futures::future::ready(None);
+        }
+        let chunk = chunk.map(|mut bytes| {
+            bytes.truncate(bytes.len().min(*remaining as usize));
+            *remaining -= bytes.len() as u64;
+            bytes
+        });
+        futures::future::ready(Some(chunk))
+    })
+}
+
+/// Respond to a download request fulfilled by the cache, if the package is
+/// present.
 ///
-/// Optionally stashes the package in the cache.
-async fn proxy_download(proxy: ProxyRef, package: &str, version: &str, _cache_path: Option<PathBuf>) -> Result<Response<Body>,u16> {
+/// Honours conditional and range requests.
+async fn download_cached(store: &StoreRef, usage: &UsageRef, headers: &HeaderMap, package: &str, version: &str) -> Result<Option<Response<Body>>,u16> {
 
-    let mut stream = proxy.begin_download(package.into(), version.into()).await.map_err(|_|404u16)?;
+    use hyper::header;
 
-    if let Some(down_stream::Opcode::Init(headers)) = stream.next().await {
+    let storage_failure = |err: std::io::Error| {
+        tracing::error!("unable to read {}/{} from the cache: {}", package, version, err);
+        500u16
+    };
 
-        let mut builder = Response::builder();
+    let stored = match store.stat(package, version).await.map_err(storage_failure)? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
 
-        builder.headers_mut().unwrap().insert(&hyper::header::CONTENT_TYPE,   hyper::header::HeaderValue::from_str(&headers.content_type).unwrap());
-        builder.headers_mut().unwrap().insert(&hyper::header::CONTENT_LENGTH, headers.content_length.into());
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, stored.content_type.as_deref().unwrap_or(store::CRATE_CONTENT_TYPE))
+        .header(header::ACCEPT_RANGES, "bytes");
 
-        let stream = stream.filter_map(|oc| async move {
-            match oc {
-                down_stream::Opcode::Chunk(buffer) => Some(Ok(Vec::<u8>::from(buffer))),
-                down_stream::Opcode::Complete(Ok(())) => None,
-                _ => Some(Err(StreamError::Unexpected))
-            }
-        });
+    if let Some(etag) = stored.etag.as_deref().and_then(|etag| header::HeaderValue::from_str(etag).ok()) {
+        builder = builder.header(header::ETAG, etag);
+    }
 
-        builder.body(Body::wrap_stream(stream)).map_err(|_|500)
+    if let Some(modified) = stored.modified {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
 
-        //Ok(Response::new(Body::wrap_stream(stream)))
+    let validators = conditional::Validators{ etag: stored.etag.as_deref(), last_modified: stored.modified };
 
-    } else {
-        tracing::error!("expected headers for file download");
-        Err(500)
+    let (offset, length) = match conditional::evaluate(headers, stored.size, &validators) {
+        conditional::Outcome::NotModified => {
+            usage.record_hit(package, version);
+            return builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).map(Some).map_err(|_|500);
+        },
+        conditional::Outcome::Unsatisfiable => {
+            return builder.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", stored.size))
+                .body(Body::empty())
+                .map(Some)
+                .map_err(|_|500);
+        },
+        conditional::Outcome::Full => (0, stored.size),
+        conditional::Outcome::Partial(first, last) => {
+            builder = builder.status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", first, last, stored.size));
+            (first, last - first + 1)
+        },
+    };
+
+    // the package may have been evicted since
+    let content = match store.get(package, version, offset).await.map_err(storage_failure)? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+
+    usage.record_hit(package, version);
+
+    builder
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::wrap_stream(limit(content, length)))
+        .map(Some)
+        .map_err(|_|500)
+}
+
+/// Respond to a download request for a crate of the private registry.
+///
+/// Only versions published to the registry are served, the cache may also hold
+/// versions fetched upstream before the name was reserved.
+async fn private_download(registry: RegistryRef, store: StoreRef, usage: UsageRef, headers: &HeaderMap, package: &str, version: &str) -> Result<Response<Body>,DownloadError> {
+    match registry.has_version(package, version).await {
+        Ok(true) => download_cached(&store, &usage, headers, package, version).await?.ok_or(DownloadError::Status(404)),
+        Ok(false) => {
+            tracing::warn!(target: "security", "refused to download {}/{}, a version of a private crate not published to the mirror", package, version);
+            Err(DownloadError::Status(404))
+        },
+        Err(err) => {
+            tracing::error!("unable to read private index entry for {}: {}", package, err);
+            Err(DownloadError::Status(500))
+        }
     }
 }
 
-/// Respond to a download request fulfilled by the proxy or the cache.
+/// Forward a download from the proxy to the client, stashing it in the cache.
 ///
-/// Will use the cache if the package is present, otherwise it will use the
-/// proxy if connected, otherwise it will fail.
-async fn download(proxy: ProxyRef, req: &Request<Body>, package: &str, version: &str) -> Result<Response<Body>,u16> {
+/// Runs independently of the response body so that the download is seen
+/// through to completion even after the client has received every byte. The
+/// final chunk is held back until the proxy session completes, which it only
+/// does successfully once it has verified the package, so a client never
+/// receives a whole package that failed verification.
+async fn relay_download(
+    usage: UsageRef,
+    package: String,
+    version: String,
+    mut stream: mpsc::Receiver<down_stream::Opcode>,
+    sender: hyper::body::Sender,
+    mut writer: Option<CacheWriter>,
+) {
+    let package_id = format!("{}/{}", package, version);
+    let mut client = Some(sender);
+    let mut held_back: Option<down_stream::Buffer> = None;
+
+    while let Some(opcode) = stream.next().await {
+        match opcode {
+            down_stream::Opcode::Chunk(buffer) => {
+                if let Some(cache) = &mut writer {
+                    if let Err(err) = cache.write(buffer.as_ref()).await {
+                        tracing::error!("unable to cache {}: {}", package_id, err);
+                        writer = None;
+                    }
+                }
+                if let (Some(tx), Some(previous)) = (&mut client, held_back.replace(buffer)) {
+                    if tx.send_data(Vec::<u8>::from(previous).into()).await.is_err() {
+                        tracing::debug!("client went away while downloading {}", package_id);
+                        client = None;
+                    }
+                }
+                if client.is_none() && writer.is_none() {
+                    return;
+                }
+            },
+            down_stream::Opcode::Complete(Ok(())) => {
+                if let Some(cache) = writer {
+                    match cache.commit().await {
+                        Ok(size) => {
+                            tracing::info!("cached {}", package_id);
+                            usage.record_admission(&package, &version, size as u64, false).await;
+                        },
+                        Err(err) => tracing::error!("unable to cache {}: {}", package_id, err),
+                    }
+                }
+                if let (Some(mut tx), Some(last)) = (client, held_back) {
+                    let _ = tx.send_data(Vec::<u8>::from(last).into()).await;
+                }
+                return;
+            },
+            opcode => {
+                tracing::error!("download of {} failed with: {:?}", package_id, opcode);
+                break;
+            },
+        }
+    }
 
-    if let Ok(cache_path) = env::var("CPM_CRATE_CACHE") {
+    if let Some(tx) = client {
+        tx.abort();
+    }
+}
 
-        let mut cache_path = PathBuf::from(&cache_path);
+/// Look up the checksum a download from the proxy must match, refreshing the
+/// crate's index entry through the proxy if the version isn't listed yet.
+///
+/// A crate that can't be verified is refused rather than served.
+async fn expected_checksum(proxy: &ProxyRef, index: &CrateIndex, package: &str, version: &str) -> Result<Checksum,DownloadError> {
 
-        cache_path.push(package);
-        cache_path.push(version);
+    let mut checksum = index.checksum(package, version).await;
 
-        if cache_path.exists() {
-            download_cached(req, cache_path).await
-        } else {
-            proxy_download(proxy, package, version, Some(cache_path)).await
+    if let Err(index::Error::NotFound(..)) = checksum {
+        if sparse_index::refresh_entry(proxy, index, package).await {
+            checksum = index.checksum(package, version).await;
         }
+    }
 
-    } else {
-        proxy_download(proxy, package, version, None).await
+    checksum.map_err(|err| {
+        tracing::warn!(target: "security", "refused to download {}/{}, it can't be verified: {}", package, version, err);
+        match err {
+            index::Error::NotFound(..) => DownloadError::Unlisted(format!("{}/{}", package, version)),
+            _ => DownloadError::Status(500),
+        }
+    })
+}
+
+/// Respond to a download request fulfilled by the proxy.
+///
+/// Stashes the package in the cache, provided it matches the checksum
+/// published in the registry index.
+async fn proxy_download(proxy: ProxyRef, index: IndexRef, store: StoreRef, usage: UsageRef, id: &PackageId) -> Result<Response<Body>,DownloadError> {
+
+    let (package, version) = (id.name(), &id.version().to_string());
+    let package_id = id.to_string();
+
+    let checksum = expected_checksum(&proxy, &index, package, version).await?;
+
+    let proxy_connection::Download{mut stream, initiated} = proxy.begin_download(id.clone(), checksum).await.map_err(|err| match err {
+        proxy_connection::Error::NoUplink => DownloadError::NoUplink(package_id.clone()),
+        proxy_connection::Error::Reserved(_) => DownloadError::Denied(package_id.clone()),
+        proxy_connection::Error::IoError(_) | proxy_connection::Error::FlowControl(_) => DownloadError::Status(500),
+    })?;
+
+
+    match stream.next().await {
+        Some(down_stream::Opcode::Init(headers)) => {
+            let mut builder = Response::builder();
+
+            builder.headers_mut().unwrap().insert(&hyper::header::CONTENT_TYPE,   hyper::header::HeaderValue::from_str(&headers.content_type).unwrap());
+            builder.headers_mut().unwrap().insert(&hyper::header::CONTENT_LENGTH, headers.content_length.into());
+
+            // only the requester that initiated a shared download stashes it,
+            // the session verifies it for every requester
+            let writer = if initiated {
+                match CacheWriter::create(&*store, package, version, headers.content_length, None).await {
+                    Ok(writer) => Some(writer),
+                    Err(err) => {
+                        tracing::error!("unable to cache {}/{}: {}", package, version, err);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            let (sender, body) = Body::channel();
+
+            tokio::spawn(relay_download(usage, package.into(), version.into(), stream, sender, writer));
+
+            builder.body(body).map_err(|_|DownloadError::Status(500))
+
+            //Ok(Response::new(Body::wrap_stream(stream)))
+        },
+        Some(down_stream::Opcode::Complete(Err(err))) => Err(DownloadError::from_proxy(package_id, err)),
+        None => Err(DownloadError::LinkReset(package_id)),
+        Some(opcode) => {
+            tracing::error!("expected headers for file download, got: {:?}", opcode);
+            Err(DownloadError::Status(500))
+        },
     }
 }
 
-/// Process incoming request before handing off to [download] if appropriate.
-async fn handler(proxy: ProxyRef, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+/// Respond to a download request fulfilled by the proxy or the cache.
+///
+/// Will use the cache if the package is present, otherwise it will use the
+/// proxy if connected, otherwise it will fail. Crates of the private registry
+/// are never fetched through the proxy.
+async fn download(proxy: ProxyRef, index: IndexRef, registry: RegistryRef, store: StoreRef, usage: UsageRef, headers: &HeaderMap, id: &PackageId) -> Result<Response<Body>,DownloadError> {
+    let (package, version) = (id.name(), &id.version().to_string());
+    match registry.is_private(package).await {
+        Ok(true) => private_download(registry, store, usage, headers, package, version).await,
+        Ok(false) => match download_cached(&store, &usage, headers, package, version).await? {
+            Some(response) => Ok(response),
+            None => proxy_download(proxy, index, store, usage, id).await,
+        },
+        Err(err) => {
+            tracing::error!("unable to read private index entry for {}: {}", package, err);
+            Err(DownloadError::Status(500))
+        }
+    }
+}
+
+/// Process incoming request before handing off to [download], the sparse
+/// index, the private registry or the proxy link if appropriate.
+async fn handler(proxy: ProxyRef, index: IndexRef, registry: RegistryRef, store: StoreRef, usage: UsageRef, from: SocketAddr, req: Request<Body>) -> Result<Response<Body>, Infallible> {
     tracing::trace!("entering handler...");
     if req.method() == Method::GET {
+        if proxy.websocket_path() == Some(req.uri().path()) {
+            return Ok(websocket::accept(proxy, req, from).unwrap_or_else(error_response));
+        }
+        if let Some(request) = sparse_index::parse_request(req.uri()) {
+            return Ok(sparse_index::serve(proxy, index, registry, &req, request).await.unwrap_or_else(error_response));
+        }
         match parse_download_request(req.uri()) {
-            Ok((package, version)) => {
-                tracing::info!("package: {:?}, version: {:?}", package, version);
-                download(proxy, &req, package, version).await.or_else(|code|Ok(error_response(code)))
+            Ok(id) => {
+                tracing::info!("package: {:?}, version: {:?}", id.name(), id.version());
+                download(proxy, index, registry, store, usage, req.headers(), &id).await.or_else(|err|Ok(err.response()))
             },
             Err(code) => Ok(error_response(code)),
         }
     } else {
-        Ok(error_response(400))
+        let uri = req.uri().clone();
+        match registry::parse_request(req.method(), &uri) {
+            Some(request) => {
+                let upstream = sparse_index::UpstreamIndex::new(proxy, index);
+                Ok(registry::serve(&registry, &upstream, store, usage, req, request).await)
+            },
+            None => Ok(error_response(400)),
+        }
     }
 }
 
@@ -171,28 +494,122 @@ async fn main() {
     let http_end_point = SocketAddr::from_str(&http_end_point).expect("legal end point value for `CPM_HTTP_LOCAL_END_POINT`");
     let cpm_api_end_point = SocketAddr::from_str(&cpm_api_end_point).expect("legal end point value for `CPM_API_LOCAL_END_POINT`");
 
-    let proxy = ProxyConnection::new();
+    let cpm_api_max_frame_length = env::var("CPM_API_MAX_FRAME_LENGTH").ok()
+        .map(|length| length.parse().expect("number of bytes for `CPM_API_MAX_FRAME_LENGTH`"))
+        .unwrap_or(SendMessage::MAX_FRAME_LENGTH);
+
+    let reserved = Arc::new(ReservedNames::parse(&env::var("CPM_RESERVED_CRATES").unwrap_or_default()));
+
+    if !reserved.is_empty() {
+        tracing::info!("reserving crate names for the private registry: {:?}", reserved);
+    }
+
+    let proxy = ProxyConnection::new(reserved.clone());
+
+    // by default index entries are kept with the cached crates, the store
+    // passes over dot directories
+    let index_path = env::var("CPM_CRATE_INDEX").map(PathBuf::from)
+        .or_else(|_| env::var("CPM_CRATE_CACHE").map(|cache| PathBuf::from(cache).join(".index")))
+        .unwrap_or_else(|_| "crates.io-index".into());
+
+    let index_ttl = env::var("CPM_INDEX_TTL").ok()
+        .map(|ttl| ttl.parse().expect("legal value for `CPM_INDEX_TTL`"))
+        .unwrap_or(60);
+
+    let index = Arc::new(CrateIndex::new(index_path).with_ttl(Duration::from_secs(index_ttl)));
+
+    tracing::info!("verifying crates against the index at: {:?}", index.root());
+
+    let private_index = env::var("CPM_PRIVATE_INDEX").map(PathBuf::from).unwrap_or_else(|_| index.root().join(".private"));
+
+    // advertised to cargo in `config.json`, so must not come from a request
+    let base_url = match env::var("CPM_BASE_URL") {
+        Ok(base_url) => base_url.trim_end_matches('/').to_string(),
+        Err(_) if !http_end_point.ip().is_unspecified() => format!("http://{}", http_end_point),
+        Err(_) => panic!("a value for `CPM_BASE_URL` when `CPM_HTTP_LOCAL_END_POINT` is an unspecified address"),
+    };
+
+    Uri::try_from(base_url.as_str()).ok()
+        .filter(|uri| matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some() && uri.query().is_none())
+        .expect("legal URL value for `CPM_BASE_URL`");
+
+    tracing::info!("advertising the registry at: {}", base_url);
+
+    let registry = Arc::new(Registry::new(CrateIndex::new(private_index), env::var("CPM_PUBLISH_TOKEN").ok(), reserved, base_url));
+
+    tracing::info!("keeping published crates in the index at: {:?}", registry.root());
+
+    let (store, default_ledger_path): (StoreRef, PathBuf) = match env::var("CPM_CRATE_STORE").as_deref() {
+        Ok("s3") => {
+            let config = store::S3Config::from_env();
+            tracing::info!("caching crates in the bucket '{}' at: {}", config.bucket, config.endpoint);
+            (Arc::new(store::S3Store::new(config)), "usage.json".into())
+        },
+        Ok("fs") | Err(_) => {
+            let cache_path = PathBuf::from(env::var("CPM_CRATE_CACHE").expect("a value for 'CPM_CRATE_CACHE'"));
+            tracing::info!("caching crates in: {:?}", cache_path);
+            (Arc::new(store::FsStore::new(&cache_path)), cache_path.join(".usage.json"))
+        },
+        Ok(other) => panic!("unknown crate store '{}', expected 'fs' or 's3'", other),
+    };
+
+    let ledger_path = env::var("CPM_CRATE_CACHE_LEDGER").map(PathBuf::from).unwrap_or(default_ledger_path);
+
+    let quota = env::var("CPM_CRATE_CACHE_QUOTA").ok()
+        .map(|quota| usage::parse_quota(&quota).expect("legal value for `CPM_CRATE_CACHE_QUOTA`"));
+
+    // other mirrors' use and pins of a shared store are invisible to the ledger
+    if quota.is_some() && store.is_shared() {
+        panic!("`CPM_CRATE_CACHE_QUOTA` can't be enforced on a store shared by several mirrors, expire crates with the bucket's lifecycle rules instead");
+    }
+
+    let policy = env::var("CPM_CRATE_CACHE_EVICTION").ok()
+        .map(|policy| policy.parse().expect("legal value for `CPM_CRATE_CACHE_EVICTION`"))
+        .unwrap_or(usage::Policy::Lru);
+
+    let usage = Arc::new(CacheUsage::load(store.clone(), ledger_path, quota, policy).await.expect("readable crate cache"));
+
+    match quota {
+        Some(quota) => tracing::info!("limiting the crate cache to {} bytes, evicting by {:?}", quota, policy),
+        None => tracing::info!("the crate cache is unbounded"),
+    }
 
     let make_svc = {
         let proxy = proxy.clone();
-        make_service_fn(move |_conn| {
+        let index = index.clone();
+        let store = store.clone();
+        let usage = usage.clone();
+        let registry = registry.clone();
+        make_service_fn(move |conn: &AddrStream| {
+            let from = conn.remote_addr();
             let proxy = proxy.clone();
-            async {
-                Ok::<_, Infallible>(service_fn(move |req| { handler(proxy.clone(), req) }))
+            let index = index.clone();
+            let registry = registry.clone();
+            let store = store.clone();
+            let usage = usage.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| { handler(proxy.clone(), index.clone(), registry.clone(), store.clone(), usage.clone(), from, req) }))
             }
         })
     };
 
-    let cache_server = Server::bind(&http_end_point).serve(make_svc).fuse();
+    // the proxy link may be upgraded from a request, and is latency sensitive
+    let cache_server = Server::bind(&http_end_point).tcp_nodelay(true).serve(make_svc).fuse();
 
     let cpm_api_server = cli_server::service(
         cpm_api_end_point,
-        std::env::var("CPM_CRATE_CACHE").expect("a value for 'CPM_CRATE_CACHE'").into(),
+        cpm_api_max_frame_length,
+        store,
+        index,
+        registry,
+        usage.clone(),
     );
 
     tracing::info!("accepting HTTP connections on: {}", http_end_point);
     tracing::info!("accepting CPM-API connections on: {}", cpm_api_end_point);
 
+    tokio::spawn(async move { usage.flush_periodically().await });
+
     let proxy_server = proxy.serve().fuse();
     let cpm_api_server = cpm_api_server.fuse();
 
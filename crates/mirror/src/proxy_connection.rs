@@ -3,168 +3,1352 @@
 //! The proxy connection supports multiple concurrent downloads to be
 //! in-progress. This accomplished by sending a request with a unique session
 //! id. The proxy then sends a series of messages tagged with the session id.
+//!
+//! Concurrent requests for the same package version share a single session.
+//! The messages of a download session are retained while it is in-progress so
+//! that later requesters can be brought up to date before receiving the live
+//! messages.
+//!
+//! A download is hashed once by its session as the content arrives, and its
+//! completion is only passed on if the content matches the checksum in the
+//! registry index, so every subscriber sees the same verified or failed
+//! outcome.
+//!
+//! Several proxies may be connected at once. Each new session is assigned to
+//! the least busy uplink, and when an uplink is lost its unfinished sessions
+//! are re-issued on a surviving one, or on the next to connect if none
+//! survive. Downloads are resumed from the content already delivered by the
+//! lost uplink, or that content is skipped if the new uplink can't resume, so
+//! subscribers see a single uninterrupted download.
+//!
+//! Proxies usually connect to the mirror, but where only the mirror's side may
+//! open connections, the mirror dials proxies listening for it instead, and
+//! dials again whenever the link is lost. The link is the same either way.
+//!
+//! Each proxy negotiates the protocol version and capabilities when it
+//! connects, and is only assigned sessions it has the capabilities for.
+//!
+//! A session is torn down when it completes, when all of its subscribers have
+//! gone away, or when the proxy has been silent about it for too long. The
+//! proxy is told to cancel sessions that are torn down early.
+//!
+//! Proxies that support heartbeats are pinged regularly, and dropped when they
+//! go silent so that they can reconnect.
+//!
+//! Messages are handed to the subscribers of each session by a task of its
+//! own, so that a slow subscriber doesn't hold up the other sessions, and a
+//! subscriber that stalls is dropped rather than holding up the others. Each
+//! session may only have a window of content waiting for its subscribers,
+//! which is granted back to proxies that agreed on flow control as the content
+//! is delivered. Proxies without flow control are not read from while the
+//! window of the session they sent content for is exhausted.
 
 use std::{
     //path::PathBuf,
     str::FromStr,
-    net::SocketAddr,
     sync::{Arc,Mutex},
-    collections::HashMap,
+    collections::{BTreeMap,HashMap},
 };
 
 use thiserror::Error;
 use displaydoc::Display;
 
-use futures::{channel::mpsc, sink::SinkExt};
+use futures::{channel::mpsc, sink::SinkExt, stream::StreamExt};
+use sha2::{Digest,Sha256};
 
-use tokio::net::TcpListener;
+use tokio::{
+    io::{AsyncRead,AsyncWrite},
+    sync::Semaphore,
+    time::{timeout,interval,sleep,Duration,Instant},
+};
 
-use common::{up_stream, down_stream, TcpSender, TcpReceiver};
+use common::{up_stream, down_stream, backoff::Backoff, compression::Codec, flow, handshake, hello, heartbeat, limits::FrameLimit, package::{Name, PackageId}, tls, transport, TcpSender, TcpReceiver, Validators};
+
+use crate::{index::Checksum, reserved::ReservedNames};
 
 /// An error that can occur on the link while the proxy is connected.
 #[derive(Error,Display,Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// No uplink was available to request download.
     NoUplink,
-    /// The active uplink was disconnected.
-    UpLinkReset,
+    /// The crate {0} is reserved for the private registry.
+    Reserved(String),
+    /// The proxy sent more content for session {0} than it was granted credit for.
+    FlowControl(u32),
     /// An IO error occurred.
     IoError(#[from]tokio::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T,Error>;
 
+/// How long a connecting proxy has to authenticate itself and negotiate the
+/// protocol.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a session may go without hearing from the proxy before it is
+/// abandoned.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a session orphaned by a lost uplink waits for another to connect.
+///
+/// Kept below the time cargo waits for a stalled download.
+const RECONNECT_GRACE: Duration = Duration::from_secs(20);
+
+/// How often sessions are checked for idleness and for subscribers that went
+/// away.
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a subscriber may refuse a message before it is dropped.
+///
+/// Kept below the time cargo waits for a stalled download, as the other
+/// subscribers of the session wait along with it.
+const SUBSCRIBER_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before dialing a proxy again after failing to, doubled
+/// after each failure up to [DIAL_MAX_DELAY].
+const DIAL_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+const DIAL_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// The sending half of a link, encoded according to the agreed protocol.
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The ends of an established link to a proxy, what authenticates the frames
+/// sent over it if keyed, and the protocol agreed with it.
+type Link = (TcpReceiver<down_stream::Message>, Writer, Option<handshake::FrameMac>, hello::Agreement);
+
+/// A reason a connecting proxy was turned away.
+#[derive(Error,Display,Debug)]
+enum Refusal {
+    /// {0}
+    Handshake(#[from] handshake::Error),
+    /// {0}
+    Hello(#[from] hello::Error),
+}
+
+/// How links with connecting proxies are secured, authenticated and kept
+/// alive.
+pub(crate) struct LinkConfig {
+    /// the pre-shared key proxies must prove they hold
+    key: Option<Vec<u8>>,
+    tls: Option<tls::TlsAcceptor>,
+    /// the greeting sent to proxies once authenticated
+    hello: hello::Hello,
+    heartbeat: heartbeat::Config,
+    /// the longest message accepted from a proxy
+    max_frame_length: usize,
+    /// the path on the http server accepting proxies over a WebSocket
+    websocket_path: Option<String>,
+}
+
+impl LinkConfig {
+
+    /// read the configuration from `CPM_LINK_*` environment variables
+    fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// read the configuration from the `CPM_LINK_*` variables `var` looks up
+    pub(crate) fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+
+        let tls = match (var("CPM_LINK_TLS_CERT"), var("CPM_LINK_TLS_KEY")) {
+            (Some(certificate), Some(key)) => {
+                let client_ca = var("CPM_LINK_TLS_CA");
+                let acceptor = tls::acceptor(certificate.as_ref(), key.as_ref(), client_ca.as_deref().map(AsRef::as_ref))
+                    .unwrap_or_else(|err| panic!("usable TLS configuration for the proxy link: {}", err));
+                tracing::info!("securing the proxy link with TLS{}", if client_ca.is_some() { ", requiring client certificates" } else { "" });
+                Some(acceptor)
+            },
+            (None, None) => None,
+            _ => panic!("both `CPM_LINK_TLS_CERT` and `CPM_LINK_TLS_KEY` are required for TLS"),
+        };
+
+        let key = var("CPM_LINK_KEY").map(String::into_bytes);
+
+        if key.as_ref().is_some_and(Vec::is_empty) {
+            panic!("a non-empty value for `CPM_LINK_KEY`");
+        }
+
+        if key.is_none() && var("CPM_LINK_TLS_CA").is_none() {
+            if var("CPM_LINK_INSECURE").is_none() {
+                panic!("`CPM_LINK_KEY` or `CPM_LINK_TLS_CA` to authenticate proxies, or `CPM_LINK_INSECURE` to let any host connect as a proxy");
+            }
+            tracing::warn!("neither `CPM_LINK_KEY` nor `CPM_LINK_TLS_CA` are set, any host can connect as a proxy");
+        }
+
+        let mut hello = hello::Hello::local(concat!("mirror ", env!("CARGO_PKG_VERSION")));
+        if let Some(codecs) = var("CPM_LINK_COMPRESSION") {
+            let offered = Codec::offered(&codecs).unwrap_or_else(|err| panic!("legal value for `CPM_LINK_COMPRESSION`: {}", err));
+            hello.capabilities = hello.capabilities.without(Codec::all()) | offered;
+        }
+
+        let mut heartbeat = heartbeat::Config::default();
+        if let Some(interval) = var("CPM_LINK_HEARTBEAT_INTERVAL") {
+            heartbeat.interval = heartbeat::Config::parse_interval(&interval)
+                .unwrap_or_else(|err| panic!("legal value for `CPM_LINK_HEARTBEAT_INTERVAL`: {}", err));
+        }
+        if let Some(misses) = var("CPM_LINK_HEARTBEAT_MISSES") {
+            heartbeat.misses = heartbeat::Config::parse_misses(&misses)
+                .unwrap_or_else(|err| panic!("legal value for `CPM_LINK_HEARTBEAT_MISSES`: {}", err));
+        }
+
+        let max_frame_length = var("CPM_LINK_MAX_FRAME_LENGTH")
+            .map(|length| length.parse().expect("number of bytes for `CPM_LINK_MAX_FRAME_LENGTH`"))
+            .unwrap_or(down_stream::Message::MAX_FRAME_LENGTH);
+
+        let websocket_path = var("CPM_LINK_WEBSOCKET_PATH");
+
+        if let Some(path) = &websocket_path {
+            // anyone reaching the http server could otherwise connect as a
+            // proxy, which `CPM_LINK_INSECURE` doesn't extend to
+            if key.is_none() && var("CPM_LINK_TLS_CA").is_none() {
+                panic!("`CPM_LINK_KEY` or `CPM_LINK_TLS_CA` to authenticate proxies connecting over a WebSocket");
+            }
+            tracing::info!("accepting proxies over a WebSocket at: {}", path);
+        }
+
+        Self{ key, tls, hello, heartbeat, max_frame_length, websocket_path }
+    }
+
+    /// secure and authenticate a newly connected proxy, then negotiate the
+    /// protocol with it
+    async fn establish(&self, socket: Box<dyn transport::Stream>) -> std::result::Result<Link, Refusal> {
+        match &self.tls {
+            Some(acceptor) => {
+                let stream = acceptor.accept(socket).await.map_err(handshake::Error::from)?;
+                self.authenticate(stream).await
+            },
+            None => self.authenticate(socket).await,
+        }
+    }
+
+    async fn authenticate<S>(&self, mut stream: S) -> std::result::Result<Link, Refusal>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let session = match &self.key {
+            Some(key) => Some(handshake::authenticate(&mut stream, key, handshake::Role::Mirror).await?),
+            None => None,
+        };
+        let (agreement, greetings) = hello::exchange(&mut stream, &self.hello).await?;
+        if let Some(session) = &session {
+            if !agreement.capabilities.contains(hello::Capabilities::AUTHENTICATED_FRAMES) {
+                return Err(handshake::Error::UnauthenticatedFrames.into());
+            }
+            handshake::confirm(&mut stream, session, handshake::Role::Mirror, &greetings).await?;
+        }
+        let (sent, received) = session.map(|session| session.frame_macs(handshake::Role::Mirror, &greetings)).unzip();
+        let (rx, tx) = tokio::io::split(stream);
+        let compressed = Codec::agreed(agreement.capabilities).is_some();
+        let receiver = TcpReceiver::new(rx)
+            .with_max_frame_length(self.max_frame_length)
+            .with_compression(compressed)
+            .with_authentication(received);
+        Ok((receiver, Box::new(tx), sent, agreement))
+    }
+}
+
+/// The package name and version of a download session.
+type DownloadKey = (String,String);
+
+/// The running hash of a download, checked against the registry index.
+struct Verification {
+    expected: Checksum,
+    hasher: Sha256,
+}
+
+impl Verification {
+    fn new(expected: Checksum) -> Self {
+        Self{ expected, hasher: Sha256::new() }
+    }
+}
+
+/// A session in-progress with the proxy.
+struct Session {
+    /// what the session is fetching, kept to re-issue it on another uplink
+    resource: up_stream::Resource,
+    /// the uplink the session was issued on, unless it is waiting for one
+    link: Option<u32>,
+    /// the package being downloaded if this session can be shared
+    download: Option<DownloadKey>,
+    /// the messages received so far for a shared session
+    history: Vec<down_stream::Opcode>,
+    /// the recipients of the session's messages
+    subscribers: Vec<mpsc::Sender<down_stream::Opcode>>,
+    /// set once the headers have been delivered to the subscribers
+    started: bool,
+    /// bytes of content delivered to the subscribers so far
+    delivered: usize,
+    /// bytes of content a re-issued session must skip, having already been
+    /// delivered by a lost uplink
+    skip: usize,
+    /// when the session was issued, last heard from or orphaned
+    last_activity: Instant,
+    /// the bytes of content the uplink may send before more is delivered
+    window: Arc<Semaphore>,
+    /// the messages on their way to the subscribers
+    deliveries: mpsc::UnboundedSender<Delivery>,
+    /// the hash of the content delivered so far, if the session is verified
+    verification: Option<Verification>,
+}
+
+impl Session {
+
+    /// prepare a message from the uplink for delivery, dropping what was
+    /// already delivered before the session was re-issued
+    ///
+    /// The successful completion of a verified session is turned into a
+    /// failure if the content delivered doesn't match the expected checksum.
+    fn admit(&mut self, opcode: down_stream::Opcode) -> Option<down_stream::Opcode> {
+        use down_stream::Opcode::*;
+        match opcode {
+            Init(_) if self.started => None,
+            Init(headers) => {
+                self.started = true;
+                Some(Init(headers))
+            },
+            Chunk(buffer) => {
+                let mut bytes = Vec::<u8>::from(buffer);
+                let skipped = self.skip.min(bytes.len());
+                self.skip -= skipped;
+                if skipped == bytes.len() {
+                    return None;
+                }
+                bytes.drain(..skipped);
+                self.delivered += bytes.len();
+                if let Some(verification) = &mut self.verification {
+                    verification.hasher.update(&bytes);
+                }
+                Some(Chunk(bytes.into()))
+            },
+            Complete(Ok(())) => {
+                let verified = self.verification.take()
+                    .is_none_or(|Verification{expected, hasher}| hasher.finalize()[..] == expected[..]);
+                if verified {
+                    Some(Complete(Ok(())))
+                } else {
+                    tracing::warn!(target: "security", "rejected {:?}, the content does not match the registry index checksum", self.resource);
+                    Some(Complete(Err(down_stream::Error::ChecksumMismatch)))
+                }
+            },
+            opcode => Some(opcode),
+        }
+    }
+}
+
+/// Content taken from a session's window, to be given back once delivered.
+struct Credit {
+    window: Arc<Semaphore>,
+    bytes: u32,
+    /// the uplink to grant the credit to, if it agreed on flow control
+    uplink: Option<Requests>,
+}
+
+/// A message on its way to the subscribers of a session.
+struct Delivery {
+    /// the message, unless it was content already delivered
+    opcode: Option<down_stream::Opcode>,
+    /// the subscribers when the message was received, later ones receive it
+    /// with the history
+    subscribers: Vec<mpsc::Sender<down_stream::Opcode>>,
+    credit: Option<Credit>,
+}
+
+/// A subscription to the messages of a download session.
+pub struct Download {
+    /// the messages of the session, from the beginning
+    pub stream: mpsc::Receiver<down_stream::Opcode>,
+    /// set if this subscription initiated the session rather than attaching
+    /// to one already in-progress
+    pub initiated: bool,
+}
+
+/// The messages sent to a connected proxy.
+type Requests = mpsc::Sender<up_stream::Message>;
+
+/// A connected proxy.
+struct Uplink {
+    requests: Requests,
+    /// the optional protocol features agreed with the proxy
+    capabilities: hello::Capabilities,
+}
+
+/// Subscribers of sessions that failed, and why.
+type Failures = Vec<(mpsc::Sender<down_stream::Opcode>, down_stream::Error)>;
+
+/// Requests to re-issue on other uplinks.
+type Reissues = Vec<(u32,Requests,up_stream::Request)>;
+
+/// Sessions the proxy should stop working on.
+type Cancellations = Vec<(Requests,u32)>;
+
+/// the capabilities an uplink needs to fetch a resource
+fn required_capabilities(resource: &up_stream::Resource) -> hello::Capabilities {
+    match resource {
+        up_stream::Resource::Crate{..} => hello::Capabilities::NONE,
+        up_stream::Resource::IndexEntry{..} => hello::Capabilities::INDEX_ENTRIES,
+        up_stream::Resource::CrateFrom{..} => hello::Capabilities::RESUME,
+    }
+}
+
 /// The current state of the proxy connection.
 #[derive(Default)]
 pub struct State{
     last_mux: u32,
-    uplink: Option<mpsc::Sender<up_stream::Request>>,
-    sessions: HashMap<u32,mpsc::Sender<down_stream::Opcode>>,
+    last_link: u32,
+    /// the uplink most recently assigned a session
+    last_pick: u32,
+    uplinks: BTreeMap<u32,Uplink>,
+    sessions: HashMap<u32,Session>,
+    downloads: HashMap<DownloadKey,u32>,
 }
 
 /// Represents a potential connection from the proxy.
-pub struct ProxyConnection(Mutex<State>);
+pub struct ProxyConnection {
+    state: Mutex<State>,
+    /// names that are never requested from the proxy
+    reserved: Arc<ReservedNames>,
+    config: LinkConfig,
+}
 
 impl State {
 
+    /// choose the uplink for a new session fetching `resource`
+    ///
+    /// Picks the uplink with the fewest sessions in-progress among those
+    /// capable of fetching the resource, taking turns between equally busy
+    /// uplinks.
+    fn pick_uplink(&mut self, resource: &up_stream::Resource) -> Option<(u32,Requests)> {
+        let last_pick = self.last_pick;
+        let sessions = &self.sessions;
+        let required = required_capabilities(resource);
+        let (&link, uplink) = self.uplinks.iter()
+            .filter(|(_, uplink)| uplink.capabilities.contains(required))
+            .min_by_key(|(&link, _)| {
+                let load = sessions.values().filter(|session| session.link == Some(link)).count();
+                (load, link <= last_pick, link)
+            })?;
+        self.last_pick = link;
+        Some((link, uplink.requests.clone()))
+    }
+
     /// begin tracking a new download session from the proxy
-    fn add_session(&mut self, tx: mpsc::Sender<down_stream::Opcode>) -> u32 {
-        loop {
+    fn add_session(&mut self, resource: up_stream::Resource, link: u32, download: Option<DownloadKey>, verification: Option<Verification>, tx: mpsc::Sender<down_stream::Opcode>, deliveries: mpsc::UnboundedSender<Delivery>) -> u32 {
+        let mut verification = verification;
+        let session_id = loop {
             use std::collections::hash_map::Entry::*;
-            self.last_mux += 1;
+            self.last_mux = self.last_mux.wrapping_add(1);
             let session_id = self.last_mux;
+            if session_id == down_stream::LINK_SESSION {
+                continue;
+            }
             match self.sessions.entry(session_id) {
                 Occupied(_) => continue,
                 Vacant(entry) => {
-                    entry.insert(tx);
+                    entry.insert(Session{
+                        resource,
+                        link: Some(link),
+                        download: download.clone(),
+                        history: Vec::new(),
+                        subscribers: vec![tx],
+                        started: false,
+                        delivered: 0,
+                        skip: 0,
+                        last_activity: Instant::now(),
+                        window: Arc::new(Semaphore::new(flow::INITIAL_WINDOW as usize)),
+                        deliveries: deliveries.clone(),
+                        verification: verification.take(),
+                    });
                     break session_id;
                 }
             }
+        };
+        if let Some(download) = download {
+            self.downloads.insert(download, session_id);
         }
+        session_id
     }
 
-    /// disassociate with the currently connected proxy and associate with the
-    /// newly provided one
-    pub fn reset_uplink_to(&mut self, stream: TcpSender<up_stream::Request>) -> Result<()> {
-        if self.uplink.is_some() {
-            let sessions = std::mem::replace(&mut self.sessions, Default::default());
+    /// attach to an in-progress download session, replaying its messages so far
+    fn attach_session(&mut self, download: &DownloadKey) -> Option<mpsc::Receiver<down_stream::Opcode>> {
+        let session = self.sessions.get_mut(self.downloads.get(download)?)?;
+        let (mut tx, rx) = mpsc::channel::<down_stream::Opcode>(session.history.len() + 8);
+        for opcode in &session.history {
+            tx.try_send(opcode.clone()).expect("channel sized to hold the history");
+        }
+        session.subscribers.push(tx);
+        Some(rx)
+    }
 
-            // distance ourself from existing connections so that they may take their time cleaning up
-            tokio::spawn(async move {
-                use down_stream::{Opcode::Complete,Error::Unspecified};
-                for (_, mut tx) in sessions {
-                    if let Err(err) = tx.send(Complete(Err(Unspecified))).await {
-                        tracing::error!("failed to cleanly terminated download on upload reset: {:?}", err)
-                    }
-                }
-            });
+    /// stop tracking a session
+    fn remove_session(&mut self, session_id: u32) {
+        if let Some(Session{download: Some(download), ..}) = self.sessions.remove(&session_id) {
+            self.downloads.remove(&download);
+        }
+    }
 
-            self.uplink = None;
-            self.sessions.clear();
+    /// stop tracking a session before it completed, returning where to send
+    /// its cancellation
+    fn cancel_session(&mut self, session_id: u32) -> Option<Requests> {
+        let link = self.sessions.get(&session_id)?.link;
+        self.remove_session(session_id);
+        self.requests(link?)
+    }
+
+    /// where to send messages for an uplink, if it is still connected
+    fn requests(&self, link: u32) -> Option<Requests> {
+        self.uplinks.get(&link).map(|uplink| uplink.requests.clone())
+    }
+
+    /// tear down sessions whose subscribers have all gone away, and fail those
+    /// the proxy has been silent about for longer than `idle` or that waited
+    /// too long for an uplink
+    fn expire_sessions(&mut self, idle: Duration) -> (Cancellations, Failures) {
+
+        let mut expired = Vec::new();
+        let mut failures = Vec::new();
+
+        for (&session_id, session) in self.sessions.iter_mut() {
+            session.subscribers.retain(|tx| !tx.is_closed());
+            if session.subscribers.is_empty() {
+                tracing::debug!("all recipients of session {} went away", session_id);
+                expired.push(session_id);
+                continue;
+            }
+            let error = match session.link {
+                Some(link) if session.last_activity.elapsed() > idle => {
+                    tracing::warn!("session {} for {:?} timed out on uplink {}", session_id, session.resource, link);
+                    down_stream::Error::UpstreamTimeout
+                },
+                None if session.last_activity.elapsed() > RECONNECT_GRACE => {
+                    tracing::warn!("session {} for {:?} failed, no uplink connected in time", session_id, session.resource);
+                    down_stream::Error::LinkReset
+                },
+                _ => continue,
+            };
+            failures.extend(session.subscribers.drain(..).map(|tx| (tx, error.clone())));
+            expired.push(session_id);
         }
 
-        let (tx, rx) = mpsc::channel::<up_stream::Request>(8);
+        let cancellations = expired.into_iter()
+            .filter_map(|session_id| Some((self.cancel_session(session_id)?, session_id)))
+            .collect();
+
+        (cancellations, failures)
+    }
+
+    /// assign a session to a new uplink, returning the request to issue on it
+    ///
+    /// A download the new uplink can resume continues from the content
+    /// already delivered, otherwise that content is skipped when it arrives
+    /// again.
+    fn reissue_session(&mut self, session_id: u32) -> Option<(u32,Requests,up_stream::Request)> {
+
+        let resource = self.sessions.get(&session_id)?.resource.clone();
+        let (link, uplink) = self.pick_uplink(&resource)?;
+        let resume = self.uplinks[&link].capabilities.contains(hello::Capabilities::RESUME);
+        let session = self.sessions.get_mut(&session_id)?;
+
+        session.link = Some(link);
+        session.last_activity = Instant::now();
+        // the content still in the old window is given back to the old uplink
+        session.window = Arc::new(Semaphore::new(flow::INITIAL_WINDOW as usize));
+
+        let resource = match resource {
+            up_stream::Resource::Crate{package} if resume && session.delivered > 0 => {
+                tracing::info!("resuming session {} on uplink {} from byte {}", session_id, link, session.delivered);
+                session.skip = 0;
+                up_stream::Resource::CrateFrom{ package, offset: session.delivered as u64 }
+            },
+            resource => {
+                tracing::info!("re-issuing session {} on uplink {}, skipping {} bytes", session_id, link, session.delivered);
+                session.skip = session.delivered;
+                resource
+            },
+        };
+
+        Some((link, uplink, up_stream::Request{ session_id, resource }))
+    }
+
+    /// add a newly connected proxy to the pool of uplinks
+    fn add_uplink(&mut self, peer: &str, writer: Writer, mac: Option<handshake::FrameMac>, agreement: &hello::Agreement) -> u32 {
 
+        let (tx, rx) = mpsc::channel::<up_stream::Message>(8);
+
+        let version = agreement.version;
+        let codec = Codec::agreed(agreement.capabilities);
+        let peer = peer.to_string();
         tokio::spawn(async move {
-            if let Err(err) = stream.mp_process(rx).await {
-                tracing::error!("uplink failed with: {}", err);
+            let result = if version == 1 {
+                TcpSender::<up_stream::Request>::new(writer).with_compression(codec).with_authentication(mac)
+                    .mp_process(rx.filter_map(|message| futures::future::ready(message.into_v1())))
+                    .await
+            } else {
+                TcpSender::<up_stream::Message>::new(writer).with_compression(codec).with_authentication(mac).mp_process(rx).await
+            };
+            if let Err(err) = result {
+                tracing::error!("uplink to {} failed with: {}", peer, err);
             }
         });
 
-        self.uplink = Some(tx);
-        Ok(())
+        self.last_link += 1;
+        self.uplinks.insert(self.last_link, Uplink{ requests: tx, capabilities: agreement.capabilities });
+        self.last_link
+    }
+
+    /// re-issue the sessions waiting for an uplink, after one connected
+    fn adopt_orphans(&mut self) -> Reissues {
+        let orphaned: Vec<u32> = self.sessions.iter()
+            .filter(|(_, session)| session.link.is_none())
+            .map(|(&session_id, _)| session_id)
+            .collect();
+        orphaned.into_iter().filter_map(|session_id| self.reissue_session(session_id)).collect()
+    }
+
+    /// remove a lost proxy from the pool of uplinks, moving its sessions to
+    /// the remaining uplinks
+    ///
+    /// Sessions no uplink can take wait for one to connect.
+    fn remove_uplink(&mut self, link: u32) -> Reissues {
+
+        let mut reissues = Vec::new();
+
+        if self.uplinks.remove(&link).is_none() {
+            return reissues;
+        }
+
+        tracing::info!("lost uplink {}, {} uplinks remain", link, self.uplinks.len());
+
+        let orphaned: Vec<u32> = self.sessions.iter()
+            .filter(|(_, session)| session.link == Some(link))
+            .map(|(&session_id, _)| session_id)
+            .collect();
+
+        for session_id in orphaned {
+            match self.reissue_session(session_id) {
+                Some(reissue) => reissues.push(reissue),
+                None => {
+                    tracing::info!("session {} is waiting for an uplink", session_id);
+                    if let Some(session) = self.sessions.get_mut(&session_id) {
+                        session.link = None;
+                        session.last_activity = Instant::now();
+                    }
+                },
+            }
+        }
+
+        reissues
     }
 }
 
 impl ProxyConnection {
 
-    /// create a new proxy connection tracker
-    pub fn new() -> Arc<Self> {
-        Arc::new(Self(Mutex::new(Default::default())))
+    /// create a new proxy connection tracker, refusing to request the
+    /// provided reserved names
+    pub fn new(reserved: Arc<ReservedNames>) -> Arc<Self> {
+        Self::with_config(reserved, LinkConfig::from_env())
     }
 
-    /// initiate a download from the proxy
-    pub async fn begin_download(self: &Arc<Self>, package: String, version: String) -> Result<mpsc::Receiver<down_stream::Opcode>> {
-        let (mut uplink, session_id, rx) = {
-            let mut state = self.0.lock().unwrap();
-            if let Some(uplink) = state.uplink.clone() {
+    /// create a new proxy connection tracker, linking with proxies as
+    /// configured
+    pub(crate) fn with_config(reserved: Arc<ReservedNames>, config: LinkConfig) -> Arc<Self> {
+        Arc::new(Self{ state: Mutex::new(Default::default()), reserved, config })
+    }
+
+    /// the path on the http server to accept proxies on over a WebSocket,
+    /// if enabled
+    pub fn websocket_path(&self) -> Option<&str> {
+        self.config.websocket_path.as_deref()
+    }
+
+    /// refuse to request reserved names from the proxy
+    fn check_reserved(&self, package: &str, what: &str) -> Result<()> {
+        if self.reserved.contains(package) {
+            tracing::warn!(target: "security", "refused to request the {} of reserved crate {} from upstream", what, package);
+            Err(Error::Reserved(package.into()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// initiate a download from the proxy, or attach to one in-progress for
+    /// the same package version
+    ///
+    /// The download only completes successfully if its content matches the
+    /// provided checksum.
+    pub async fn begin_download(self: &Arc<Self>, package: PackageId, checksum: Checksum) -> Result<Download> {
+        self.check_reserved(package.name(), "download")?;
+        let download = (package.name().into(), package.version().to_string());
+        self.begin_session(up_stream::Resource::Crate{package}, Some(download), Some(Verification::new(checksum))).await
+    }
+
+    /// initiate a fetch of a sparse index entry from the proxy
+    pub async fn begin_index_fetch(self: &Arc<Self>, package: Name, validators: Validators) -> Result<mpsc::Receiver<down_stream::Opcode>> {
+        self.check_reserved(package.as_str(), "index entry")?;
+        Ok(self.begin_session(up_stream::Resource::IndexEntry{package, validators}, None, None).await?.stream)
+    }
+
+    /// initiate a session fetching the provided resource from the proxy
+    ///
+    /// Attaches to an in-progress session instead if one exists for the
+    /// provided download.
+    async fn begin_session(self: &Arc<Self>, resource: up_stream::Resource, download: Option<DownloadKey>, verification: Option<Verification>) -> Result<Download> {
+        let (link, mut uplink, session_id, rx) = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(stream) = download.as_ref().and_then(|download| state.attach_session(download)) {
+                tracing::trace!("attached to session in-progress for {:?}", resource);
+                return Ok(Download{ stream, initiated: false });
+            }
+            if let Some((link, uplink)) = state.pick_uplink(&resource) {
                 let (tx,rx) = mpsc::channel::<down_stream::Opcode>(8);
-                let session_id = state.add_session(tx);
-                (uplink, session_id, rx)
+                let (deliveries, pending) = mpsc::unbounded();
+                let session_id = state.add_session(resource.clone(), link, download, verification, tx, deliveries);
+                tokio::spawn(self.clone().deliver_session(session_id, pending));
+                (link, uplink, session_id, rx)
             } else {
                 return Err(Error::NoUplink);
             }
         };
-        tracing::trace!("beginning proxy download of {}/{} on {}", package, version ,session_id);
-        uplink.send(up_stream::Request{session_id, package, version}).await.map_err(|_|Error::UpLinkReset)?;
-        Ok(rx)
+        tracing::trace!("beginning proxy session {} for {:?}", session_id, resource);
+        if uplink.send(up_stream::Request{session_id, resource}.into()).await.is_err() {
+            // the session is re-issued along with the others of the lost uplink
+            self.lose_uplink(link).await;
+        }
+        Ok(Download{ stream: rx, initiated: true })
+    }
+
+    /// send a message to an uplink, unless it was lost
+    async fn send_to(&self, link: u32, message: up_stream::Message) {
+        let uplink = self.state.lock().unwrap().requests(link);
+        if let Some(mut uplink) = uplink {
+            let _ = uplink.send(message).await;
+        }
+    }
+
+    /// handle a message concerning the link itself
+    async fn process_link_message(&self, link: u32, opcode: down_stream::Opcode, heartbeat: Option<&Mutex<heartbeat::Heartbeat>>) {
+        use down_stream::Opcode::*;
+        match (opcode, heartbeat) {
+            (Ping(id), _) => self.send_to(link, up_stream::Message::Pong(id)).await,
+            (Pong(id), Some(heartbeat)) => match heartbeat.lock().unwrap().pong(id) {
+                Some((rtt, true)) => tracing::info!("uplink {} round trip time is {:?}", link, rtt),
+                Some((rtt, false)) => tracing::debug!("uplink {} round trip time is {:?}", link, rtt),
+                None => (),
+            },
+            (opcode, _) => tracing::warn!("unexpected message for uplink {}: {:?}", link, opcode),
+        }
+    }
+
+    /// ping an uplink at the heartbeat interval, returning once it has been
+    /// silent for too long
+    async fn ping_uplink(&self, link: u32, heartbeat: &Mutex<heartbeat::Heartbeat>) {
+        let mut ticks = interval(heartbeat.lock().unwrap().interval());
+        loop {
+            ticks.tick().await;
+            let ping = heartbeat.lock().unwrap().tick();
+            match ping {
+                Some(id) => self.send_to(link, up_stream::Message::Ping(id)).await,
+                None => return,
+            }
+        }
+    }
+
+    /// take the content of a chunk from its session's window, waiting for the
+    /// window to open if the uplink has no flow control
+    async fn take_window(&self, link: u32, session_id: u32, opcode: &down_stream::Opcode, flow_control: bool) -> Result<Option<(Arc<Semaphore>, u32)>> {
+        let length = match opcode {
+            down_stream::Opcode::Chunk(buffer) => buffer.as_ref().len(),
+            _ => return Ok(None),
+        };
+        let window = self.state.lock().unwrap().sessions.get(&session_id)
+            .filter(|session| session.link == Some(link))
+            .map(|session| session.window.clone());
+        let window = match window {
+            Some(window) => window,
+            None => return Ok(None),
+        };
+        // an uplink without flow control may send larger chunks than fit the window
+        let bytes = length.min(flow::INITIAL_WINDOW as usize) as u32;
+        let permit = if flow_control {
+            window.try_acquire_many(bytes).map_err(|_| Error::FlowControl(session_id))?
+        } else {
+            window.acquire_many(bytes).await.expect("session windows to stay open")
+        };
+        permit.forget();
+        Ok(Some((window, bytes)))
     }
 
     /// process incoming download message from the proxy
-    async fn process_receives(self: &Arc<Self>, mut stream: TcpReceiver<down_stream::Message>) -> Result<()> {
+    async fn process_receives(self: &Arc<Self>, link: u32, mut stream: TcpReceiver<down_stream::Message>, flow_control: bool, heartbeat: Option<&Mutex<heartbeat::Heartbeat>>) -> Result<()> {
 
         while let Some(down_stream::Message{session_id, opcode}) = stream.next().await? {
             tracing::trace!("down_stream message received for {}: {:?}", session_id, opcode);
-            use std::collections::hash_map::Entry::*;
-            let res = match self.0.lock().unwrap().sessions.entry(session_id) {
-                Occupied(entry) => Some(entry.get().clone()),
-                Vacant(_) => None,
-            };
-            match res {
-                Some(mut sender) => if let Err(err) = sender.send(opcode).await {
-                    tracing::error!("failed to deliver message for session {} with: {}", session_id, err);
-                    if let Occupied(entry) = self.0.lock().unwrap().sessions.entry(session_id) {
-                        entry.remove();
+
+            if let Some(heartbeat) = heartbeat {
+                heartbeat.lock().unwrap().heard();
+            }
+
+            if session_id == down_stream::LINK_SESSION {
+                self.process_link_message(link, opcode, heartbeat).await;
+                continue;
+            }
+
+            let complete = matches!(opcode, down_stream::Opcode::Complete(_));
+
+            let window = self.take_window(link, session_id, &opcode, flow_control).await?;
+
+            let mut state = self.state.lock().unwrap();
+            let uplink = state.requests(link).filter(|_| flow_control);
+            let credit = window.map(|(window, bytes)| Credit{ window, bytes, uplink });
+            let delivered = match state.sessions.get_mut(&session_id).filter(|session| session.link == Some(link)) {
+                Some(session) => {
+                    session.last_activity = Instant::now();
+                    let opcode = session.admit(opcode);
+                    match &opcode {
+                        Some(opcode) if session.download.is_some() && !complete => session.history.push(opcode.clone()),
+                        Some(_) => (),
+                        None => tracing::trace!("skipped content already delivered for session {}", session_id),
                     }
+                    let subscribers = session.subscribers.clone();
+                    session.deliveries.unbounded_send(Delivery{ opcode, subscribers, credit }).is_ok()
                 },
-                None => tracing::warn!("received fragment for unknown session {}", session_id),
+                None => {
+                    tracing::debug!("received fragment for unknown session {}", session_id);
+                    false
+                },
+            };
+            if complete && delivered {
+                state.remove_session(session_id);
             }
         }
 
         Ok(())
     }
 
-    /// listen for connections from the proxy, replaces the existing one with new ones.
+    /// hand the messages of a session to its subscribers, giving back the
+    /// content to the session's window once delivered
+    async fn deliver_session(self: Arc<Self>, session_id: u32, mut pending: mpsc::UnboundedReceiver<Delivery>) {
+
+        while let Some(Delivery{opcode, subscribers, credit}) = pending.next().await {
+
+            if let Some(opcode) = opcode {
+                let sends = subscribers.into_iter().map(|mut sender| {
+                    let opcode = opcode.clone();
+                    async move {
+                        match timeout(SUBSCRIBER_STALL_TIMEOUT, sender.send(opcode)).await {
+                            Ok(Ok(())) => true,
+                            Ok(Err(err)) => {
+                                tracing::debug!("failed to deliver message for session {} with: {}", session_id, err);
+                                false
+                            },
+                            Err(_) => {
+                                tracing::warn!("dropping a recipient of session {} that stalled for {:?}", session_id, SUBSCRIBER_STALL_TIMEOUT);
+                                sender.close_channel();
+                                false
+                            },
+                        }
+                    }
+                });
+                let failed = futures::future::join_all(sends).await.contains(&false);
+                if failed {
+                    let cancellation = {
+                        let mut state = self.state.lock().unwrap();
+                        match state.sessions.get_mut(&session_id) {
+                            Some(session) => {
+                                session.subscribers.retain(|tx| !tx.is_closed());
+                                if session.subscribers.is_empty() {
+                                    tracing::debug!("no remaining recipients for session {}", session_id);
+                                    state.cancel_session(session_id)
+                                } else {
+                                    None
+                                }
+                            },
+                            None => None,
+                        }
+                    };
+                    if let Some(uplink) = cancellation {
+                        Self::send_cancellations(vec![(uplink, session_id)]).await;
+                    }
+                }
+            }
+
+            if let Some(Credit{window, bytes, uplink}) = credit {
+                window.add_permits(bytes as usize);
+                if let Some(mut uplink) = uplink {
+                    let _ = uplink.send(up_stream::Message::Credit{ session_id, bytes }).await;
+                }
+            }
+        }
+    }
+
+    /// serve a connected proxy until it disconnects, then hand its sessions to
+    /// the remaining uplinks
+    async fn run_uplink(self: Arc<Self>, (rx, writer, mac, agreement): Link, from: String, heartbeat: heartbeat::Config) {
+
+        let (link, reissues) = {
+            let mut state = self.state.lock().unwrap();
+            let link = state.add_uplink(&from, writer, mac, &agreement);
+            tracing::info!("uplink {} connected from {}, {} uplinks available", link, from, state.uplinks.len());
+            (link, state.adopt_orphans())
+        };
+
+        tracing::info!(
+            "uplink {} runs {} with protocol version {}, capabilities: {}",
+            link, agreement.peer_software, agreement.version, agreement.capabilities,
+        );
+
+        self.send_reissues(reissues).await;
+
+        let heartbeat = agreement.capabilities.contains(hello::Capabilities::HEARTBEAT)
+            .then(|| Mutex::new(heartbeat::Heartbeat::new(heartbeat)));
+
+        let flow_control = agreement.capabilities.contains(hello::Capabilities::FLOW_CONTROL);
+
+        let result = match &heartbeat {
+            Some(heartbeat) => tokio::select! {
+                result = self.process_receives(link, rx, flow_control, Some(heartbeat)) => result,
+                _ = self.ping_uplink(link, heartbeat) => {
+                    let deadline = heartbeat.lock().unwrap().deadline();
+                    tracing::warn!("uplink {} is dead, nothing was heard from it for {:?}", link, deadline);
+                    Ok(())
+                },
+            },
+            None => self.process_receives(link, rx, flow_control, None).await,
+        };
+
+        if let Err(err) = result {
+            tracing::error!("receive process failed with: {}", err);
+        }
+
+        tracing::info!("uplink {} from {} disconnected", link, from);
+
+        self.lose_uplink(link).await;
+    }
+
+    /// remove a lost uplink from the pool, re-issuing its sessions on the
+    /// remaining uplinks or leaving them to wait for one to connect
+    async fn lose_uplink(&self, link: u32) {
+        let reissues = self.state.lock().unwrap().remove_uplink(link);
+        self.send_reissues(reissues).await;
+    }
+
+    /// issue sessions assigned to new uplinks, losing those that turn out to
+    /// be gone as well
+    async fn send_reissues(&self, mut reissues: Reissues) {
+        while let Some((link, mut uplink, request)) = reissues.pop() {
+            if uplink.send(request.into()).await.is_err() {
+                reissues.extend(self.state.lock().unwrap().remove_uplink(link));
+            }
+        }
+    }
+
+    /// tell the subscribers of failed sessions why their download failed
+    fn fail_subscribers(failures: Failures) {
+        // distance ourself from existing connections so that they may take their time cleaning up
+        tokio::spawn(async move {
+            use down_stream::Opcode::Complete;
+            for (mut tx, error) in failures {
+                if let Err(err) = tx.send(Complete(Err(error))).await {
+                    tracing::debug!("failed to cleanly terminate failed download: {:?}", err)
+                }
+            }
+        });
+    }
+
+    /// tell proxies to stop working on sessions that were torn down
+    async fn send_cancellations(cancellations: Cancellations) {
+        for (mut uplink, session_id) in cancellations {
+            tracing::debug!("cancelling session {}", session_id);
+            // a lost uplink has stopped working on the session anyway
+            let _ = uplink.send(up_stream::Message::Cancel{ session_id }).await;
+        }
+    }
+
+    /// periodically tear down sessions that were abandoned by their
+    /// subscribers or by the proxy, or that no uplink took over in time
+    async fn expire_sessions(self: Arc<Self>) {
+        let mut sweep = interval(SESSION_SWEEP_INTERVAL);
+        loop {
+            sweep.tick().await;
+            let (cancellations, failures) = self.state.lock().unwrap().expire_sessions(SESSION_IDLE_TIMEOUT);
+            Self::fail_subscribers(failures);
+            Self::send_cancellations(cancellations).await;
+        }
+    }
+
+    /// secure and authenticate a newly connected proxy, as configured, and
+    /// negotiate the protocol before adding it to the pool of uplinks
+    ///
+    /// Returns whether the link was established.
+    async fn accept_uplink(self: Arc<Self>, socket: Box<dyn transport::Stream>, from: String) -> bool {
+        match timeout(HANDSHAKE_TIMEOUT, self.config.establish(socket)).await {
+            Ok(Ok(link)) => {
+                let heartbeat = self.config.heartbeat;
+                self.run_uplink(link, from, heartbeat).await;
+                return true;
+            },
+            Ok(Err(Refusal::Handshake(err))) => tracing::warn!(target: "security", "rejected proxy link from {}: {}", from, err),
+            Ok(Err(Refusal::Hello(err))) => tracing::error!("refused proxy link from {}: {}", from, err),
+            Err(_) => tracing::warn!(target: "security", "rejected proxy link from {}: handshake timed out", from),
+        }
+        false
+    }
+
+    /// accept a newly connected proxy, from the listener or a WebSocket to the
+    /// http server
+    pub fn accept(self: &Arc<Self>, socket: Box<dyn transport::Stream>, from: String) {
+        tracing::info!("accepted connection from: {}", from);
+        tokio::spawn(self.clone().accept_uplink(socket, from));
+    }
+
+    /// connect to a proxy listening for the mirror, and connect again whenever
+    /// the link is lost, backing off while the proxy can't be reached
+    async fn dial_uplink(self: Arc<Self>, end_point: transport::EndPoint) {
+        let mut backoff = Backoff::new(DIAL_INITIAL_DELAY, DIAL_MAX_DELAY);
+        let mut reported = false;
+        loop {
+            match end_point.connect().await {
+                Ok(socket) => {
+                    tracing::debug!("connected to proxy at: {}", end_point);
+                    if self.clone().accept_uplink(socket, end_point.to_string()).await {
+                        backoff.reset();
+                        reported = false;
+                    }
+                },
+                Err(err) if !reported => {
+                    tracing::error!("failed connection to proxy at {} with: {}", end_point, err);
+                    reported = true;
+                },
+                Err(err) => tracing::debug!("failed connection to proxy at {} with: {}", end_point, err),
+            }
+            let delay = backoff.next_delay();
+            tracing::debug!("dialing proxy at {} in {:?}", end_point, delay);
+            sleep(delay).await;
+        }
+    }
+
+    /// listen for connections from proxies, and dial those listening for the
+    /// mirror, adding each to the pool of uplinks.
     ///
     /// All received messages from the proxy are handled by the [Self::process_receives] function
     pub async fn serve(self: Arc<Self>)-> Result<()> {
 
-        let local_end_point = std::env::var("CPM_MIRROR_PROXY_LOCAL_END_POINT").expect("value for `CPM_MIRROR_PROXY_LOCAL_END_POINT`");
+        let local_end_point = std::env::var("CPM_MIRROR_PROXY_LOCAL_END_POINT").ok().map(|end_point| {
+            transport::EndPoint::from_str(&end_point).unwrap_or_else(|err| panic!("legal end point value for `CPM_MIRROR_PROXY_LOCAL_END_POINT`: {}", err))
+        });
+
+        let remote_end_points = std::env::var("CPM_MIRROR_PROXY_REMOTE_END_POINTS").unwrap_or_default().split(',')
+            .map(str::trim)
+            .filter(|end_point| !end_point.is_empty())
+            .map(|end_point| transport::EndPoint::from_str(end_point).unwrap_or_else(|err| panic!("legal end points for `CPM_MIRROR_PROXY_REMOTE_END_POINTS`: {}", err)))
+            .collect::<Vec<_>>();
+
+        if local_end_point.is_none() && remote_end_points.is_empty() && self.websocket_path().is_none() {
+            panic!("value for `CPM_MIRROR_PROXY_LOCAL_END_POINT`, `CPM_MIRROR_PROXY_REMOTE_END_POINTS` or `CPM_LINK_WEBSOCKET_PATH`");
+        }
+
+        tokio::spawn(self.clone().expire_sessions());
 
-        let local_end_point = SocketAddr::from_str(&local_end_point).expect("legal end point value for `CPM_MIRROR_PROXY_LOCAL_END_POINT`");
+        for end_point in remote_end_points {
+            tracing::info!("dialing proxy at: {}", end_point);
+            tokio::spawn(self.clone().dial_uplink(end_point));
+        }
 
-        let listener = TcpListener::bind(local_end_point).await?;
+        let local_end_point = match local_end_point {
+            Some(local_end_point) => local_end_point,
+            None => return futures::future::pending().await,
+        };
+
+        let listener = local_end_point.listen().await?;
+        tracing::info!("listening for proxies on: {}", local_end_point);
         loop {
             let (socket, from) = listener.accept().await?;
-            tracing::info!("accepted connection from: {}", from);
-            let (rx,tx) = socket.into_split();
-            self.0.lock().unwrap().reset_uplink_to(tx.into())?;
-            match self.process_receives(rx.into()).await {
-                Err(err) => {
-                    tracing::error!("receive process failed with: {}", err);
-                },
-                Ok(_) => {},
-            }
+            self.accept(socket, from);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use down_stream::Opcode::*;
+
+    const CONTENT: &[u8] = b"the content of a crate";
+
+    fn session(checksum: Option<Checksum>) -> Session {
+        Session{
+            resource: up_stream::Resource::Crate{ package: PackageId::new("log", "0.4.14").unwrap() },
+            link: Some(1),
+            download: Some(("log".into(), "0.4.14".into())),
+            history: Vec::new(),
+            subscribers: Vec::new(),
+            started: false,
+            delivered: 0,
+            skip: 0,
+            last_activity: Instant::now(),
+            window: Arc::new(Semaphore::new(flow::INITIAL_WINDOW as usize)),
+            deliveries: mpsc::unbounded().0,
+            verification: checksum.map(Verification::new),
+        }
+    }
+
+    fn checksum(content: &[u8]) -> Checksum {
+        Sha256::digest(content).into()
+    }
+
+    fn chunk(bytes: &[u8]) -> down_stream::Opcode {
+        Chunk(bytes.to_vec().into())
+    }
+
+    fn completion(session: &mut Session, chunks: &[&[u8]]) -> Option<down_stream::Opcode> {
+        for bytes in chunks {
+            session.admit(chunk(bytes));
+        }
+        session.admit(Complete(Ok(())))
+    }
+
+    #[test]
+    fn matching_content_completes() {
+        let mut session = session(Some(checksum(CONTENT)));
+        assert!(matches!(completion(&mut session, &[&CONTENT[..5], &CONTENT[5..]]), Some(Complete(Ok(())))));
+    }
+
+    #[test]
+    fn tampered_content_fails() {
+        let mut session = session(Some(checksum(CONTENT)));
+        assert!(matches!(completion(&mut session, &[b"the content of a crab"]), Some(Complete(Err(down_stream::Error::ChecksumMismatch)))));
+    }
+
+    #[test]
+    fn truncated_content_fails() {
+        let mut session = session(Some(checksum(CONTENT)));
+        assert!(matches!(completion(&mut session, &[&CONTENT[..5]]), Some(Complete(Err(down_stream::Error::ChecksumMismatch)))));
+    }
+
+    #[test]
+    fn unverified_sessions_complete() {
+        let mut session = session(None);
+        assert!(matches!(completion(&mut session, &[b"anything"]), Some(Complete(Ok(())))));
+    }
+
+    #[test]
+    fn content_skipped_after_a_reissue_is_hashed_once() {
+        let mut session = session(Some(checksum(CONTENT)));
+        session.admit(chunk(&CONTENT[..8]));
+        // the new uplink sends the download again from the beginning
+        session.skip = session.delivered;
+        assert!(session.admit(chunk(&CONTENT[..4])).is_none());
+        assert!(matches!(session.admit(chunk(&CONTENT[4..12])), Some(Chunk(buffer)) if buffer.as_ref() == &CONTENT[8..12]));
+        assert!(matches!(completion(&mut session, &[&CONTENT[12..]]), Some(Complete(Ok(())))));
+    }
+
+    #[test]
+    fn failures_from_the_proxy_are_passed_on() {
+        let mut session = session(Some(checksum(CONTENT)));
+        assert!(matches!(session.admit(Complete(Err(down_stream::Error::NotFound))), Some(Complete(Err(down_stream::Error::NotFound)))));
+    }
+
+    /// lose the uplink of a session after it delivered `delivered` bytes of
+    /// content, with an uplink of the provided capabilities left to take over,
+    /// returning the request re-issuing the session and the session
+    fn reissue_after(delivered: usize, capabilities: hello::Capabilities) -> (up_stream::Request, Session) {
+        let mut state = State::default();
+        let (lost, _rx1) = connect(&mut state, hello::Capabilities::NONE);
+        let (session_id, _subscriber) = begin(&mut state, crate_resource());
+        state.sessions.get_mut(&session_id).unwrap().admit(chunk(&CONTENT[..delivered]));
+        let (survivor, _rx2) = connect(&mut state, capabilities);
+
+        let mut reissues = state.remove_uplink(lost);
+        let (link, _, request) = reissues.pop().unwrap();
+        assert!(reissues.is_empty());
+        assert_eq!(link, survivor);
+        assert_eq!(request.session_id, session_id);
+        (request, state.sessions.remove(&session_id).unwrap())
+    }
+
+    #[test]
+    fn reissued_sessions_resume_from_the_content_delivered() {
+        let (request, mut session) = reissue_after(8, hello::Capabilities::RESUME);
+        assert!(matches!(&request.resource, up_stream::Resource::CrateFrom{ package, offset: 8 } if package.to_string() == "log/0.4.14"));
+        assert_eq!(session.skip, 0);
+        // the new uplink sends the rest of the download
+        assert!(matches!(session.admit(chunk(&CONTENT[8..])), Some(Chunk(buffer)) if buffer.as_ref() == &CONTENT[8..]));
+        assert_eq!(session.delivered, CONTENT.len());
+    }
+
+    #[test]
+    fn reissued_sessions_skip_the_content_delivered_without_resume() {
+        let (request, mut session) = reissue_after(8, hello::Capabilities::NONE);
+        assert!(matches!(&request.resource, up_stream::Resource::Crate{ package } if package.to_string() == "log/0.4.14"));
+        assert_eq!(session.skip, 8);
+        // the new uplink sends the download from the beginning
+        assert!(matches!(session.admit(chunk(CONTENT)), Some(Chunk(buffer)) if buffer.as_ref() == &CONTENT[8..]));
+        assert_eq!(session.delivered, CONTENT.len());
+    }
+
+    #[test]
+    fn reissued_sessions_start_over_when_nothing_was_delivered() {
+        let (request, session) = reissue_after(0, hello::Capabilities::RESUME);
+        assert!(matches!(&request.resource, up_stream::Resource::Crate{..}));
+        assert_eq!(session.skip, 0);
+    }
+
+    /// connect an uplink with the provided capabilities, returning what is
+    /// sent to it
+    fn connect(state: &mut State, capabilities: hello::Capabilities) -> (u32, mpsc::Receiver<up_stream::Message>) {
+        let (requests, rx) = mpsc::channel(8);
+        state.last_link += 1;
+        state.uplinks.insert(state.last_link, Uplink{ requests, capabilities });
+        (state.last_link, rx)
+    }
+
+    /// begin a session on the uplink picked for it, returning its id and the
+    /// messages for its subscriber
+    fn begin(state: &mut State, resource: up_stream::Resource) -> (u32, mpsc::Receiver<down_stream::Opcode>) {
+        let (link, _) = state.pick_uplink(&resource).unwrap();
+        let (tx, rx) = mpsc::channel(8);
+        (state.add_session(resource, link, None, None, tx, mpsc::unbounded().0), rx)
+    }
+
+    fn crate_resource() -> up_stream::Resource {
+        up_stream::Resource::Crate{ package: PackageId::new("log", "0.4.14").unwrap() }
+    }
+
+    fn index_resource() -> up_stream::Resource {
+        up_stream::Resource::IndexEntry{ package: Name::new("log").unwrap(), validators: Validators::default() }
+    }
+
+    fn link_of(state: &State, session_id: u32) -> Option<u32> {
+        state.sessions[&session_id].link
+    }
+
+    #[test]
+    fn sessions_go_to_the_least_busy_uplink() {
+        let mut state = State::default();
+        let (first, _rx1) = connect(&mut state, hello::Capabilities::NONE);
+        let (second, _rx2) = connect(&mut state, hello::Capabilities::NONE);
+        let sessions: Vec<_> = (0..4).map(|_| begin(&mut state, crate_resource())).collect();
+        let links: Vec<_> = sessions.iter().map(|(session_id, _)| link_of(&state, *session_id)).collect();
+        assert_eq!(links, [Some(first), Some(second), Some(first), Some(second)]);
+
+        // the second uplink finished its sessions, so takes the next two
+        state.remove_session(sessions[1].0);
+        state.remove_session(sessions[3].0);
+        assert_eq!(state.pick_uplink(&crate_resource()).unwrap().0, second);
+        begin(&mut state, crate_resource());
+        assert_eq!(state.pick_uplink(&crate_resource()).unwrap().0, second);
+    }
+
+    #[test]
+    fn equally_busy_uplinks_take_turns() {
+        let mut state = State::default();
+        let _uplinks: Vec<_> = (0..3).map(|_| connect(&mut state, hello::Capabilities::NONE)).collect();
+        let picks: Vec<_> = (0..6).map(|_| state.pick_uplink(&crate_resource()).unwrap().0).collect();
+        assert_eq!(picks, [1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sessions_only_go_to_capable_uplinks() {
+        let mut state = State::default();
+        let (_, _rx1) = connect(&mut state, hello::Capabilities::NONE);
+        assert!(state.pick_uplink(&index_resource()).is_none());
+        let (capable, _rx2) = connect(&mut state, hello::Capabilities::INDEX_ENTRIES);
+        for _ in 0..3 {
+            let (session_id, _rx) = begin(&mut state, index_resource());
+            assert_eq!(link_of(&state, session_id), Some(capable));
         }
     }
+
+    #[test]
+    fn sessions_move_to_a_surviving_uplink() {
+        let mut state = State::default();
+        let (lost, _rx1) = connect(&mut state, hello::Capabilities::NONE);
+        let (survivor, _rx2) = connect(&mut state, hello::Capabilities::NONE);
+        let (moved, _subscriber1) = begin(&mut state, crate_resource());
+        let (kept, _subscriber2) = begin(&mut state, crate_resource());
+        assert_eq!(link_of(&state, moved), Some(lost));
+
+        let reissues = state.remove_uplink(lost);
+        assert!(matches!(reissues.as_slice(), [(link, _, request)] if *link == survivor && request.session_id == moved));
+        assert_eq!(link_of(&state, moved), Some(survivor));
+        assert_eq!(link_of(&state, kept), Some(survivor));
+        assert!(state.remove_uplink(lost).is_empty());
+    }
+
+    #[test]
+    fn sessions_wait_for_an_uplink_after_the_last_is_lost() {
+        let mut state = State::default();
+        let (lost, _rx1) = connect(&mut state, hello::Capabilities::NONE);
+        let (session_id, _subscriber) = begin(&mut state, crate_resource());
+
+        assert!(state.remove_uplink(lost).is_empty());
+        assert_eq!(link_of(&state, session_id), None);
+        assert!(state.pick_uplink(&crate_resource()).is_none());
+
+        let (next, _rx2) = connect(&mut state, hello::Capabilities::NONE);
+        let reissues = state.adopt_orphans();
+        assert!(matches!(reissues.as_slice(), [(link, _, request)] if *link == next && request.session_id == session_id));
+        assert_eq!(link_of(&state, session_id), Some(next));
+    }
+
+    #[test]
+    fn sessions_fail_when_no_uplink_connects_in_time() {
+        let mut state = State::default();
+        let (lost, _rx) = connect(&mut state, hello::Capabilities::NONE);
+        let (session_id, _subscriber) = begin(&mut state, crate_resource());
+        state.remove_uplink(lost);
+
+        let (cancellations, failures) = state.expire_sessions(SESSION_IDLE_TIMEOUT);
+        assert!(cancellations.is_empty() && failures.is_empty());
+
+        state.sessions.get_mut(&session_id).unwrap().last_activity -= RECONNECT_GRACE + Duration::from_secs(1);
+        let (cancellations, failures) = state.expire_sessions(SESSION_IDLE_TIMEOUT);
+        // there's no uplink to tell
+        assert!(cancellations.is_empty());
+        assert!(matches!(failures.as_slice(), [(_, down_stream::Error::LinkReset)]));
+        assert!(state.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_subscriber_cancels_the_session() {
+        let mut state = State::default();
+        let (link, mut uplink) = connect(&mut state, hello::Capabilities::NONE);
+        let download: DownloadKey = ("log".into(), "0.4.14".into());
+        let (tx, first) = mpsc::channel(8);
+        let session_id = state.add_session(crate_resource(), link, Some(download.clone()), None, tx, mpsc::unbounded().0);
+        let second = state.attach_session(&download).unwrap();
+
+        drop(first);
+        let (cancellations, failures) = state.expire_sessions(SESSION_IDLE_TIMEOUT);
+        assert!(cancellations.is_empty() && failures.is_empty());
+        assert!(state.attach_session(&download).is_some());
+
+        drop(second);
+        let (cancellations, failures) = state.expire_sessions(SESSION_IDLE_TIMEOUT);
+        assert!(failures.is_empty());
+        assert!(state.sessions.is_empty() && state.downloads.is_empty());
+
+        ProxyConnection::send_cancellations(cancellations).await;
+        assert!(matches!(uplink.next().await, Some(up_stream::Message::Cancel{ session_id: id }) if id == session_id));
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire() {
+        let mut state = State::default();
+        let (_, mut uplink) = connect(&mut state, hello::Capabilities::NONE);
+        let (idle, _subscriber1) = begin(&mut state, crate_resource());
+        let (active, _subscriber2) = begin(&mut state, crate_resource());
+        let ago = |elapsed: Duration| Instant::now() - elapsed;
+        state.sessions.get_mut(&idle).unwrap().last_activity = ago(SESSION_IDLE_TIMEOUT + Duration::from_secs(1));
+        state.sessions.get_mut(&active).unwrap().last_activity = ago(SESSION_IDLE_TIMEOUT - Duration::from_secs(1));
+
+        let (cancellations, failures) = state.expire_sessions(SESSION_IDLE_TIMEOUT);
+        assert!(matches!(failures.as_slice(), [(_, down_stream::Error::UpstreamTimeout)]));
+        assert_eq!(state.sessions.keys().collect::<Vec<_>>(), [&active]);
+
+        ProxyConnection::send_cancellations(cancellations).await;
+        assert!(matches!(uplink.next().await, Some(up_stream::Message::Cancel{ session_id }) if session_id == idle));
+    }
 }
@@ -0,0 +1,722 @@
+//! a private registry overlaid on the upstream index
+//!
+//! Implements the publish, yank and unyank endpoints of the registry web API.
+//! Published crates are kept in the crate store, pinned so they are never
+//! evicted, and listed in an index of their own. A crate listed in the private
+//! index hides any crate of the same name in the upstream index, as does a
+//! reserved name even before anything has been published under it.
+//!
+//! Modifications require the configured token, and are refused altogether
+//! without one. Only reserved names may be published if the upstream index
+//! already lists a crate by that name, so publishing can't shadow an upstream
+//! crate by accident. Names are compared the way crates.io compares them,
+//! ignoring case and treating `-` and `_` alike.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    io,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{Body, Request, Response};
+use hyper::header;
+use hyper::http::{Method, StatusCode, Uri};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest,Sha256};
+use tokio::sync::Mutex;
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use common::package;
+
+use crate::{index::CrateIndex, reserved::{self, ReservedNames}, StoreRef, UsageRef};
+
+/// The largest `.crate` file that may be published.
+const MAX_CRATE_SIZE: usize = 10 * 1024 * 1024;
+
+/// A request to the registry web API.
+pub enum RegistryRequest<'a> {
+    /// `PUT /api/v1/crates/new`
+    Publish,
+    /// `DELETE /api/v1/crates/{name}/{version}/yank`
+    Yank(&'a str, &'a str),
+    /// `PUT /api/v1/crates/{name}/{version}/unyank`
+    Unyank(&'a str, &'a str),
+}
+
+/// An error that can occur while modifying the private registry.
+#[derive(Error,Display,Debug)]
+pub enum Error {
+    /// a valid token is required to modify this registry
+    Unauthorized,
+    /// this registry can't be modified as no token is configured
+    Disabled,
+    /// malformed publish request: {0}
+    Malformed(String),
+    /// the crate is larger than the {0} byte limit
+    TooLarge(usize),
+    /// `{0}` is not a legal crate name
+    IllegalName(String),
+    /// `{0}` is not a legal version
+    IllegalVersion(String),
+    /// crate `{0}` conflicts with the existing crate `{1}`
+    NameConflict(String,String),
+    /// crate `{0}` exists in the upstream registry, its name must be reserved to publish it here
+    ExistsUpstream(String),
+    /// unable to check the upstream registry for `{0}`: {1}
+    Upstream(String,io::Error),
+    /// crate version `{0}@{1}` already exists
+    AlreadyExists(String,String),
+    /// crate version `{0}@{1}` was not published to this registry
+    NotFound(String,String),
+    /// the private index entry for {0} is malformed: {1}
+    CorruptIndex(String,serde_json::Error),
+    /// IO error: {0}
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::Unauthorized | Error::Disabled => StatusCode::FORBIDDEN,
+            Error::Malformed(_) | Error::IllegalName(_) | Error::IllegalVersion(_) => StatusCode::BAD_REQUEST,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::NameConflict(..) | Error::ExistsUpstream(_) | Error::AlreadyExists(..) => StatusCode::CONFLICT,
+            Error::Upstream(..) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::NotFound(..) => StatusCode::NOT_FOUND,
+            Error::CorruptIndex(..) | Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T,Error>;
+
+/// The registry being mirrored.
+#[async_trait]
+pub trait Upstream: Send + Sync {
+    /// check if the upstream index lists a crate, spelled as provided but for
+    /// case
+    async fn lists(&self, name: &str) -> io::Result<bool>;
+}
+
+/// The metadata cargo sends along with a published crate.
+#[derive(Deserialize)]
+struct PublishMetadata {
+    name: String,
+    vers: String,
+    deps: Vec<PublishDependency>,
+    features: BTreeMap<String,Vec<String>>,
+    links: Option<String>,
+    #[serde(default)]
+    rust_version: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PublishDependency {
+    name: String,
+    version_req: String,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: String,
+    registry: Option<String>,
+    explicit_name_in_toml: Option<String>,
+}
+
+/// A line of the index describing a published version.
+#[derive(Serialize)]
+struct IndexLine {
+    name: String,
+    vers: String,
+    deps: Vec<IndexDependency>,
+    cksum: String,
+    features: BTreeMap<String,Vec<String>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    features2: BTreeMap<String,Vec<String>>,
+    yanked: bool,
+    links: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rust_version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IndexDependency {
+    name: String,
+    req: String,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: String,
+    registry: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    package: Option<String>,
+}
+
+impl From<PublishDependency> for IndexDependency {
+    fn from(dep: PublishDependency) -> Self {
+        // the index names a renamed dependency by its name in the manifest
+        let (name, package) = match dep.explicit_name_in_toml {
+            Some(explicit_name) => (explicit_name, Some(dep.name)),
+            None => (dep.name, None),
+        };
+        Self{
+            name,
+            req: dep.version_req,
+            features: dep.features,
+            optional: dep.optional,
+            default_features: dep.default_features,
+            target: dep.target,
+            kind: dep.kind,
+            registry: dep.registry,
+            package,
+        }
+    }
+}
+
+impl IndexLine {
+    fn new(metadata: PublishMetadata, checksum: &[u8]) -> Self {
+
+        // features using the `dep:` and `?` syntax are hidden from older
+        // versions of cargo in `features2`
+        let (features2, features) = metadata.features.into_iter()
+            .partition::<BTreeMap<_,_>,_>(|(_, values)| values.iter().any(|value| value.starts_with("dep:") || value.contains("?/")));
+
+        Self{
+            name: metadata.name,
+            vers: metadata.vers,
+            deps: metadata.deps.into_iter().map(IndexDependency::from).collect(),
+            cksum: checksum.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            v: if features2.is_empty() { None } else { Some(2) },
+            features,
+            features2,
+            yanked: false,
+            links: metadata.links,
+            rust_version: metadata.rust_version,
+        }
+    }
+}
+
+/// check a crate name and version against the rules of crates.io, see
+/// [package]
+fn check_package(name: &str, version: &str) -> Result<()> {
+    package::check_name(name).map_err(|_| Error::IllegalName(name.into()))?;
+    package::parse_version(version).map_err(|_| Error::IllegalVersion(version.into()))?;
+    Ok(())
+}
+
+/// compare a presented token with the configured one in constant time, by
+/// verifying a MAC of one against the other
+fn same_token(presented: &[u8], token: &[u8]) -> bool {
+    let mac = |token: &[u8]| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"cpm-publish-token").expect("HMAC to accept any key");
+        mac.update(token);
+        mac
+    };
+    mac(presented).verify(&mac(token).finalize().into_bytes()).is_ok()
+}
+
+/// the spellings a crate conflicting with the provided name is most likely
+/// listed under upstream: as written, and with every `_` or `-` replaced by
+/// the other, the index itself ignores case
+fn upstream_spellings(name: &str) -> Vec<String> {
+    let mut spellings = vec![name.to_string(), name.replace('_', "-"), name.replace('-', "_")];
+    spellings.sort();
+    spellings.dedup();
+    spellings
+}
+
+/// versions that only differ in build metadata are the same version
+fn same_version(a: &str, b: &str) -> bool {
+    a.split('+').next() == b.split('+').next()
+}
+
+/// split a publish request body into its metadata and `.crate` file
+fn split_publish_body(body: &[u8]) -> Result<(&[u8],&[u8])> {
+
+    fn take<'a>(body: &mut &'a [u8], what: &str) -> Result<&'a [u8]> {
+        let malformed = || Error::Malformed(format!("truncated {}", what));
+        let length = body.get(..4).ok_or_else(malformed)?;
+        let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+        let content = body.get(4..4 + length).ok_or_else(malformed)?;
+        *body = &body[4 + length..];
+        Ok(content)
+    }
+
+    let mut body = body;
+    let metadata = take(&mut body, "metadata")?;
+    let content = take(&mut body, "crate")?;
+
+    if !body.is_empty() {
+        return Err(Error::Malformed("trailing bytes".into()));
+    }
+
+    Ok((metadata, content))
+}
+
+/// The crates published directly to the mirror.
+pub struct Registry {
+    index: CrateIndex,
+    token: Option<String>,
+    reserved: Arc<ReservedNames>,
+    /// the URL cargo reaches the mirror at
+    base_url: String,
+    /// serializes modifications of the private index
+    lock: Mutex<()>,
+}
+
+impl Registry {
+
+    /// use the provided private index, requiring modifications to present the
+    /// provided token and refusing them all without one
+    pub fn new(index: CrateIndex, token: Option<String>, reserved: Arc<ReservedNames>, base_url: String) -> Self {
+        Self{ index, token, reserved, base_url, lock: Mutex::new(()) }
+    }
+
+    /// the URL cargo reaches the mirror at, without a trailing `/`
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// the directory containing the private index
+    pub fn root(&self) -> &std::path::Path {
+        self.index.root()
+    }
+
+    /// read a crate's entry if it was published to this registry
+    pub async fn entry(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.index.read_entry(name).await?.map(|(content, _)| content))
+    }
+
+    /// check if a crate name is reserved for this registry
+    pub fn is_reserved(&self, name: &str) -> bool {
+        self.reserved.contains(name)
+    }
+
+    /// check if a crate belongs to this registry, whether published or
+    /// reserved, and so must never be looked up upstream
+    pub async fn is_private(&self, name: &str) -> io::Result<bool> {
+        Ok(self.is_reserved(name) || self.entry(name).await?.is_some())
+    }
+
+    /// check if a crate version was published to this registry
+    pub async fn has_version(&self, name: &str, version: &str) -> Result<bool> {
+        Ok(self.read_lines(name).await?.iter().any(|line| line["vers"].as_str() == Some(version)))
+    }
+
+    fn authorize(&self, req: &Request<Body>) -> Result<()> {
+        self.check_token(req.headers().get(header::AUTHORIZATION).map(|value| value.as_bytes()))
+    }
+
+    /// check a presented token against the configured one, which operators
+    /// also present to pin crates through the `cpm` API
+    pub fn check_token(&self, presented: Option<&[u8]>) -> Result<()> {
+        let token = self.token.as_ref().ok_or(Error::Disabled)?;
+        if presented.is_some_and(|presented| same_token(presented, token.as_bytes())) {
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    }
+
+    /// read the lines of a crate's entry, checking that it is spelled the
+    /// same way as any previously published version
+    async fn read_lines(&self, name: &str) -> Result<Vec<serde_json::Value>> {
+
+        let lines = self.read_entry_lines(name).await?;
+
+        if let Some(existing) = lines.first().and_then(|line| line["name"].as_str()) {
+            if existing != name {
+                return Err(Error::NameConflict(name.into(), existing.into()));
+            }
+        }
+
+        Ok(lines)
+    }
+
+    async fn read_entry_lines(&self, name: &str) -> Result<Vec<serde_json::Value>> {
+
+        let content = match self.entry(name).await? {
+            Some(content) => content,
+            None => return Ok(Vec::new()),
+        };
+
+        content.split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice::<serde_json::Value>)
+            .collect::<std::result::Result<Vec<_>,_>>()
+            .map_err(|err| Error::CorruptIndex(name.into(), err))
+    }
+
+    /// find the name of a published crate that crates.io would consider the
+    /// same as the provided one, which may be spelled differently
+    async fn similar_crate(&self, name: &str) -> Result<Option<String>> {
+
+        let normalized = reserved::normalize(name);
+
+        for entry in self.index.names().await? {
+            if reserved::normalize(&entry) == normalized {
+                let lines = self.read_entry_lines(&entry).await?;
+                if let Some(existing) = lines.first().and_then(|line| line["name"].as_str()) {
+                    return Ok(Some(existing.into()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn write_lines(&self, name: &str, lines: &[serde_json::Value]) -> Result<()> {
+        let mut content = Vec::new();
+        for line in lines {
+            serde_json::to_writer(&mut content, line).map_err(io::Error::from)?;
+            content.push(b'\n');
+        }
+        Ok(self.index.write_entry(name, &content).await?)
+    }
+
+    /// add a new crate version to the store and the private index
+    async fn publish(&self, store: &StoreRef, usage: &UsageRef, upstream: &dyn Upstream, body: &[u8]) -> Result<()> {
+
+        let (metadata, content) = split_publish_body(body)?;
+
+        let metadata: PublishMetadata = serde_json::from_slice(metadata)
+            .map_err(|err| Error::Malformed(err.to_string()))?;
+
+        check_package(&metadata.name, &metadata.vers)?;
+
+        if content.len() > MAX_CRATE_SIZE {
+            return Err(Error::TooLarge(MAX_CRATE_SIZE));
+        }
+
+        let name = metadata.name.clone();
+        let version = metadata.vers.clone();
+
+        if !self.is_reserved(&name) {
+            for spelling in upstream_spellings(&name) {
+                if upstream.lists(&spelling).await.map_err(|err| Error::Upstream(name.clone(), err))? {
+                    tracing::warn!(target: "security", "refused to publish {}, which exists in the upstream index", name);
+                    return Err(Error::ExistsUpstream(name));
+                }
+            }
+        }
+
+        let _guard = self.lock.lock().await;
+
+        if let Some(existing) = self.similar_crate(&name).await? {
+            if existing != name {
+                return Err(Error::NameConflict(name, existing));
+            }
+        }
+
+        let mut lines = self.read_lines(&name).await?;
+
+        if lines.iter().any(|line| line["vers"].as_str().is_some_and(|vers| same_version(vers, &version))) {
+            return Err(Error::AlreadyExists(name, version));
+        }
+
+        // store the crate before listing it so the index never refers to a
+        // crate that can't be downloaded
+        let mut put = store.put(&name, &version).await?;
+        put.write(content).await?;
+        put.commit().await?;
+
+        let line = IndexLine::new(metadata, &Sha256::digest(content));
+        lines.push(serde_json::to_value(line).map_err(io::Error::from)?);
+
+        if let Err(err) = self.write_lines(&name, &lines).await {
+            // a crate that was never listed can't be yanked or republished
+            if let Err(err) = store.delete(&name, &version).await {
+                tracing::error!("unable to remove unlisted crate {}/{}: {}", name, version, err);
+            }
+            return Err(err);
+        }
+
+        usage.record_admission(&name, &version, content.len() as u64, true).await;
+
+        tracing::info!("published {}/{}", name, version);
+
+        Ok(())
+    }
+
+    /// set the yanked flag of a published crate version
+    async fn set_yanked(&self, name: &str, version: &str, yanked: bool) -> Result<()> {
+
+        let _guard = self.lock.lock().await;
+
+        let mut lines = self.read_lines(name).await?;
+
+        let line = lines.iter_mut()
+            .find(|line| line["vers"].as_str() == Some(version))
+            .ok_or_else(|| Error::NotFound(name.into(), version.into()))?;
+
+        line["yanked"] = yanked.into();
+
+        self.write_lines(name, &lines).await
+    }
+}
+
+/// Check if the provided request is for the registry web API.
+pub fn parse_request<'a>(method: &Method, uri: &'a Uri) -> Option<RegistryRequest<'a>> {
+
+    let path = uri.path().strip_prefix("/api/v1/crates/")?;
+
+    if path == "new" {
+        return (method == Method::PUT).then_some(RegistryRequest::Publish);
+    }
+
+    let mut parts = path.split('/');
+
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(version), Some("yank"), None) if method == Method::DELETE => Some(RegistryRequest::Yank(name, version)),
+        (Some(name), Some(version), Some("unyank"), None) if method == Method::PUT => Some(RegistryRequest::Unyank(name, version)),
+        _ => None,
+    }
+}
+
+/// Respond to a request for the registry web API.
+pub async fn serve(registry: &Registry, upstream: &dyn Upstream, store: StoreRef, usage: UsageRef, req: Request<Body>, request: RegistryRequest<'_>) -> Response<Body> {
+
+    let result = match registry.authorize(&req) {
+        Ok(()) => match request {
+            RegistryRequest::Publish => publish(registry, upstream, &store, &usage, req).await,
+            RegistryRequest::Yank(name, version) => yank(registry, name, version, true).await,
+            RegistryRequest::Unyank(name, version) => yank(registry, name, version, false).await,
+        },
+        Err(err) => Err(err),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err(err) => {
+            tracing::warn!("registry request failed: {}", err);
+            (err.status(), serde_json::json!({ "errors": [{ "detail": err.to_string() }] }))
+        },
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn publish(registry: &Registry, upstream: &dyn Upstream, store: &StoreRef, usage: &UsageRef, req: Request<Body>) -> Result<serde_json::Value> {
+
+    // the limit on the crate, plus generous room for its metadata
+    let limit = MAX_CRATE_SIZE * 2;
+
+    let length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or_else(|| Error::Malformed("missing content length".into()))?;
+
+    if length > limit {
+        return Err(Error::TooLarge(MAX_CRATE_SIZE));
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await
+        .map_err(|err| Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, err)))?;
+
+    registry.publish(store, usage, upstream, &body).await?;
+
+    Ok(serde_json::json!({ "warnings": { "invalid_categories": [], "invalid_badges": [], "other": [] } }))
+}
+
+async fn yank(registry: &Registry, name: &str, version: &str, yanked: bool) -> Result<serde_json::Value> {
+    check_package(name, version)?;
+    registry.set_yanked(name, version, yanked).await?;
+    tracing::info!("{} {}/{}", if yanked { "yanked" } else { "unyanked" }, name, version);
+    Ok(serde_json::json!({ "ok": true }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::{store::FsStore, usage::{CacheUsage, Policy}};
+
+    /// an upstream index listing the provided names, or unreachable
+    struct FakeUpstream(Option<Vec<&'static str>>);
+
+    #[async_trait]
+    impl Upstream for FakeUpstream {
+        async fn lists(&self, name: &str) -> io::Result<bool> {
+            match &self.0 {
+                Some(names) => Ok(names.iter().any(|listed| listed.eq_ignore_ascii_case(name))),
+                None => Err(io::Error::other("unreachable")),
+            }
+        }
+    }
+
+    struct Fixture {
+        _dir: tempfile::TempDir,
+        registry: Registry,
+        store: StoreRef,
+        usage: UsageRef,
+    }
+
+    async fn fixture(token: Option<&str>, reserved: &str) -> Fixture {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cache")).unwrap();
+        let store: StoreRef = Arc::new(FsStore::new(dir.path().join("cache")));
+        let usage = Arc::new(CacheUsage::load(store.clone(), dir.path().join("usage.json"), None, Policy::Lru).await.unwrap());
+        let registry = Registry::new(CrateIndex::new(dir.path().join("index")), token.map(String::from), Arc::new(ReservedNames::parse(reserved)), "http://mirror".into());
+        Fixture{ _dir: dir, registry, store, usage }
+    }
+
+    fn body(name: &str, version: &str, content: &[u8]) -> Vec<u8> {
+        let metadata = serde_json::json!({
+            "name": name,
+            "vers": version,
+            "deps": [],
+            "features": {},
+            "links": null,
+        }).to_string();
+        let mut body = Vec::new();
+        body.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        body.extend_from_slice(metadata.as_bytes());
+        body.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        body.extend_from_slice(content);
+        body
+    }
+
+    impl Fixture {
+        async fn publish(&self, name: &str, version: &str, upstream: &FakeUpstream) -> Result<()> {
+            self.registry.publish(&self.store, &self.usage, upstream, &body(name, version, b"content")).await
+        }
+
+        async fn yanked(&self, name: &str, version: &str) -> Option<bool> {
+            let lines = self.registry.read_lines(name).await.unwrap();
+            lines.iter().find(|line| line["vers"] == version).map(|line| line["yanked"].as_bool().unwrap())
+        }
+    }
+
+    const NOTHING_UPSTREAM: FakeUpstream = FakeUpstream(Some(Vec::new()));
+
+    fn request(token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(Method::PUT).uri("/api/v1/crates/new");
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, token);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_the_configured_token() {
+        let fixture = fixture(Some("secret"), "").await;
+        assert!(fixture.registry.authorize(&request(Some("secret"))).is_ok());
+        assert!(matches!(fixture.registry.authorize(&request(Some("secreT"))), Err(Error::Unauthorized)));
+        assert!(matches!(fixture.registry.authorize(&request(Some("secret "))), Err(Error::Unauthorized)));
+        assert!(matches!(fixture.registry.authorize(&request(Some(""))), Err(Error::Unauthorized)));
+        assert!(matches!(fixture.registry.authorize(&request(None)), Err(Error::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn refuses_modifications_without_a_token() {
+        let fixture = fixture(None, "").await;
+        assert!(matches!(fixture.registry.authorize(&request(Some("secret"))), Err(Error::Disabled)));
+        assert!(matches!(fixture.registry.authorize(&request(None)), Err(Error::Disabled)));
+    }
+
+    #[tokio::test]
+    async fn publishes_a_crate() {
+        let fixture = fixture(Some("secret"), "").await;
+        fixture.publish("acme", "1.0.0", &NOTHING_UPSTREAM).await.unwrap();
+        fixture.publish("acme", "1.1.0", &NOTHING_UPSTREAM).await.unwrap();
+
+        assert!(fixture.store.exists("acme", "1.0.0").await.unwrap());
+        assert!(fixture.registry.has_version("acme", "1.1.0").await.unwrap());
+        assert_eq!(fixture.yanked("acme", "1.0.0").await, Some(false));
+
+        let lines = fixture.registry.read_lines("acme").await.unwrap();
+        assert_eq!(lines[0]["cksum"], format!("{:x}", Sha256::digest(b"content")));
+
+        assert!(matches!(fixture.publish("acme", "1.0.0", &NOTHING_UPSTREAM).await, Err(Error::AlreadyExists(..))));
+        assert!(matches!(fixture.publish("acme", "1.0.0+build", &NOTHING_UPSTREAM).await, Err(Error::AlreadyExists(..))));
+    }
+
+    #[tokio::test]
+    async fn refuses_names_differing_from_a_published_crate() {
+        let fixture = fixture(Some("secret"), "").await;
+        fixture.publish("acme-tool", "1.0.0", &NOTHING_UPSTREAM).await.unwrap();
+        for name in &["acme_tool", "Acme-Tool", "ACME_TOOL"] {
+            assert!(matches!(fixture.publish(name, "2.0.0", &NOTHING_UPSTREAM).await, Err(Error::NameConflict(..))), "{}", name);
+        }
+        assert!(!fixture.registry.has_version("acme-tool", "2.0.0").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_names_listed_upstream_unless_reserved() {
+        let fixture = fixture(Some("secret"), "serde").await;
+        let upstream = FakeUpstream(Some(vec!["serde", "serde_json", "tokio-util"]));
+
+        for name in &["serde_json", "serde-json", "Serde_Json", "tokio_util", "Tokio-Util"] {
+            assert!(matches!(fixture.publish(name, "1.0.0", &upstream).await, Err(Error::ExistsUpstream(_))), "{}", name);
+            assert!(!fixture.store.exists(name, "1.0.0").await.unwrap());
+        }
+
+        fixture.publish("serde", "1.0.0", &upstream).await.unwrap();
+        fixture.publish("acme", "1.0.0", &upstream).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_unreserved_names_while_upstream_is_unreachable() {
+        let fixture = fixture(Some("secret"), "acme-*").await;
+        let unreachable = FakeUpstream(None);
+        assert!(matches!(fixture.publish("tool", "1.0.0", &unreachable).await, Err(Error::Upstream(..))));
+        fixture.publish("acme-tool", "1.0.0", &unreachable).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_the_crate_if_it_cannot_be_listed() {
+        let fixture = fixture(Some("secret"), "").await;
+        // a file where the entry's directory should be
+        std::fs::create_dir_all(fixture.registry.root()).unwrap();
+        std::fs::write(fixture.registry.root().join("ac"), b"").unwrap();
+
+        assert!(matches!(fixture.publish("acme", "1.0.0", &NOTHING_UPSTREAM).await, Err(Error::Io(_))));
+        assert!(!fixture.store.exists("acme", "1.0.0").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn yanks_and_unyanks() {
+        let fixture = fixture(Some("secret"), "").await;
+        fixture.publish("acme", "1.0.0", &NOTHING_UPSTREAM).await.unwrap();
+        fixture.publish("acme", "1.1.0", &NOTHING_UPSTREAM).await.unwrap();
+
+        fixture.registry.set_yanked("acme", "1.0.0", true).await.unwrap();
+        assert_eq!(fixture.yanked("acme", "1.0.0").await, Some(true));
+        assert_eq!(fixture.yanked("acme", "1.1.0").await, Some(false));
+
+        fixture.registry.set_yanked("acme", "1.0.0", false).await.unwrap();
+        assert_eq!(fixture.yanked("acme", "1.0.0").await, Some(false));
+
+        assert!(matches!(fixture.registry.set_yanked("acme", "2.0.0", true).await, Err(Error::NotFound(..))));
+        assert!(matches!(fixture.registry.set_yanked("other", "1.0.0", true).await, Err(Error::NotFound(..))));
+    }
+
+    #[test]
+    fn parses_requests() {
+        let parse = |method: Method, uri: &'static str| parse_request(&method, &Uri::from_static(uri)).map(|request| match request {
+            RegistryRequest::Publish => "publish".to_string(),
+            RegistryRequest::Yank(name, version) => format!("yank {} {}", name, version),
+            RegistryRequest::Unyank(name, version) => format!("unyank {} {}", name, version),
+        });
+        assert_eq!(parse(Method::PUT, "/api/v1/crates/new").as_deref(), Some("publish"));
+        assert_eq!(parse(Method::DELETE, "/api/v1/crates/acme/1.0.0/yank").as_deref(), Some("yank acme 1.0.0"));
+        assert_eq!(parse(Method::PUT, "/api/v1/crates/acme/1.0.0/unyank").as_deref(), Some("unyank acme 1.0.0"));
+        assert_eq!(parse(Method::POST, "/api/v1/crates/new"), None);
+        assert_eq!(parse(Method::PUT, "/api/v1/crates/acme/1.0.0/yank"), None);
+        assert_eq!(parse(Method::DELETE, "/api/v1/crates/acme/1.0.0/yank/x"), None);
+    }
+}
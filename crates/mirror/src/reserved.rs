@@ -0,0 +1,100 @@
+//! crate names reserved for the private registry
+//!
+//! Names are compared the way crates.io compares them, ignoring case and
+//! treating `-` and `_` alike, so that an upstream crate can't slip past the
+//! list with a differently spelled name.
+//!
+//! Refused lookups are logged as warnings with the `security` target.
+
+use std::collections::BTreeSet;
+
+/// bring a crate name into the form used for comparisons
+pub(crate) fn normalize(name: &str) -> String {
+    name.chars().map(|c| if c == '_' { '-' } else { c.to_ascii_lowercase() }).collect()
+}
+
+/// Crate names which must never be looked up upstream.
+#[derive(Default,Debug)]
+pub struct ReservedNames {
+    exact: BTreeSet<String>,
+    prefixes: Vec<String>,
+}
+
+impl ReservedNames {
+
+    /// parse a comma separated list of names, where a name ending in `*`
+    /// reserves every name with that prefix, i.e. `acme-*,internal-tool`
+    pub fn parse(list: &str) -> Self {
+        let mut reserved = Self::default();
+        for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name.strip_suffix('*') {
+                Some(prefix) => reserved.prefixes.push(normalize(prefix)),
+                None => { reserved.exact.insert(normalize(name)); },
+            }
+        }
+        reserved
+    }
+
+    /// check if nothing is reserved
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.prefixes.is_empty()
+    }
+
+    /// check if a crate name is reserved
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize(name);
+        self.exact.contains(&name) || self.prefixes.iter().any(|prefix| name.starts_with(prefix.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn matches_names_and_prefixes() {
+        let reserved = ReservedNames::parse(" acme-*, Internal_Tool ,,tool");
+        let cases = [
+            ("internal-tool", true),
+            ("internal_tool", true),
+            ("INTERNAL-TOOL", true),
+            ("Internal_Tool", true),
+            ("internal-tools", false),
+            ("internal", false),
+            ("tool", true),
+            ("tools", false),
+            ("acme-", true),
+            ("acme-a", true),
+            ("acme_a", true),
+            ("ACME_Widgets", true),
+            ("acme", false),
+            ("acmeX", false),
+            ("xacme-a", false),
+        ];
+        for (name, contained) in cases.iter() {
+            assert_eq!(reserved.contains(name), *contained, "{}", name);
+        }
+    }
+
+    #[test]
+    fn parses_empty_lists() {
+        assert!(ReservedNames::parse("").is_empty());
+        assert!(ReservedNames::parse(" , ,").is_empty());
+        assert!(!ReservedNames::parse("a").is_empty());
+        assert!(!ReservedNames::parse("a*").is_empty());
+        assert!(!ReservedNames::parse("").contains("a"));
+    }
+
+    #[test]
+    fn a_lone_star_reserves_everything() {
+        let reserved = ReservedNames::parse("*");
+        assert!(reserved.contains("serde") && reserved.contains("a"));
+    }
+
+    #[test]
+    fn normalizes_case_and_separators() {
+        assert_eq!(normalize("Serde_JSON-Core"), "serde-json-core");
+        assert_eq!(normalize("a-b"), normalize("A_B"));
+    }
+}
@@ -0,0 +1,374 @@
+//! serves the cargo sparse registry protocol
+//!
+//! Index entries are fetched on demand through the proxy and kept in the index
+//! directory. A cached copy is served as is within the index's time to live,
+//! after which it's revalidated with the upstream index using the validators
+//! it was fetched with, falling back to the cached copy when the proxy is
+//! unavailable. Crates published to the private registry are
+//! served from its index instead, and neither they nor reserved names are ever
+//! looked up in the upstream index.
+
+use std::io;
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+use hyper::header::{self, HeaderValue};
+use hyper::http::{Uri, StatusCode};
+
+use futures::StreamExt;
+use sha2::{Digest,Sha256};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use common::{down_stream, index_path, package, Validators};
+
+use crate::{ProxyRef, IndexRef, RegistryRef, index::CrateIndex, proxy_connection, registry};
+
+/// A request for a resource of the sparse index.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexRequest<'a> {
+    /// the registry configuration, `config.json`
+    Config,
+    /// the index entry of the named crate
+    Entry(&'a str),
+}
+
+/// An error that can occur while fetching an entry through the proxy.
+#[derive(Error,Display,Debug)]
+enum FetchError {
+    /// the proxy is unavailable: {0}
+    Proxy(#[from] proxy_connection::Error),
+    /// the upstream index does not list the crate
+    NotFound,
+    /// the upstream index did not provide the entry
+    Upstream,
+    /// the proxy sent an unexpected response
+    Unexpected,
+    /// {0}
+    InvalidName(#[from] package::Error),
+}
+
+/// Check if the provided URL refers to a resource of the sparse index.
+pub fn parse_request(uri: &Uri) -> Option<IndexRequest<'_>> {
+
+    let path = uri.path().strip_prefix('/')?;
+
+    if path == "config.json" {
+        return Some(IndexRequest::Config);
+    }
+
+    let name = path.rsplit('/').next()?;
+
+    if package::check_name(name).is_ok() && index_path(name) == path {
+        Some(IndexRequest::Entry(name))
+    } else {
+        None
+    }
+}
+
+/// Respond to a request for a resource of the sparse index.
+pub async fn serve(proxy: ProxyRef, index: IndexRef, registry: RegistryRef, req: &Request<Body>, request: IndexRequest<'_>) -> Result<Response<Body>,u16> {
+    match request {
+        IndexRequest::Config => config(&registry),
+        IndexRequest::Entry(name) => {
+            match registry.entry(name).await {
+                Ok(Some(content)) => respond(req, content, Validators::default()),
+                Ok(None) if registry.is_reserved(name) => {
+                    tracing::warn!(target: "security", "refused to look up reserved crate {} in the upstream index", name);
+                    Err(404)
+                },
+                Ok(None) => entry(proxy, index, req, name).await,
+                Err(err) => {
+                    tracing::error!("unable to read private index entry for {}: {}", name, err);
+                    Err(500)
+                }
+            }
+        },
+    }
+}
+
+/// Describe this mirror as the download and API server of the registry.
+///
+/// The URLs come from configuration, never from the request's `Host` header.
+fn config(registry: &RegistryRef) -> Result<Response<Body>,u16> {
+
+    let config = serde_json::json!({
+        "dl": format!("{}/api/v1/crates", registry.base_url()),
+        "api": registry.base_url(),
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(config.to_string()))
+        .map_err(|_|500)
+}
+
+/// Fetch a crate's entry through the proxy.
+///
+/// Resolves to `None` if the entry matched the provided validators.
+async fn fetch_entry(proxy: &ProxyRef, name: &str, validators: Validators) -> Result<Option<(Vec<u8>,Validators)>,FetchError> {
+
+    use down_stream::Opcode::*;
+
+    let mut stream = proxy.begin_index_fetch(package::Name::new(name)?, validators).await?;
+
+    match stream.next().await {
+        Some(NotModified) => Ok(None),
+        Some(Init(headers)) => {
+            let mut content = Vec::with_capacity(headers.content_length);
+            loop {
+                match stream.next().await {
+                    Some(Chunk(buffer)) => content.extend_from_slice(buffer.as_ref()),
+                    Some(Complete(Ok(()))) if content.len() == headers.content_length => {
+                        break Ok(Some((content, headers.validators)));
+                    },
+                    Some(Complete(Err(_))) => break Err(FetchError::Upstream),
+                    _ => break Err(FetchError::Unexpected),
+                }
+            }
+        },
+        Some(Complete(Err(down_stream::Error::NotFound))) => Err(FetchError::NotFound),
+        Some(Complete(Err(_))) => Err(FetchError::Upstream),
+        _ => Err(FetchError::Unexpected),
+    }
+}
+
+/// Bring a crate's cached index entry up to date with the upstream index,
+/// returning whether anything new was fetched.
+///
+/// An entry within its time to live is left alone.
+pub async fn refresh_entry(proxy: &ProxyRef, index: &CrateIndex, name: &str) -> bool {
+
+    if index.is_fresh(name).await {
+        return false;
+    }
+
+    let validators = match index.read_entry(name).await {
+        Ok(cached) => cached.map(|(_, validators)| validators).unwrap_or_default(),
+        Err(err) => {
+            tracing::error!("unable to read index entry for {}: {}", name, err);
+            return false;
+        },
+    };
+
+    match fetch_entry(proxy, name, validators.clone()).await {
+        Ok(Some((content, validators))) => match index.store_entry(name, &content, &validators).await {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::error!("unable to cache index entry for {}: {}", name, err);
+                false
+            },
+        },
+        Ok(None) => {
+            if let Err(err) = index.mark_fresh(name, &validators).await {
+                tracing::error!("unable to cache index entry for {}: {}", name, err);
+            }
+            false
+        },
+        Err(err) => {
+            tracing::debug!("unable to refresh index entry for {}: {}", name, err);
+            false
+        },
+    }
+}
+
+/// The upstream index, as consulted by the private registry before
+/// publishing.
+pub struct UpstreamIndex {
+    proxy: ProxyRef,
+    index: IndexRef,
+}
+
+impl UpstreamIndex {
+    pub fn new(proxy: ProxyRef, index: IndexRef) -> Self {
+        Self{ proxy, index }
+    }
+}
+
+/// A cached entry counts while the upstream index can't be reached, without
+/// one the answer is unknown.
+#[async_trait]
+impl registry::Upstream for UpstreamIndex {
+    async fn lists(&self, name: &str) -> io::Result<bool> {
+
+        let cached = self.index.read_entry(name).await?;
+
+        if cached.is_some() && self.index.is_fresh(name).await {
+            return Ok(true);
+        }
+
+        let validators = cached.as_ref().map(|(_, validators)| validators.clone()).unwrap_or_default();
+
+        match fetch_entry(&self.proxy, name, validators).await {
+            Ok(Some((content, validators))) => {
+                self.index.store_entry(name, &content, &validators).await?;
+                Ok(true)
+            },
+            Ok(None) if cached.is_some() => Ok(true),
+            Err(FetchError::NotFound) => Ok(false),
+            Err(_) if cached.is_some() => Ok(true),
+            Ok(None) => Err(io::Error::other(FetchError::Unexpected)),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+/// Respond with a crate's index entry, revalidating it with the upstream
+/// index first if it's past its time to live.
+async fn entry(proxy: ProxyRef, index: IndexRef, req: &Request<Body>, name: &str) -> Result<Response<Body>,u16> {
+
+    let cached = index.read_entry(name).await.map_err(|err| {
+        tracing::error!("unable to read index entry for {}: {}", name, err);
+        500u16
+    })?;
+
+    let cached = match cached {
+        Some((content, validators)) if index.is_fresh(name).await => return respond(req, content, validators),
+        cached => cached,
+    };
+
+    let validators = cached.as_ref().map(|(_, validators)| validators.clone()).unwrap_or_default();
+
+    let (content, validators) = match (fetch_entry(&proxy, name, validators).await, cached) {
+        (Ok(Some((content, validators))), _) => {
+            if let Err(err) = index.store_entry(name, &content, &validators).await {
+                tracing::error!("unable to cache index entry for {}: {}", name, err);
+            }
+            (content, validators)
+        },
+        (Ok(None), Some(cached)) => {
+            if let Err(err) = index.mark_fresh(name, &cached.1).await {
+                tracing::error!("unable to cache index entry for {}: {}", name, err);
+            }
+            cached
+        },
+        (Err(err), Some(cached)) => {
+            tracing::debug!("serving cached index entry for {}: {}", name, err);
+            cached
+        },
+        (_, None) => return Err(404),
+    };
+
+    respond(req, content, validators)
+}
+
+/// Respond with the content of an index entry, or that the requester's copy
+/// is up to date.
+fn respond(req: &Request<Body>, content: Vec<u8>, validators: Validators) -> Result<Response<Body>,u16> {
+
+    let etag = format!("\"{:x}\"", Sha256::digest(&content));
+
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).map(|value| value == etag.as_str());
+    let if_modified_since = req.headers().get(header::IF_MODIFIED_SINCE)
+        .map(|value| Some(value.as_bytes()) == validators.last_modified.as_ref().map(|v| v.as_bytes()));
+
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::ETAG, &etag);
+
+    if let Some(last_modified) = validators.last_modified.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+        builder = builder.header(header::LAST_MODIFIED, last_modified);
+    }
+
+    if if_none_match.or(if_modified_since).unwrap_or(false) {
+        builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).map_err(|_|500)
+    } else {
+        builder.body(Body::from(content)).map_err(|_|500)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::{registry::Registry, reserved::ReservedNames};
+
+    fn assert_parses(path: &str, expected: Option<IndexRequest<'_>>) {
+        let uri: Uri = path.parse().unwrap();
+        assert_eq!(parse_request(&uri), expected, "{}", path);
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> Request<Body> {
+        let mut request = Request::builder().uri("/3/l/log");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()
+    }
+
+    #[test]
+    fn parses_entry_paths() {
+        assert_parses("/config.json", Some(IndexRequest::Config));
+        assert_parses("/1/a", Some(IndexRequest::Entry("a")));
+        assert_parses("/2/cc", Some(IndexRequest::Entry("cc")));
+        assert_parses("/3/s/syn", Some(IndexRequest::Entry("syn")));
+        assert_parses("/se/rd/serde", Some(IndexRequest::Entry("serde")));
+        assert_parses("/se/rd/serde_json", Some(IndexRequest::Entry("serde_json")));
+    }
+
+    #[test]
+    fn refuses_paths_not_matching_the_layout() {
+        // cargo requests entries by their lowercase paths
+        for path in &["/", "/serde", "/se/serde", "/rd/se/serde", "/3/x/syn", "/1/ab", "/se/rd/Serde", "/SE/RD/serde", "/se/rd/serde/", "/se/rd/../serde", "/config.json/x", "/1/-", "/se/rd/se%72de"] {
+            assert_parses(path, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn describes_the_mirror_from_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Arc::new(Registry::new(CrateIndex::new(dir.path()), None, Arc::new(ReservedNames::parse("")), "http://mirror.example.com:3000".into()));
+        let response = config(&registry).unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let config: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(config, serde_json::json!({
+            "dl": "http://mirror.example.com:3000/api/v1/crates",
+            "api": "http://mirror.example.com:3000",
+        }));
+    }
+
+    #[tokio::test]
+    async fn responds_with_entries_and_their_validators() {
+        let validators = Validators{ last_modified: Some("Fri, 24 May 2013 00:00:00 GMT".into()), ..Default::default() };
+        let response = respond(&request(&[]), b"{}".to_vec(), validators).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], format!("\"{:x}\"", Sha256::digest(b"{}")).as_str());
+        assert_eq!(response.headers()[header::LAST_MODIFIED], "Fri, 24 May 2013 00:00:00 GMT");
+        assert_eq!(body(response).await, b"{}");
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_the_entity_tag() {
+        let etag = format!("\"{:x}\"", Sha256::digest(b"{}"));
+        let response = respond(&request(&[(header::IF_NONE_MATCH, &etag)]), b"{}".to_vec(), Validators::default()).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(body(response).await.is_empty());
+
+        let response = respond(&request(&[(header::IF_NONE_MATCH, "\"stale\"")]), b"{}".to_vec(), Validators::default()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_the_modification_date() {
+        let modified = "Fri, 24 May 2013 00:00:00 GMT";
+        let validators = Validators{ last_modified: Some(modified.into()), ..Default::default() };
+        let response = respond(&request(&[(header::IF_MODIFIED_SINCE, modified)]), b"{}".to_vec(), validators.clone()).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = respond(&request(&[(header::IF_MODIFIED_SINCE, "Sat, 25 May 2013 00:00:00 GMT")]), b"{}".to_vec(), validators.clone()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // a stale entity tag takes precedence over a matching date
+        let headers = [(header::IF_NONE_MATCH, "\"stale\""), (header::IF_MODIFIED_SINCE, modified)];
+        let response = respond(&request(&headers), b"{}".to_vec(), validators).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
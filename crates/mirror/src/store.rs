@@ -0,0 +1,82 @@
+//! storage of cached crates
+//!
+//! The cache is accessed through the [CrateStore] trait so that it can live on
+//! the local filesystem, or in an S3-compatible object store shared by several
+//! mirror hosts.
+
+use std::{io, pin::Pin, time::SystemTime};
+
+use async_trait::async_trait;
+use futures::Stream;
+use hyper::body::Bytes;
+
+/// storage in a local directory
+mod filesystem;
+/// storage in an S3-compatible object store
+mod s3;
+
+pub use filesystem::FsStore;
+pub use s3::{S3Config, S3Store};
+
+/// The content of a stored package.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// The media type of a `.crate` file, a gzip compressed tarball, as served by
+/// crates.io.
+pub const CRATE_CONTENT_TYPE: &str = "application/gzip";
+
+/// A package version held by a store.
+#[derive(Debug)]
+pub struct StoredCrate {
+    pub name: String,
+    pub version: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// a quoted entity tag that changes whenever the package is replaced
+    pub etag: Option<String>,
+    /// the media type the package was stored with, if the store keeps one
+    pub content_type: Option<String>,
+}
+
+/// A package being written to a store.
+///
+/// The package only becomes visible once committed, and is discarded if
+/// dropped before then.
+#[async_trait]
+pub trait PendingPut: Send {
+    /// append a fragment of the package
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// atomically make the package visible in the store
+    async fn commit(self: Box<Self>) -> io::Result<()>;
+}
+
+/// A place to keep cached packages.
+#[async_trait]
+pub trait CrateStore: Send + Sync {
+    /// open a stored package, providing its content from the provided offset
+    async fn get(&self, name: &str, version: &str, offset: u64) -> io::Result<Option<ByteStream>>;
+
+    /// describe a stored package
+    async fn stat(&self, name: &str, version: &str) -> io::Result<Option<StoredCrate>>;
+
+    /// begin storing a package, replacing any existing one once committed
+    async fn put(&self, name: &str, version: &str) -> io::Result<Box<dyn PendingPut>>;
+
+    /// check if a package is stored
+    async fn exists(&self, name: &str, version: &str) -> io::Result<bool> {
+        Ok(self.stat(name, version).await?.is_some())
+    }
+
+    /// enumerate every stored package
+    async fn list(&self) -> io::Result<Vec<StoredCrate>>;
+
+    /// remove a stored package, succeeding if it is already absent
+    async fn delete(&self, name: &str, version: &str) -> io::Result<()>;
+
+    /// whether other mirrors may share the store, and so use and pin packages
+    /// this mirror's ledger knows nothing about
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    io,
+    io::SeekFrom,
+    path::{Path,PathBuf},
+    sync::atomic::{AtomicU32,Ordering},
+    time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncSeekExt,AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream,CrateStore,PendingPut,StoredCrate};
+
+/// Keeps packages in a directory as `<name>/<version>`.
+///
+/// Packages are written to a temporary file along side their final location
+/// and renamed into place when committed.
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self{ root: root.into() }
+    }
+
+    fn path(&self, name: &str, version: &str) -> PathBuf {
+        self.root.join(name).join(version)
+    }
+}
+
+/// describe a package file, its entity tag derived from its size and
+/// modification time as the file is replaced rather than modified
+fn stored_crate(name: String, version: String, metadata: &std::fs::Metadata) -> StoredCrate {
+    let modified = metadata.modified().ok();
+    let etag = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos()));
+    StoredCrate{ name, version, size: metadata.len(), modified, etag, content_type: None }
+}
+
+/// A package being written to a temporary file.
+struct FsPendingPut {
+    file: tokio::fs::File,
+    temp_path: Option<PathBuf>,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl PendingPut for FsPendingPut {
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes).await
+    }
+
+    async fn commit(mut self: Box<Self>) -> io::Result<()> {
+
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+
+        let temp_path = self.temp_path.take().unwrap();
+
+        if let Err(err) = tokio::fs::rename(&temp_path, &self.path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FsPendingPut {
+    fn drop(&mut self) {
+        if let Some(temp_path) = self.temp_path.take() {
+            if let Err(err) = std::fs::remove_file(&temp_path) {
+                tracing::error!("failed to remove partial download {:?}: {}", temp_path, err);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CrateStore for FsStore {
+
+    async fn get(&self, name: &str, version: &str, offset: u64) -> io::Result<Option<ByteStream>> {
+        let mut file = match tokio::fs::File::open(self.path(name, version)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(Some(Box::pin(ReaderStream::new(file))))
+    }
+
+    async fn stat(&self, name: &str, version: &str) -> io::Result<Option<StoredCrate>> {
+        match tokio::fs::metadata(self.path(name, version)).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(stored_crate(name.into(), version.into(), &metadata))),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn put(&self, name: &str, version: &str) -> io::Result<Box<dyn PendingPut>> {
+
+        static NEXT_TEMP_ID: AtomicU32 = AtomicU32::new(0);
+
+        let path = self.path(name, version);
+        let dir = path.parent().unwrap();
+
+        tokio::fs::create_dir_all(dir).await?;
+
+        let temp_path = dir.join(format!(
+            ".{}.{}-{}.partial",
+            version,
+            std::process::id(),
+            NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed),
+        ));
+
+        let file = tokio::fs::File::create(&temp_path).await?;
+
+        Ok(Box::new(FsPendingPut{ file, temp_path: Some(temp_path), path }))
+    }
+
+    async fn list(&self) -> io::Result<Vec<StoredCrate>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || scan(&root)).await.expect("cache scan not to panic")
+    }
+
+    async fn delete(&self, name: &str, version: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(name, version)).await {
+            Ok(()) => {},
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+        // only succeeds once the last version is gone
+        let _ = tokio::fs::remove_dir(self.root.join(name)).await;
+        Ok(())
+    }
+}
+
+/// enumerate the packages in the cache directory, removing any temporary
+/// files left behind by an interrupted write
+fn scan(root: &Path) -> io::Result<Vec<StoredCrate>> {
+
+    let mut crates = Vec::new();
+
+    for crate_dir in std::fs::read_dir(root)? {
+        let crate_dir = crate_dir?;
+        let name = crate_dir.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || !crate_dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(crate_dir.path())? {
+            let file = file?;
+            let version = file.file_name().to_string_lossy().into_owned();
+            let metadata = file.metadata()?;
+            if version.starts_with('.') && version.ends_with(".partial") {
+                let _ = std::fs::remove_file(file.path());
+                continue;
+            }
+            if version.starts_with('.') || !metadata.is_file() {
+                continue;
+            }
+            crates.push(stored_crate(name.clone(), version, &metadata));
+        }
+    }
+
+    Ok(crates)
+}
@@ -0,0 +1,449 @@
+//! tracks usage of the crate cache to enforce its quota
+//!
+//! Keeps a ledger of the size, last access time and hit count of every cached
+//! package version, persisted as a local JSON file. When an admission takes the
+//! cache over its quota, the coldest entries are evicted according to the
+//! configured policy. Pinned entries, and every version of a pinned crate, are
+//! never evicted.
+
+use std::{
+    io,
+    str::FromStr,
+    path::PathBuf,
+    sync::{Arc,Mutex,atomic::{AtomicBool,Ordering}},
+    collections::{BTreeMap,BTreeSet},
+    time::{Duration,SystemTime,UNIX_EPOCH},
+};
+
+use serde::{Serialize,Deserialize};
+
+use crate::store::{CrateStore,StoredCrate};
+
+/// How often the ledger is saved if only access statistics have changed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Which entries to evict first when the cache exceeds its quota.
+#[derive(Debug,Clone,Copy)]
+pub enum Policy {
+    /// least recently used
+    Lru,
+    /// least frequently used, ties broken by least recently used
+    Lfu,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self,Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lru" => Ok(Policy::Lru),
+            "lfu" => Ok(Policy::Lfu),
+            _ => Err(format!("unknown eviction policy '{}', expected 'lru' or 'lfu'", s)),
+        }
+    }
+}
+
+/// Parse a byte count with an optional `K`, `M`, `G` or `T` suffix.
+pub fn parse_quota(s: &str) -> Result<u64,String> {
+    let s = s.trim();
+    let (digits, scale) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1u64 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        Some((i, 'T')) | Some((i, 't')) => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    digits.trim().parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("illegal cache quota '{}'", s))
+}
+
+/// Usage of a single cached package version.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+struct Entry {
+    size: u64,
+    /// seconds since the unix epoch
+    last_access: u64,
+    hits: u64,
+    pinned: bool,
+}
+
+/// The persisted state of the cache.
+#[derive(Serialize,Deserialize,Default)]
+struct Ledger {
+    /// crate name -> version -> usage
+    entries: BTreeMap<String,BTreeMap<String,Entry>>,
+    /// crates with every version pinned
+    pinned_crates: BTreeSet<String>,
+}
+
+impl Ledger {
+
+    fn total_size(&self) -> u64 {
+        self.entries.values().flat_map(|versions| versions.values()).map(|entry| entry.size).sum()
+    }
+
+    /// choose the coldest unpinned entry
+    fn victim(&self, policy: Policy) -> Option<(String,String)> {
+        self.entries.iter()
+            .filter(|(name, _)| !self.pinned_crates.contains(*name))
+            .flat_map(|(name, versions)| versions.iter().map(move |(version, entry)| (name, version, entry)))
+            .filter(|(_, _, entry)| !entry.pinned)
+            .min_by_key(|(_, _, entry)| match policy {
+                Policy::Lru => (0, entry.last_access),
+                Policy::Lfu => (entry.hits, entry.last_access),
+            })
+            .map(|(name, version, _)| (name.clone(), version.clone()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Tracks the contents of the crate cache.
+pub struct CacheUsage {
+    store: Arc<dyn CrateStore>,
+    ledger_path: PathBuf,
+    quota: Option<u64>,
+    policy: Policy,
+    ledger: Mutex<Ledger>,
+    dirty: AtomicBool,
+    /// serializes writes of the ledger, which share a temporary file
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl CacheUsage {
+
+    /// load the ledger of the cache, reconciling it with the content of the store
+    pub async fn load(store: Arc<dyn CrateStore>, ledger_path: PathBuf, quota: Option<u64>, policy: Policy) -> io::Result<Self> {
+
+        let ledger = match tokio::fs::read(&ledger_path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                tracing::error!("discarding corrupt cache ledger {:?}: {}", ledger_path, err);
+                Ledger::default()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ledger::default(),
+            Err(err) => return Err(err),
+        };
+
+        let ledger = reconcile(store.list().await?, ledger);
+
+        let usage = Self{ store, ledger_path, quota, policy, ledger: Mutex::new(ledger), dirty: AtomicBool::new(false), saving: Default::default() };
+
+        usage.persist().await?;
+
+        Ok(usage)
+    }
+
+    /// note that a cached package version was served
+    pub fn record_hit(&self, name: &str, version: &str) {
+        let mut ledger = self.ledger.lock().unwrap();
+        if let Some(entry) = ledger.entries.get_mut(name).and_then(|versions| versions.get_mut(version)) {
+            entry.hits += 1;
+            entry.last_access = now();
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// note that a package version was added to the cache, evicting others if
+    /// the cache is now over its quota
+    pub async fn record_admission(&self, name: &str, version: &str, size: u64, pinned: bool) {
+        self.ledger.lock().unwrap().entries.entry(name.into()).or_default().insert(version.into(), Entry{
+            size,
+            last_access: now(),
+            hits: 1,
+            pinned,
+        });
+        self.enforce_quota().await;
+        self.save().await;
+    }
+
+    /// pin a package version, or every version of a crate, so it is never evicted
+    ///
+    /// Returns false if the version is not in the cache.
+    pub async fn pin(&self, name: &str, version: Option<&str>, pinned: bool) -> bool {
+        let found = {
+            let mut ledger = self.ledger.lock().unwrap();
+            match version {
+                Some(version) => match ledger.entries.get_mut(name).and_then(|versions| versions.get_mut(version)) {
+                    Some(entry) => {
+                        entry.pinned = pinned;
+                        true
+                    },
+                    None => false,
+                },
+                None => {
+                    if pinned {
+                        ledger.pinned_crates.insert(name.into());
+                    } else {
+                        ledger.pinned_crates.remove(name);
+                    }
+                    true
+                },
+            }
+        };
+        if found {
+            tracing::info!("{} {}{}", if pinned { "pinned" } else { "unpinned" }, name, version.map(|v| format!("/{}", v)).unwrap_or_default());
+            if !pinned {
+                self.enforce_quota().await;
+            }
+            self.save().await;
+        }
+        found
+    }
+
+    /// evict the coldest entries until the cache is within its quota
+    async fn enforce_quota(&self) {
+
+        let quota = match self.quota {
+            Some(quota) => quota,
+            None => return,
+        };
+
+        loop {
+            let victim = {
+                let mut ledger = self.ledger.lock().unwrap();
+                if ledger.total_size() <= quota {
+                    return;
+                }
+                match ledger.victim(self.policy) {
+                    Some((name, version)) => {
+                        let versions = ledger.entries.get_mut(&name).unwrap();
+                        versions.remove(&version);
+                        if versions.is_empty() {
+                            ledger.entries.remove(&name);
+                        }
+                        (name, version)
+                    },
+                    None => {
+                        tracing::warn!("cache exceeds its quota of {} bytes with only pinned entries", quota);
+                        return;
+                    }
+                }
+            };
+
+            let (name, version) = victim;
+
+            match self.store.delete(&name, &version).await {
+                Ok(()) => tracing::info!("evicted {}/{} from the cache", name, version),
+                Err(err) => tracing::error!("failed to evict {}/{}: {}", name, version, err),
+            }
+        }
+    }
+
+    /// write the ledger, logging failures
+    async fn save(&self) {
+        if let Err(err) = self.persist().await {
+            tracing::error!("failed to save the cache ledger: {}", err);
+        }
+    }
+
+    /// atomically write the ledger
+    ///
+    /// The ledger is marked clean as it is copied, and dirty again if the copy
+    /// doesn't make it to disk, so changes made meanwhile are never lost.
+    async fn persist(&self) -> io::Result<()> {
+        let _saving = self.saving.lock().await;
+        let bytes = {
+            let ledger = self.ledger.lock().unwrap();
+            self.dirty.store(false, Ordering::Relaxed);
+            serde_json::to_vec_pretty(&*ledger)
+        };
+        let mut temp_path = self.ledger_path.clone().into_os_string();
+        temp_path.push(".partial");
+        let result = async {
+            tokio::fs::write(&temp_path, bytes?).await?;
+            tokio::fs::rename(&temp_path, &self.ledger_path).await
+        }.await;
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// periodically save access statistics
+    pub async fn flush_periodically(&self) {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            if self.dirty.load(Ordering::Relaxed) {
+                self.save().await;
+            }
+        }
+    }
+}
+
+/// bring the ledger in line with the packages present in the store
+fn reconcile(stored: Vec<StoredCrate>, ledger: Ledger) -> Ledger {
+
+    let mut reconciled = Ledger{ entries: BTreeMap::new(), pinned_crates: ledger.pinned_crates };
+
+    for StoredCrate{name, version, size, modified, ..} in stored {
+        let entry = match ledger.entries.get(&name).and_then(|versions| versions.get(&version)) {
+            Some(entry) => Entry{ size, ..entry.clone() },
+            None => Entry{
+                size,
+                last_access: modified
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or_else(now),
+                hits: 0,
+                pinned: false,
+            },
+        };
+        reconciled.entries.entry(name).or_default().insert(version, entry);
+    }
+
+    reconciled
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn entry(size: u64, last_access: u64, hits: u64) -> Entry {
+        Entry{ size, last_access, hits, pinned: false }
+    }
+
+    fn ledger(entries: &[(&str, &str, Entry)]) -> Ledger {
+        let mut ledger = Ledger::default();
+        for (name, version, entry) in entries {
+            ledger.entries.entry(name.to_string()).or_default().insert(version.to_string(), entry.clone());
+        }
+        ledger
+    }
+
+    fn victim(ledger: &Ledger, policy: Policy) -> Option<(&str, &str)> {
+        let victim = ledger.victim(policy);
+        victim.map(|(name, version)| {
+            let (name, versions) = ledger.entries.get_key_value(&name).unwrap();
+            (name.as_str(), versions.get_key_value(&version).unwrap().0.as_str())
+        })
+    }
+
+    fn stored(name: &str, version: &str, size: u64, modified: u64) -> StoredCrate {
+        StoredCrate{ name: name.into(), version: version.into(), size, modified: Some(UNIX_EPOCH + Duration::from_secs(modified)), etag: None, content_type: None }
+    }
+
+    #[test]
+    fn parses_quotas() {
+        assert_eq!(parse_quota("1024"), Ok(1024));
+        assert_eq!(parse_quota(" 20G "), Ok(20 << 30));
+        assert_eq!(parse_quota("3k"), Ok(3 << 10));
+        assert_eq!(parse_quota("5 M"), Ok(5 << 20));
+        assert_eq!(parse_quota("2T"), Ok(2 << 40));
+        assert_eq!(parse_quota("0"), Ok(0));
+    }
+
+    #[test]
+    fn rejects_illegal_quotas() {
+        for quota in &["", "G", "-1G", "1.5G", "1P", "ten", "18446744073709551615K"] {
+            assert!(parse_quota(quota).is_err(), "{:?}", quota);
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert!(matches!("LRU".parse(), Ok(Policy::Lru)));
+        assert!(matches!("lfu".parse(), Ok(Policy::Lfu)));
+        assert!("fifo".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let ledger = ledger(&[
+            ("a", "1.0.0", entry(1, 30, 1)),
+            ("b", "1.0.0", entry(1, 10, 50)),
+            ("b", "2.0.0", entry(1, 20, 1)),
+        ]);
+        assert_eq!(victim(&ledger, Policy::Lru), Some(("b", "1.0.0")));
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used_then_least_recently() {
+        let ledger = ledger(&[
+            ("a", "1.0.0", entry(1, 10, 5)),
+            ("b", "1.0.0", entry(1, 30, 2)),
+            ("c", "1.0.0", entry(1, 20, 2)),
+        ]);
+        assert_eq!(victim(&ledger, Policy::Lfu), Some(("c", "1.0.0")));
+    }
+
+    #[test]
+    fn pinned_versions_and_crates_are_never_victims() {
+        let mut ledger = ledger(&[
+            ("a", "1.0.0", Entry{ pinned: true, ..entry(1, 10, 1) }),
+            ("b", "1.0.0", entry(1, 20, 1)),
+            ("b", "2.0.0", entry(1, 25, 1)),
+            ("c", "1.0.0", entry(1, 30, 1)),
+        ]);
+        ledger.pinned_crates.insert("b".into());
+        assert_eq!(victim(&ledger, Policy::Lru), Some(("c", "1.0.0")));
+        assert_eq!(victim(&ledger, Policy::Lfu), Some(("c", "1.0.0")));
+
+        ledger.entries.remove("c");
+        assert_eq!(victim(&ledger, Policy::Lru), None);
+    }
+
+    #[test]
+    fn reconcile_keeps_usage_of_stored_crates() {
+        let ledger = ledger(&[
+            ("a", "1.0.0", Entry{ pinned: true, ..entry(5, 10, 7) }),
+            ("gone", "1.0.0", entry(5, 10, 7)),
+        ]);
+        let reconciled = reconcile(vec![stored("a", "1.0.0", 8, 99), stored("new", "0.1.0", 3, 42)], ledger);
+
+        let a = &reconciled.entries["a"]["1.0.0"];
+        assert_eq!((a.size, a.last_access, a.hits, a.pinned), (8, 10, 7, true));
+
+        let new = &reconciled.entries["new"]["0.1.0"];
+        assert_eq!((new.size, new.last_access, new.hits, new.pinned), (3, 42, 0, false));
+
+        assert!(!reconciled.entries.contains_key("gone"));
+        assert_eq!(reconciled.total_size(), 11);
+    }
+
+    #[test]
+    fn reconcile_keeps_pinned_crates() {
+        let mut ledger = Ledger::default();
+        ledger.pinned_crates.insert("a".into());
+        let reconciled = reconcile(Vec::new(), ledger);
+        assert!(reconciled.pinned_crates.contains("a"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_admissions_are_all_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cache")).unwrap();
+        let store = Arc::new(crate::store::FsStore::new(dir.path().join("cache")));
+        let ledger_path = dir.path().join("usage.json");
+        let usage = Arc::new(CacheUsage::load(store, ledger_path.clone(), None, Policy::Lru).await.unwrap());
+
+        let admissions = (0..64).map(|i| {
+            let usage = usage.clone();
+            tokio::spawn(async move { usage.record_admission("a", &format!("{}.0.0", i), 1, false).await })
+        });
+        for admission in admissions {
+            admission.await.unwrap();
+        }
+
+        let saved: Ledger = serde_json::from_slice(&std::fs::read(&ledger_path).unwrap()).unwrap();
+        assert_eq!(saved.entries["a"].len(), 64);
+        assert!(!usage.dirty.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn failed_saves_leave_the_ledger_dirty() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cache")).unwrap();
+        let store = Arc::new(crate::store::FsStore::new(dir.path().join("cache")));
+        let usage = CacheUsage::load(store, dir.path().join("usage.json"), None, Policy::Lru).await.unwrap();
+
+        usage.record_admission("a", "1.0.0", 1, false).await;
+        usage.record_hit("a", "1.0.0");
+        std::fs::remove_dir_all(dir.path()).unwrap();
+
+        assert!(usage.persist().await.is_err());
+        assert!(usage.dirty.load(Ordering::Relaxed));
+    }
+}
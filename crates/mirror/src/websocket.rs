@@ -0,0 +1,221 @@
+//! proxies connecting over a WebSocket
+//!
+//! Where the only way from the proxy to the mirror is through an HTTP(S)
+//! reverse proxy, the proxy link can be carried by a WebSocket upgraded from a
+//! request to the http server, at the path configured by
+//! `CPM_LINK_WEBSOCKET_PATH`.
+
+use std::net::SocketAddr;
+
+use hyper::{Body, Request, Response, header, http::StatusCode};
+
+use common::transport::{self, WebSocket};
+
+use crate::ProxyRef;
+
+/// whether a header lists a token, ignoring case
+fn lists(req: &Request<Body>, name: header::HeaderName, token: &str) -> bool {
+    req.headers().get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|listed| listed.trim().eq_ignore_ascii_case(token))
+}
+
+/// upgrade a request to a WebSocket carrying the link of a proxy connecting
+/// from `from`
+pub fn accept(proxy: ProxyRef, mut req: Request<Body>, from: SocketAddr) -> Result<Response<Body>, u16> {
+
+    let key = req.headers().get(header::SEC_WEBSOCKET_KEY).cloned();
+
+    let key = match key {
+        Some(key) if lists(&req, header::CONNECTION, "upgrade") && lists(&req, header::UPGRADE, "websocket") => key,
+        _ => {
+            tracing::warn!("request for the proxy link from {} is not a WebSocket upgrade", from);
+            return Err(400);
+        },
+    };
+
+    if req.headers().get(header::SEC_WEBSOCKET_VERSION).map(|version| version.as_bytes()) != Some(b"13") {
+        return Ok(Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let upgrade = hyper::upgrade::on(&mut req);
+
+    // the address of the proxy as seen by the reverse proxy in front of the mirror
+    let from = match req.headers().get("x-forwarded-for").and_then(|value| value.to_str().ok()) {
+        Some(forwarded) => format!("websocket {} (for {})", from, forwarded),
+        None => format!("websocket {}", from),
+    };
+
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => proxy.accept(Box::new(WebSocket::accepted(upgraded).await), from),
+            Err(err) => tracing::error!("upgrade of the proxy link from {} failed with: {}", from, err),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, transport::derive_accept_key(key.as_bytes()))
+        .body(Body::empty())
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::{collections::HashMap, convert::Infallible, str::FromStr, sync::Arc, time::Duration};
+
+    use hyper::{Server, server::conn::AddrStream, service::{make_service_fn, service_fn}};
+    use sha2::{Digest, Sha256};
+
+    use common::{
+        TcpSender, TcpReceiver, Validators,
+        compression::Codec,
+        down_stream::{self, Headers, Opcode},
+        handshake::{self, Role},
+        hello,
+        package::PackageId,
+        up_stream,
+    };
+
+    use crate::{proxy_connection::{self, LinkConfig, ProxyConnection}, reserved::ReservedNames};
+
+    const KEY: &str = "correct horse battery staple";
+
+    /// the link configuration from the provided `CPM_LINK_*` variables
+    fn config(vars: &[(&str, &str)]) -> LinkConfig {
+        let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        LinkConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    fn proxy() -> ProxyRef {
+        let config = config(&[("CPM_LINK_KEY", KEY), ("CPM_LINK_WEBSOCKET_PATH", "/link")]);
+        ProxyConnection::with_config(Arc::new(ReservedNames::parse("")), config)
+    }
+
+    fn upgrade(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::get("/link");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    const FROM: ([u8; 4], u16) = ([127, 0, 0, 1], 40000);
+
+    #[test]
+    #[should_panic(expected = "to authenticate proxies connecting over a WebSocket")]
+    fn refuses_websockets_without_a_key_or_client_ca() {
+        config(&[("CPM_LINK_INSECURE", "1"), ("CPM_LINK_WEBSOCKET_PATH", "/link")]);
+    }
+
+    #[test]
+    fn accepts_websockets_with_a_key() {
+        assert_eq!(proxy().websocket_path(), Some("/link"));
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_that_are_not_upgrades_to_a_websocket() {
+        let cases: [&[(&str, &str)]; 4] = [
+            &[],
+            &[("connection", "upgrade"), ("upgrade", "websocket"), ("sec-websocket-version", "13")],
+            &[("connection", "keep-alive"), ("upgrade", "websocket"), ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="), ("sec-websocket-version", "13")],
+            &[("connection", "upgrade"), ("upgrade", "h2c"), ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="), ("sec-websocket-version", "13")],
+        ];
+        for headers in cases.iter() {
+            assert_eq!(accept(proxy(), upgrade(headers), FROM.into()).err(), Some(400), "{:?}", headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn requires_websocket_version_13() {
+        let req = upgrade(&[("connection", "upgrade"), ("upgrade", "websocket"), ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="), ("sec-websocket-version", "8")]);
+        let response = accept(proxy(), req, FROM.into()).unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_VERSION], "13");
+    }
+
+    #[tokio::test]
+    async fn accepts_upgrades_to_a_websocket() {
+        let req = upgrade(&[("connection", "keep-alive, Upgrade"), ("upgrade", "WebSocket"), ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="), ("sec-websocket-version", "13")]);
+        let response = accept(proxy(), req, FROM.into()).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        // the example of RFC 6455
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    /// serve the proxy link over a WebSocket at any path, returning the port
+    fn serve(proxy: ProxyRef) -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let make_svc = make_service_fn(move |conn: &AddrStream| {
+            let (proxy, from) = (proxy.clone(), conn.remote_addr());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = accept(proxy.clone(), req, from).unwrap();
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+        port
+    }
+
+    /// a proxy serving the first request over the link with `content`
+    async fn serve_a_download(port: u16, content: Vec<u8>) {
+        let end_point = transport::EndPoint::from_str(&format!("ws://127.0.0.1:{}/link", port)).unwrap();
+        let mut stream = end_point.connect().await.unwrap();
+        let session = handshake::authenticate(&mut stream, KEY.as_bytes(), Role::Proxy).await.unwrap();
+        let (agreement, greetings) = hello::exchange(&mut stream, &hello::Hello::local("proxy")).await.unwrap();
+        handshake::confirm(&mut stream, &session, Role::Proxy, &greetings).await.unwrap();
+        let (sent, received) = session.frame_macs(Role::Proxy, &greetings);
+        let codec = Codec::agreed(agreement.capabilities);
+        let (rx, tx) = tokio::io::split(stream);
+        let mut sender = TcpSender::<down_stream::Message>::new(tx).with_compression(codec).with_authentication(Some(sent));
+        let mut receiver = TcpReceiver::<up_stream::Message>::new(rx).with_compression(codec.is_some()).with_authentication(Some(received));
+
+        let request = loop {
+            match receiver.next().await.unwrap() {
+                Some(up_stream::Message::Request(request)) => break request,
+                Some(up_stream::Message::Ping(_)) => (),
+                message => panic!("expected a request, got {:?}", message),
+            }
+        };
+        let headers = Headers{ content_type: "application/x-tar".into(), content_length: content.len(), validators: Validators::default() };
+        for opcode in [Opcode::Init(headers), Opcode::Chunk(content.into()), Opcode::Complete(Ok(()))] {
+            sender.send(&down_stream::Message{ session_id: request.session_id, opcode }).await.unwrap();
+        }
+        // until the mirror hangs up
+        while receiver.next().await.is_ok_and(|message| message.is_some()) {}
+    }
+
+    #[tokio::test]
+    async fn downloads_over_a_websocket() {
+        let proxy = proxy();
+        let port = serve(proxy.clone());
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i / 100) as u8).collect();
+        tokio::spawn(serve_a_download(port, content.clone()));
+
+        let package = PackageId::new("log", "0.4.14").unwrap();
+        let mut download = loop {
+            match proxy.begin_download(package.clone(), Sha256::digest(&content).into()).await {
+                Ok(download) => break download,
+                Err(proxy_connection::Error::NoUplink) => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(err) => panic!("{}", err),
+            }
+        };
+        use futures::StreamExt;
+        assert!(matches!(download.stream.next().await, Some(Opcode::Init(headers)) if headers.content_length == content.len()));
+        assert!(matches!(download.stream.next().await, Some(Opcode::Chunk(chunk)) if chunk.as_ref() == content.as_slice()));
+        assert!(matches!(download.stream.next().await, Some(Opcode::Complete(Ok(())))));
+    }
+}
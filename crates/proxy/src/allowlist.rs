@@ -0,0 +1,149 @@
+//! addresses the mirror may connect from
+//!
+//! When the proxy listens for the mirror rather than connecting to it,
+//! connections are only accepted from the addresses and networks listed in
+//! `CPM_LINK_ALLOWED_MIRRORS`. Host names are not accepted, so that the list
+//! can't be subverted through DNS.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+/// An address, or a network of addresses sharing a prefix such as
+/// `10.1.0.0/16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u32,
+}
+
+/// '{0}' is not an address or network, such as `10.1.2.3` or `10.1.0.0/16`
+#[derive(Error, Display, Debug)]
+pub struct InvalidNetwork(String);
+
+impl FromStr for Network {
+    type Err = InvalidNetwork;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        let network = network.trim();
+        let invalid = || InvalidNetwork(network.into());
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u32>().map_err(|_| invalid())?)),
+            None => (network, None),
+        };
+        let address = address.parse::<IpAddr>().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        match prefix {
+            Some(prefix) if prefix > bits => Err(invalid()),
+            prefix => Ok(Self{ address, prefix: prefix.unwrap_or(bits) }),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl Network {
+
+    /// whether the address is in the network
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 peers of a socket listening on IPv6 appear as mapped addresses
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// whether the address is in any of the networks
+pub fn allows(networks: &[Network], address: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(address))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn network(network: &str) -> Network {
+        network.parse().unwrap()
+    }
+
+    fn address(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_addresses_and_networks() {
+        assert_eq!(network("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(network(" 10.1.0.0/16 ").to_string(), "10.1.0.0/16");
+        assert_eq!(network("fd00::1").to_string(), "fd00::1/128");
+        assert_eq!(network("fd00::/8").to_string(), "fd00::/8");
+    }
+
+    #[test]
+    fn refuses_out_of_range_prefixes_and_host_names() {
+        for network in &["10.1.2.3/33", "fd00::/129", "10.1.2.3/-1", "10.1.2.3/", "10.1.2.3/x", "mirror.example.com", "mirror.example.com/24", ""] {
+            assert!(network.parse::<Network>().is_err(), "{}", network);
+        }
+    }
+
+    #[test]
+    fn matches_addresses_within_the_prefix() {
+        let ipv4 = network("10.1.0.0/16");
+        assert!(ipv4.contains(address("10.1.0.0")));
+        assert!(ipv4.contains(address("10.1.255.255")));
+        assert!(!ipv4.contains(address("10.2.0.0")));
+        assert!(!ipv4.contains(address("10.0.255.255")));
+
+        let ipv6 = network("fd00:1::/32");
+        assert!(ipv6.contains(address("fd00:1:ffff::1")));
+        assert!(!ipv6.contains(address("fd00:2::1")));
+    }
+
+    #[test]
+    fn full_prefixes_match_a_single_address() {
+        assert!(network("10.1.2.3/32").contains(address("10.1.2.3")));
+        assert!(!network("10.1.2.3/32").contains(address("10.1.2.4")));
+        assert!(network("fd00::1/128").contains(address("fd00::1")));
+        assert!(!network("fd00::1/128").contains(address("fd00::2")));
+    }
+
+    #[test]
+    fn empty_prefixes_match_every_address_of_the_family() {
+        assert!(network("0.0.0.0/0").contains(address("10.1.2.3")));
+        assert!(network("0.0.0.0/0").contains(address("255.255.255.255")));
+        assert!(!network("0.0.0.0/0").contains(address("fd00::1")));
+        assert!(network("::/0").contains(address("fd00::1")));
+        assert!(!network("::/0").contains(address("10.1.2.3")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_ipv4_networks() {
+        assert!(network("10.1.0.0/16").contains(address("::ffff:10.1.2.3")));
+        assert!(!network("10.1.0.0/16").contains(address("::ffff:10.2.2.3")));
+        assert!(network("10.1.2.3").contains(address("::ffff:10.1.2.3")));
+        // only mapped addresses stand for IPv4 peers, not compatible ones
+        assert!(!network("10.1.2.3").contains(address("::10.1.2.3")));
+    }
+
+    #[test]
+    fn allows_addresses_in_any_network() {
+        let networks = [network("10.1.2.3"), network("192.168.0.0/24")];
+        assert!(allows(&networks, address("10.1.2.3")));
+        assert!(allows(&networks, address("192.168.0.77")));
+        assert!(!allows(&networks, address("10.1.2.4")));
+        assert!(!allows(&[], address("10.1.2.3")));
+    }
+}
@@ -1,72 +1,194 @@
-///! # Rust Cargo crate proxy service
-
+//! # Rust Cargo crate proxy service
 
 use std::{
-    //sync::Arc,
+    sync::{Arc, Mutex},
+    collections::HashMap,
     str::FromStr,
     net::SocketAddr,
-    convert::{TryFrom,TryInto},
-};
-
-use futures::{
-    sink::SinkExt,
-    channel::mpsc,
+    path::PathBuf,
+    convert::TryFrom,
 };
 
 use hyper::{
     http,
-    body::HttpBody,
-    header::{HeaderName, CONTENT_TYPE,CONTENT_LENGTH},
+    body::{Bytes, HttpBody},
+    header::{HeaderName, CONTENT_TYPE,CONTENT_LENGTH,CONTENT_RANGE,RANGE,ETAG,LAST_MODIFIED,IF_NONE_MATCH,IF_MODIFIED_SINCE},
 };
 
 use tokio::{
     pin,select,
-    io, time::{sleep, Duration},
-    net::TcpStream,
+    io, time::{sleep, interval, timeout, Duration},
+    net::TcpListener,
     sync::watch,
+    task::{JoinHandle, JoinSet},
 };
 
+use serde::de::DeserializeOwned;
+
 use thiserror::Error;
 use displaydoc::Display;
 
-use common::{TcpSender,TcpReceiver,up_stream,down_stream};
+use common::{TcpSender,TcpReceiver,up_stream,down_stream,backoff::Backoff,compression::Codec,handshake,hello,heartbeat,limits::FrameLimit,tls,transport,Validators,index_path};
+
+/// sharing the link between download sessions
+mod multiplexer;
+/// addresses the mirror may connect from
+mod allowlist;
+/// reporting the state of the link
+mod status;
+/// which redirects from the upstream server are followed
+mod redirect;
+
+use multiplexer::Multiplexer;
+use status::LinkStatus;
 
 use structopt::StructOpt;
 #[derive(StructOpt,Debug)]
 struct ServiceConfig {
-    /// The address and port of the mirror service.
-    #[structopt(short, long, env = "CPM_MIRROR_REMOTE_END_POINT")]
-    mirror_end_point: SocketAddr,
+    /// Where to reach the mirror service: `host:port`, `unix:<path>`,
+    /// `exec:<command>`, i.e. `exec:ssh mirror-host cpm-link`, or a `ws://` or
+    /// `wss://` URL.
+    #[structopt(short, long, env = "CPM_MIRROR_REMOTE_END_POINT", required_unless = "link-local-end-point", conflicts_with = "link-local-end-point")]
+    mirror_end_point: Option<transport::EndPoint>,
+
+    /// The address and port to listen for the mirror on, for the mirror to
+    /// connect to the proxy rather than the other way around.
+    #[structopt(long, env = "CPM_LINK_LOCAL_END_POINT", requires = "allowed-mirrors")]
+    link_local_end_point: Option<SocketAddr>,
+
+    /// The addresses, or networks such as `10.1.0.0/16`, the mirror may
+    /// connect from, in a comma separated list.
+    #[structopt(long, env = "CPM_LINK_ALLOWED_MIRRORS", use_delimiter = true)]
+    allowed_mirrors: Vec<allowlist::Network>,
 
     /// The base URL of the crate server.
-    #[structopt(short, long, default_value="https://crates.io/api/v1/crates", env = "CPM_CRATES_IO_BASE_URL")]
+    #[structopt(short, long, default_value="https://crates.io/api/v1/crates", env = "CPM_CRATES_IO_BASE_URL", parse(try_from_str = base_url))]
     crates_io_base_url: String,
+
+    /// The base URL of the sparse registry index.
+    #[structopt(short = "i", long, default_value="https://index.crates.io", env = "CPM_CRATES_IO_INDEX_URL", parse(try_from_str = base_url))]
+    crates_io_index_url: String,
+
+    /// The hosts the crate server may redirect downloads to, in a comma
+    /// separated list.
+    #[structopt(long, default_value = "crates.io,static.crates.io", env = "CPM_CRATES_IO_REDIRECT_HOSTS", use_delimiter = true)]
+    redirect_hosts: Vec<String>,
+
+    /// The most redirects followed for a download.
+    #[structopt(long, default_value = "5", env = "CPM_CRATES_IO_MAX_REDIRECTS")]
+    max_redirects: usize,
+
+    /// The key shared with the mirror to authenticate the link.
+    #[structopt(long, env = "CPM_LINK_KEY", hide_env_values = true)]
+    link_key: Option<String>,
+
+    /// Serve a mirror that is authenticated by neither a link key nor TLS.
+    #[structopt(long, env = "CPM_LINK_INSECURE")]
+    link_insecure: bool,
+
+    /// The CA certificates to verify the mirror with, enabling TLS.
+    #[structopt(long, env = "CPM_LINK_TLS_CA", parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// The certificate chain identifying the proxy to the mirror.
+    #[structopt(long, env = "CPM_LINK_TLS_CERT", parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// The private key of the proxy's certificate.
+    #[structopt(long, env = "CPM_LINK_TLS_KEY", parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// The name expected on the mirror's certificate, its address by default.
+    #[structopt(long, env = "CPM_LINK_TLS_SERVER_NAME")]
+    tls_server_name: Option<String>,
+
+    /// The number of seconds between pings of the mirror.
+    #[structopt(long, default_value = "15", env = "CPM_LINK_HEARTBEAT_INTERVAL", parse(try_from_str = heartbeat::Config::parse_interval))]
+    heartbeat_interval: Duration,
+
+    /// The number of intervals without word from the mirror before the link is
+    /// considered dead.
+    #[structopt(long, default_value = "3", env = "CPM_LINK_HEARTBEAT_MISSES", parse(try_from_str = heartbeat::Config::parse_misses))]
+    heartbeat_misses: u32,
+
+    /// The number of seconds to wait before connecting to the mirror again,
+    /// doubled after each failed attempt.
+    #[structopt(long, default_value = "1", env = "CPM_LINK_RECONNECT_DELAY")]
+    reconnect_delay: u64,
+
+    /// The most seconds to wait before connecting to the mirror again.
+    #[structopt(long, default_value = "60", env = "CPM_LINK_RECONNECT_MAX_DELAY")]
+    reconnect_max_delay: u64,
+
+    /// A file kept up to date with the state of the link, as JSON.
+    #[structopt(long, env = "CPM_LINK_STATUS_FILE", parse(from_os_str))]
+    status_file: Option<PathBuf>,
+
+    /// The codecs offered to compress the link with, in a comma separated
+    /// list, or "none".
+    #[structopt(long, default_value = "zstd,deflate", env = "CPM_LINK_COMPRESSION", parse(try_from_str = Codec::offered))]
+    link_compression: hello::Capabilities,
+
+    /// The longest message accepted from the mirror, in bytes, `65536` by
+    /// default.
+    #[structopt(long, env = "CPM_LINK_MAX_FRAME_LENGTH")]
+    link_max_frame_length: Option<usize>,
+}
+
+/// check a base URL given on the command line, an absolute `http` or `https`
+/// URL that paths are appended to, so without a trailing `/`
+fn base_url(url: &str) -> Result<String, String> {
+    let url = url.trim_end_matches('/');
+    let uri = http::Uri::try_from(url).map_err(|err| format!("'{}' is not a URL: {}", url, err))?;
+    match (uri.scheme_str(), uri.authority(), uri.query()) {
+        (Some("http") | Some("https"), Some(_), None) => Ok(url.into()),
+        _ => Err(format!("'{}' is not an absolute http or https URL without a query", url)),
+    }
 }
 
-const TX_QUEUE_LENGTH: usize = 256;
-const DOWN_LINK_RETRY_DELAY: Duration = Duration::from_millis(1000);
+/// How long the mirror may take to secure and authenticate the link and to
+/// negotiate the protocol.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most links from the mirror served at once when listening for it, which
+/// leaves room for a reconnecting mirror while its old link times out.
+const MAX_LINKS: usize = 4;
+
+/// How long to wait after failing to accept a connection from the mirror.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(1000);
+
+/// How long the upstream server may take to respond, or to send the next part
+/// of a download.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
 
 type HttpClient = hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::connect::HttpConnector>>;
 
 struct DownloadStream {
     session_id: u32,
-    tx_channel: mpsc::Sender<down_stream::Message>,
+    link: Arc<Multiplexer>,
+    /// set if the mirror understands the specific variants of [down_stream::Error]
+    typed_errors: bool,
 }
 
 impl DownloadStream {
 
-    async fn send_message(&mut self, opcode: down_stream::Opcode) -> std::result::Result<(),mpsc::SendError> {
-        self.tx_channel.send(down_stream::Message{ session_id: self.session_id, opcode }).await
+    async fn send_message(&mut self, opcode: down_stream::Opcode) -> std::result::Result<(),multiplexer::Closed> {
+        self.link.send(self.session_id, opcode)
     }
 
-    async fn send_complete(&mut self) -> std::result::Result<(),mpsc::SendError> {
+    async fn send_content(&mut self, content: Bytes) -> std::result::Result<(),multiplexer::Closed> {
+        self.link.send_content(self.session_id, content).await
+    }
+
+    async fn send_complete(&mut self) -> std::result::Result<(),multiplexer::Closed> {
         use down_stream::{Opcode::Complete};
         self.send_message(Complete(Ok(()))).await
     }
 
-    async fn send_failed(&mut self) -> std::result::Result<(),mpsc::SendError> {
-        use down_stream::{Opcode::Complete,Error::Unspecified};
-        self.send_message(Complete(Err(Unspecified))).await
+    async fn send_failed(&mut self, error: down_stream::Error) -> std::result::Result<(),multiplexer::Closed> {
+        use down_stream::Opcode::Complete;
+        let error = if self.typed_errors { error } else { error.untyped() };
+        self.send_message(Complete(Err(error))).await
     }
 }
 
@@ -75,65 +197,144 @@ enum DownloadError {
     /// HTTP error: {0}
     Hyper(#[from] hyper::Error),
     /// Downlink error: {0}
-    Downlink(#[from] mpsc::SendError),
+    Downlink(#[from] multiplexer::Closed),
     /// The requested file is not available: {0}
     NotAvailable(hyper::StatusCode),
     /// Bad redirect
     BadRedirect,
+    /// Redirect refused: {0}
+    Redirect(#[from] redirect::Violation),
+    /// Unable to form the upstream request
+    BadRequest,
     /// The required header '{0}' was invalid or missing
     BadOrMissingHeader(&'static hyper::header::HeaderName),
+    /// The upstream server timed out
+    Timeout,
+    /// The upstream server returned a different range than requested
+    BadRange,
+}
+
+impl From<tokio::time::error::Elapsed> for DownloadError {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        DownloadError::Timeout
+    }
 }
 
-async fn do_download(mut response: hyper::Response<hyper::Body>, tx: &mut DownloadStream) -> Result<(),DownloadError> {
+impl DownloadError {
+    /// the reason for the failure reported to the mirror
+    fn reason(&self) -> down_stream::Error {
+        use hyper::StatusCode;
+        match self {
+            DownloadError::NotAvailable(StatusCode::NOT_FOUND | StatusCode::GONE) => down_stream::Error::NotFound,
+            DownloadError::NotAvailable(status) => down_stream::Error::UpstreamStatus(status.as_u16()),
+            DownloadError::Timeout => down_stream::Error::UpstreamTimeout,
+            DownloadError::Redirect(redirect::Violation::Insecure(_) | redirect::Violation::HostNotAllowed(_)) => down_stream::Error::Denied,
+            DownloadError::Redirect(redirect::Violation::TooMany(_)) => down_stream::Error::TooManyRedirects,
+            DownloadError::Hyper(err) if err.is_timeout() => down_stream::Error::UpstreamTimeout,
+            err => down_stream::Error::Generic(err.to_string()),
+        }
+    }
+}
+
+fn get_header<T:FromStr>(response: &hyper::Response<hyper::Body>, name: &'static HeaderName) -> Result<T,DownloadError> {
+    use DownloadError::BadOrMissingHeader;
+    T::from_str(
+        response.headers()
+            .get(name).ok_or(BadOrMissingHeader(name))?
+            .to_str().map_err(|_|BadOrMissingHeader(name))?
+    ).map_err(|_|BadOrMissingHeader(name))
+}
+
+fn get_validators(response: &hyper::Response<hyper::Body>) -> Validators {
+    Validators {
+        etag: get_header(response, &ETAG).ok(),
+        last_modified: get_header(response, &LAST_MODIFIED).ok(),
+    }
+}
+
+/// the first byte of a partial response, from its `Content-Range` header
+fn get_range_start(response: &hyper::Response<hyper::Body>) -> Result<u64,DownloadError> {
+    let range: String = get_header(response, &CONTENT_RANGE)?;
+    range.strip_prefix("bytes ")
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse().ok())
+        .ok_or(DownloadError::BadOrMissingHeader(&CONTENT_RANGE))
+}
+
+/// Stream a download to the mirror, starting from `offset`.
+///
+/// The headers sent describe the whole file. If the server ignored the range
+/// requested, the content before `offset` is dropped here.
+async fn do_download(mut response: hyper::Response<hyper::Body>, offset: u64, tx: &mut DownloadStream) -> Result<(),DownloadError> {
 
     use down_stream::Opcode::*;
 
     tracing::trace!("headers: {:?}", response.headers());
 
-    fn get_header<T:FromStr>(response: &hyper::Response<hyper::Body>, name: &'static HeaderName) -> Result<T,DownloadError> {
-        use DownloadError::BadOrMissingHeader;
-        T::from_str(
-            response.headers()
-                .get(name).ok_or_else(||BadOrMissingHeader(name))?
-                .to_str().map_err(|_|BadOrMissingHeader(name))?
-        ).map_err(|_|BadOrMissingHeader(name))
-    }
+    let content_length: usize = get_header(&response, &CONTENT_LENGTH)?;
+
+    let (mut skip, content_length) = if response.status() == hyper::StatusCode::PARTIAL_CONTENT {
+        if get_range_start(&response)? != offset {
+            return Err(DownloadError::BadRange);
+        }
+        (0, content_length + offset as usize)
+    } else {
+        (offset as usize, content_length)
+    };
 
     let headers = down_stream::Headers {
         content_type: get_header(&response, &CONTENT_TYPE)?,
-        content_length: get_header(&response, &CONTENT_LENGTH)?,
+        content_length,
+        validators: get_validators(&response),
     };
 
     tx.send_message(Init(headers)).await?;
 
-    while let Some(block) = response.data().await {
+    while let Some(block) = timeout(UPSTREAM_TIMEOUT, response.data()).await? {
 
-        let block = block?;
+        let mut block = block?;
 
         tracing::trace!("block: {}", block.len());
 
-        tx.send_message(Chunk(block.to_vec().into())).await?;
+        let skipped = skip.min(block.len());
+        skip -= skipped;
+        if skipped == block.len() {
+            continue;
+        }
+        let block = block.split_off(skipped);
+
+        tx.send_content(block).await?;
     }
 
     Ok(())
 
 }
 
-fn get_redirect_location(headers: &hyper::HeaderMap) -> std::result::Result<http::Uri,()> {
-    headers
+fn get_redirect_location(headers: &hyper::HeaderMap, base: &http::Uri) -> std::result::Result<http::Uri,()> {
+    let location = headers
         .get("location")
-        .ok_or_else(||())?
+        .ok_or(())?
         .to_str()
-        .map_err(|_|())?
-        .try_into().map_err(|_|())
+        .map_err(|_|())?;
+    redirect::resolve(base, location).ok_or(())
 }
 
 
-async fn download_file(client: HttpClient, mut uri: http::Uri, tx: &mut DownloadStream) -> Result<(),DownloadError> {
+async fn download_file(client: HttpClient, mut uri: http::Uri, offset: u64, redirects: &redirect::Policy, tx: &mut DownloadStream) -> Result<(),DownloadError> {
+
+    let mut followed = 0;
 
     let response = loop {
 
-        let response = client.get(uri).await?;
+        let mut request = hyper::Request::get(&uri);
+
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let request = request.body(hyper::Body::empty()).map_err(|_|DownloadError::BadRequest)?;
+
+        let response = timeout(UPSTREAM_TIMEOUT, client.request(request)).await??;
 
         tracing::trace!("response: {:?}", response.status());
 
@@ -145,105 +346,498 @@ async fn download_file(client: HttpClient, mut uri: http::Uri, tx: &mut Download
             return Err(DownloadError::NotAvailable(response.status()));
         }
 
-        uri = get_redirect_location(response.headers()).map_err(|_|DownloadError::BadRedirect)?;
+        let location = get_redirect_location(response.headers(), &uri).map_err(|_|DownloadError::BadRedirect)?;
+
+        followed += 1;
+        if let Err(violation) = redirects.check(&location, followed) {
+            tracing::warn!(target: "security", "refused to follow a redirect from {}: {}", uri, violation);
+            return Err(violation.into());
+        }
+
+        uri = location;
 
         tracing::trace!("redirecting to: {:?}", uri);
     };
 
-    do_download(response, tx).await
+    do_download(response, offset, tx).await
 }
 
-async fn rx_process(
-    mut rx_end_point: TcpReceiver<up_stream::Request>,
-    tx_channel: mpsc::Sender<down_stream::Message>,
+/// Fetch an entry of the sparse index, conditionally if validators of a cached
+/// copy were provided.
+///
+/// The entry is buffered so its length is known even if the index server
+/// doesn't provide one.
+async fn fetch_index_entry(client: HttpClient, uri: http::Uri, validators: Validators, tx: &mut DownloadStream) -> Result<(),DownloadError> {
+
+    use down_stream::Opcode::*;
+
+    let mut request = hyper::Request::get(uri);
+
+    if let Some(etag) = &validators.etag {
+        request = request.header(IF_NONE_MATCH, etag.as_str());
+    }
+
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+    }
+
+    let response = timeout(UPSTREAM_TIMEOUT, client.request(request.body(hyper::Body::empty()).map_err(|_|DownloadError::BadRequest)?)).await??;
+
+    tracing::trace!("response: {:?}", response.status());
+
+    if response.status() == hyper::StatusCode::NOT_MODIFIED {
+        tx.send_message(NotModified).await?;
+        return Ok(());
+    }
+
+    if !response.status().is_success() {
+        return Err(DownloadError::NotAvailable(response.status()));
+    }
+
+    let content_type = get_header(&response, &CONTENT_TYPE).unwrap_or_else(|_|"text/plain".into());
+    let validators = get_validators(&response);
+
+    let content = timeout(UPSTREAM_TIMEOUT, hyper::body::to_bytes(response.into_body())).await??;
+
+    tx.send_message(Init(down_stream::Headers{ content_type, content_length: content.len(), validators })).await?;
+    tx.send_content(content).await?;
+
+    Ok(())
+}
+
+/// Serve the mirror's requests until the link closes.
+///
+/// `T` is the type of message sent by the mirror in the agreed protocol
+/// version.
+async fn rx_process<T: DeserializeOwned + FrameLimit + Into<up_stream::Message>>(
+    mut rx_end_point: TcpReceiver<T>,
+    link: Arc<Multiplexer>,
     client: HttpClient,
-    base_url: &str,
+    config: &ServiceConfig,
+    capabilities: hello::Capabilities,
+    heartbeat: Option<&Mutex<heartbeat::Heartbeat>>,
 ) -> Result<(), io::Error> {
-    while let Some(up_stream::Request{session_id,package,version}) = rx_end_point.next().await? {
 
-        let tx_channel = tx_channel.clone();
+    let typed_errors = capabilities.contains(hello::Capabilities::TYPED_ERRORS);
+    let (base_url, index_url) = (&config.crates_io_base_url, &config.crates_io_index_url);
+    let redirects = Arc::new(redirect::Policy::new(&config.redirect_hosts, config.max_redirects));
 
-        let uri_str = format!("{}/{}/{}/download", base_url, package, version);
+    let mut sessions = HashMap::<u32,JoinHandle<()>>::new();
+
+    while let Some(message) = rx_end_point.next().await? {
+
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.lock().unwrap().heard();
+        }
+
+        let up_stream::Request{session_id,resource} = match message.into() {
+            up_stream::Message::Request(request) => request,
+            up_stream::Message::Cancel{session_id} => {
+                if let Some(session) = sessions.remove(&session_id).filter(|session| !session.is_finished()) {
+                    tracing::info!("cancelled session {}", session_id);
+                    session.abort();
+                }
+                link.cancel(session_id);
+                continue;
+            },
+            up_stream::Message::Ping(id) => {
+                link.send_urgent(down_stream::Opcode::Pong(id)).map_err(|_|io::Error::from(io::ErrorKind::BrokenPipe))?;
+                continue;
+            },
+            up_stream::Message::Pong(id) => {
+                match heartbeat.and_then(|heartbeat| heartbeat.lock().unwrap().pong(id)) {
+                    Some((rtt, true)) => tracing::info!("mirror round trip time is {:?}", rtt),
+                    Some((rtt, false)) => tracing::debug!("mirror round trip time is {:?}", rtt),
+                    None => (),
+                }
+                continue;
+            },
+            up_stream::Message::Credit{session_id, bytes} => {
+                link.grant(session_id, bytes);
+                continue;
+            },
+        };
+
+        sessions.retain(|_, session| !session.is_finished());
+
+        // names and versions were checked as they were received
+        let uri_str = match &resource {
+            up_stream::Resource::Crate{package} |
+            up_stream::Resource::CrateFrom{package, ..} => format!("{}/{}/{}/download", base_url, package.name(), package.version()),
+            up_stream::Resource::IndexEntry{package, ..} => format!("{}/{}", index_url, index_path(package.as_str())),
+        };
+        link.open(session_id);
+        let mut stream = DownloadStream{ session_id, link: link.clone(), typed_errors };
         tracing::info!("request for: {}", uri_str);
-        let uri = http::Uri::try_from(&uri_str).expect(&format!("{} to be a valid URI", uri_str));
-        let mut stream = DownloadStream{ session_id, tx_channel };
+        let uri = match http::Uri::try_from(&uri_str) {
+            Ok(uri) => uri,
+            Err(err) => {
+                tracing::error!("unable to request {}: {}", uri_str, err);
+                if let Err(err) = stream.send_failed(down_stream::Error::Generic(err.to_string())).await {
+                    tracing::error!("unable to deliver failure: {}", err);
+                }
+                continue;
+            },
+        };
 
         let client = client.clone();
-        tokio::spawn(async move {
-            match download_file(client, uri, &mut stream).await {
+        let redirects = redirects.clone();
+        let session = tokio::spawn(async move {
+            let result = match resource {
+                up_stream::Resource::Crate{..} => download_file(client, uri, 0, &redirects, &mut stream).await,
+                up_stream::Resource::CrateFrom{offset, ..} => download_file(client, uri, offset, &redirects, &mut stream).await,
+                up_stream::Resource::IndexEntry{validators, ..} => fetch_index_entry(client, uri, validators, &mut stream).await,
+            };
+            match result {
                 Ok(_) => {
-                    tracing::info!("download of {}/{} completed", package, version);
+                    tracing::info!("download of {} completed", uri_str);
                     if let Err(err) = stream.send_complete().await {
                         tracing::error!("unable to deliver completion: {}", err);
                     }
                 },
                 Err(err) => {
-                    tracing::error!("download of {}/{} failed with: {}", package, version, err);
-                    if let Err(err) = stream.send_failed().await {
+                    tracing::error!("download of {} failed with: {}", uri_str, err);
+                    if let Err(err) = stream.send_failed(err.reason()).await {
                         tracing::error!("unable to deliver failure: {}", err);
                     }
                 }
             }
         });
+        sessions.insert(session_id, session);
     }
     Ok(())
 }
 
-async fn run_connection(end_point_id: SocketAddr, base_url: &str, mut running: watch::Receiver<bool>) -> Result<(), (bool,io::Error)> {
+/// The TLS configuration of the link, and the name expected on the mirror's
+/// certificate.
+type LinkTls = (tls::TlsConnector, tls::ServerName);
+
+/// The receiving half of a link.
+type Reader = Box<dyn io::AsyncRead + Send + Unpin>;
+
+/// ping the mirror at the heartbeat interval, failing once it has been silent
+/// for too long
+async fn ping_mirror(heartbeat: &Mutex<heartbeat::Heartbeat>, link: &Multiplexer) -> Result<(), io::Error> {
+    let mut ticks = interval(heartbeat.lock().unwrap().interval());
+    loop {
+        ticks.tick().await;
+        let ping = heartbeat.lock().unwrap().tick();
+        let id = ping.ok_or_else(|| {
+            let deadline = heartbeat.lock().unwrap().deadline();
+            io::Error::new(io::ErrorKind::TimedOut, format!("the link is dead, nothing was heard from the mirror for {:?}", deadline))
+        })?;
+        link.send_urgent(down_stream::Opcode::Ping(id)).map_err(|_|io::Error::from(io::ErrorKind::BrokenPipe))?;
+    }
+}
+
+/// send the messages of the sessions to the mirror as they take turns
+async fn tx_process(mut tx_end_point: TcpSender<down_stream::Message>, link: &Multiplexer) -> Result<(), io::Error> {
+    loop {
+        tx_end_point.send(&link.next().await).await?;
+    }
+}
+
+fn refusal(err: handshake::Error) -> io::Error {
+    match err {
+        handshake::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::PermissionDenied, err),
+    }
+}
+
+/// authenticate the mirror if a link key is configured and negotiate the
+/// protocol, checking the greetings weren't altered on their way, then split
+/// the link into its ends
+async fn establish<S>(mut stream: S, key: Option<&str>, compression: hello::Capabilities) -> io::Result<(Reader, Option<handshake::FrameMac>, TcpSender<down_stream::Message>, hello::Agreement)>
+where
+    S: io::AsyncRead + io::AsyncWrite + Send + Unpin + 'static,
+{
+    let session = match key {
+        Some(key) => Some(handshake::authenticate(&mut stream, key.as_bytes(), handshake::Role::Proxy).await.map_err(refusal)?),
+        None => None,
+    };
+
+    let mut hello = hello::Hello::local(concat!("proxy ", env!("CARGO_PKG_VERSION")));
+    hello.capabilities = hello.capabilities.without(Codec::all()) | compression;
+    let (agreement, greetings) = hello::exchange(&mut stream, &hello).await.map_err(|err| match err {
+        hello::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::Unsupported, err),
+    })?;
+
+    if let Some(session) = &session {
+        if !agreement.capabilities.contains(hello::Capabilities::AUTHENTICATED_FRAMES) {
+            return Err(refusal(handshake::Error::UnauthenticatedFrames));
+        }
+        handshake::confirm(&mut stream, session, handshake::Role::Proxy, &greetings).await.map_err(refusal)?;
+    }
+
+    tracing::info!(
+        "mirror runs {} with protocol version {}, capabilities: {}",
+        agreement.peer_software, agreement.version, agreement.capabilities,
+    );
+
+    let (sent, received) = session.map(|session| session.frame_macs(handshake::Role::Proxy, &greetings)).unzip();
+    let (rx, tx) = io::split(stream);
+    let codec = Codec::agreed(agreement.capabilities);
+    Ok((Box::new(rx), received, TcpSender::new(tx).with_compression(codec).with_authentication(sent), agreement))
+}
+
+/// wait until the proxy is told to shut down
+async fn terminated(mut running: watch::Receiver<bool>) {
+    while *running.borrow_and_update() {
+        if running.changed().await.is_err() {
+            // nothing is left to tell the proxy to shut down
+            return futures::future::pending().await;
+        }
+    }
+}
+
+async fn run_connection(config: &ServiceConfig, end_point: &transport::EndPoint, tls: Option<&LinkTls>, status: &LinkStatus, running: watch::Receiver<bool>) -> Result<(), (bool,io::Error)> {
+
+    let socket = end_point.connect().await.map_err(|e|(false,e))?;
+
+    run_link(config, socket, end_point, tls, status, running).await
+}
+
+/// serve the mirror over a connected link until it is lost or the proxy
+/// terminates
+async fn run_link(config: &ServiceConfig, socket: Box<dyn transport::Stream>, peer: impl std::fmt::Display, tls: Option<&LinkTls>, status: &LinkStatus, running: watch::Receiver<bool>) -> Result<(), (bool,io::Error)> {
 
     let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
 
-    let (rx_end_point, tx_end_point) = TcpStream::connect(end_point_id).await.map_err(|e|(false,e))?.into_split();
-    let (tx_channel, rx_channel) = mpsc::channel(TX_QUEUE_LENGTH);
+    let established = async {
+        match tls {
+            Some((connector, server_name)) => {
+                let stream = connector.connect(server_name.clone(), socket).await?;
+                establish(stream, config.link_key.as_deref(), config.link_compression).await
+            },
+            None => establish(socket, config.link_key.as_deref(), config.link_compression).await,
+        }
+    };
 
-    let rx_process_fut = rx_process(rx_end_point.into(), tx_channel, client, base_url);
-    let tx_process_fut = TcpSender::mp_process(tx_end_point.into(), rx_channel);
-    let terminated_fut = async { while *running.borrow() { running.changed().await.unwrap(); } Ok(()) };
+    let (rx_end_point, rx_mac, tx_end_point, agreement) = timeout(HANDSHAKE_TIMEOUT, established).await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "the mirror did not complete the handshake in time")))
+        .map_err(|e|(true,e))?;
+
+    let link = Multiplexer::new(agreement.capabilities.contains(hello::Capabilities::FLOW_CONTROL));
+
+    let heartbeat = agreement.capabilities.contains(hello::Capabilities::HEARTBEAT).then(|| {
+        Mutex::new(heartbeat::Heartbeat::new(heartbeat::Config{
+            interval: config.heartbeat_interval,
+            misses: config.heartbeat_misses,
+        }))
+    });
+
+    let compressed = Codec::agreed(agreement.capabilities).is_some();
+    let rx_process_fut = async {
+        if agreement.version == 1 {
+            let max_frame_length = config.link_max_frame_length.unwrap_or(up_stream::Request::MAX_FRAME_LENGTH);
+            let rx_end_point = TcpReceiver::<up_stream::Request>::new(rx_end_point).with_max_frame_length(max_frame_length).with_compression(compressed).with_authentication(rx_mac);
+            rx_process(rx_end_point, link.clone(), client, config, agreement.capabilities, heartbeat.as_ref()).await
+        } else {
+            let max_frame_length = config.link_max_frame_length.unwrap_or(up_stream::Message::MAX_FRAME_LENGTH);
+            let rx_end_point = TcpReceiver::<up_stream::Message>::new(rx_end_point).with_max_frame_length(max_frame_length).with_compression(compressed).with_authentication(rx_mac);
+            rx_process(rx_end_point, link.clone(), client, config, agreement.capabilities, heartbeat.as_ref()).await
+        }
+    };
+    let tx_process_fut = tx_process(tx_end_point, &link);
+    let heartbeat_fut = async {
+        match &heartbeat {
+            Some(heartbeat) => ping_mirror(heartbeat, &link).await,
+            None => futures::future::pending().await,
+        }
+    };
+    let terminated_fut = async { terminated(running).await; Ok(()) };
 
-    pin!{ rx_process_fut, tx_process_fut, terminated_fut };
+    pin!{ rx_process_fut, tx_process_fut, heartbeat_fut, terminated_fut };
 
-    tracing::info!("connection established to: {}", end_point_id);
+    tracing::info!("connection established to: {}", peer);
+    status.connected();
 
-    (select! {
+    let result = select! {
         r = rx_process_fut => r,
         r = tx_process_fut => r,
+        r = heartbeat_fut => r,
         r = terminated_fut => r,
-    })
-    .map_err(|e|(true,e))
+    };
+
+    link.close();
+
+    status.disconnected(result.as_ref().err().map(|err| err as &dyn std::fmt::Display));
+
+    result.map_err(|e|(true,e))
 }
 
-pub async fn run_for_a_while(end_point: SocketAddr, base_url: String, running: watch::Receiver<bool>) {
-    tracing::info!("base crate URL is: {}", base_url);
+/// connect to the mirror, and connect again whenever the link is lost,
+/// backing off while the mirror can't be reached
+async fn dial_for_a_while(config: &ServiceConfig, end_point: &transport::EndPoint, tls: Option<&LinkTls>, status: &LinkStatus, running: watch::Receiver<bool>) {
+
     tracing::info!("attempting connection to: {}", end_point);
 
+    let mut backoff = Backoff::new(Duration::from_secs(config.reconnect_delay), Duration::from_secs(config.reconnect_max_delay));
     let mut show_error = true;
 
     while *running.borrow() {
-        match run_connection(end_point, &base_url, running.clone()).await {
+        status.connecting();
+        let result = select! {
+            r = run_connection(config, end_point, tls, status, running.clone()) => r,
+            _ = terminated(running.clone()) => break,
+        };
+        match result {
             Ok(_) => break,
             Err((did_connect, err)) => {
+                // start over once the link had been established
+                if status.failures() == 0 {
+                    backoff.reset();
+                }
+                let delay = backoff.next_delay();
+                status.backing_off(&err, delay);
                 if show_error || did_connect {
                     tracing::error!("failed connection to {} with: {}", end_point, err);
                     show_error = false;
                 } else {
                     tracing::debug!("failed connection to {} with: {}", end_point, err);
                 }
-                sleep(DOWN_LINK_RETRY_DELAY).await;
+                tracing::debug!("attempting connection to {} in {:?}", end_point, delay);
+                select! {
+                    _ = sleep(delay) => (),
+                    _ = terminated(running.clone()) => break,
+                }
             }
         }
+    }
+}
+
+/// wait for the mirror to connect, serving each link from an allowed address
+/// until it is lost
+async fn listen_for_a_while(config: Arc<ServiceConfig>, local_end_point: SocketAddr, tls: Option<LinkTls>, status: Arc<LinkStatus>, running: watch::Receiver<bool>) {
+
+    let listener = TcpListener::bind(local_end_point).await
+        .unwrap_or_else(|err| panic!("able to listen for the mirror on {}: {}", local_end_point, err));
+
+    tracing::info!("listening for the mirror on: {}", local_end_point);
+
+    let mut links = JoinSet::new();
+
+    loop {
+        let (socket, from) = select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::error!("failed to accept a connection from the mirror: {}", err);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                },
+            },
+            Some(_) = links.join_next() => continue,
+            _ = terminated(running.clone()) => break,
+        };
+
+        if !allowlist::allows(&config.allowed_mirrors, from.ip()) {
+            tracing::warn!(target: "security", "refused connection from {}, it is not an allowed mirror", from);
+            continue;
+        }
+
+        if links.len() >= MAX_LINKS {
+            tracing::warn!(target: "security", "refused connection from {}, already serving {} links", from, MAX_LINKS);
+            continue;
+        }
 
-        tracing::debug!("attempting connection to: {}", end_point);
+        tracing::info!("accepted connection from: {}", from);
+
+        if let Err(err) = socket.set_nodelay(true) {
+            tracing::error!("failed connection from {} with: {}", from, err);
+            continue;
+        }
+
+        let (config, tls, status, running) = (config.clone(), tls.clone(), status.clone(), running.clone());
+        links.spawn(async move {
+            if let Err((_, err)) = run_link(&config, Box::new(socket), from, tls.as_ref(), &status, running).await {
+                tracing::error!("failed connection from {} with: {}", from, err);
+            }
+        });
+    }
+
+    // the links close on their own once the proxy shuts down
+    while links.join_next().await.is_some() {}
+}
+
+async fn run_for_a_while(config: ServiceConfig, running: watch::Receiver<bool>) {
+
+    tracing::info!("base crate URL is: {}", config.crates_io_base_url);
+    tracing::info!("sparse index URL is: {}", config.crates_io_index_url);
+
+    let tls = config.tls_ca.as_ref().map(|ca| {
+        let identity = config.tls_cert.as_deref().zip(config.tls_key.as_deref());
+        let connector = tls::connector(ca, identity)
+            .unwrap_or_else(|err| panic!("usable TLS configuration for the mirror link: {}", err));
+        let server_name = config.tls_server_name.as_deref().or_else(|| config.mirror_end_point.as_ref().and_then(transport::EndPoint::host))
+            .unwrap_or_else(|| panic!("`CPM_LINK_TLS_SERVER_NAME` to name the mirror's certificate, as its end point has no host name"));
+        let server_name = tls::server_name(server_name)
+            .unwrap_or_else(|err| panic!("legal TLS server name '{}': {}", server_name, err));
+        tracing::info!("securing the mirror link with TLS");
+        (connector, server_name)
+    });
+
+    if config.link_key.as_ref().is_some_and(String::is_empty) {
+        panic!("a non-empty value for `CPM_LINK_KEY`");
+    }
+
+    if config.link_key.is_none() && tls.is_none() {
+        if !config.link_insecure {
+            panic!("`CPM_LINK_KEY` or `CPM_LINK_TLS_CA` to authenticate the mirror, or `--link-insecure` to serve any host connecting as one");
+        }
+        tracing::warn!("neither `CPM_LINK_KEY` nor `CPM_LINK_TLS_CA` are set, the mirror will not be authenticated");
+    }
+
+    let status = match (&config.mirror_end_point, config.link_local_end_point) {
+        (Some(end_point), _) => {
+            let status = LinkStatus::new(end_point, false, config.status_file.clone());
+            dial_for_a_while(&config, end_point, tls.as_ref(), &status, running).await;
+            Arc::new(status)
+        },
+        (None, Some(local_end_point)) => {
+            let status = Arc::new(LinkStatus::new(local_end_point, true, config.status_file.clone()));
+            listen_for_a_while(Arc::new(config), local_end_point, tls, status.clone(), running).await;
+            status
+        },
+        (None, None) => unreachable!("either the mirror's end point or the one to listen on is required"),
+    };
+
+    status.stopped();
+    tracing::info!("shut down");
+}
+
+/// wait for the proxy to be asked to shut down by SIGTERM or Ctrl-C
+async fn shutdown_requested() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("able to handle SIGTERM");
+        select! {
+            _ = sigterm.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
 
 fn run_forever(config: ServiceConfig) {
-    tracing_subscriber::fmt::init();
-    let (_set_running,running) = tokio::sync::watch::channel(true);
+    let (set_running,running) = tokio::sync::watch::channel(true);
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(run_for_a_while(config.mirror_end_point, config.crates_io_base_url, running))
+        .block_on(async move {
+            tokio::spawn(async move {
+                shutdown_requested().await;
+                tracing::info!("shutting down");
+                let _ = set_running.send(false);
+            });
+            run_for_a_while(config, running).await
+        })
 }
 
 fn main() {
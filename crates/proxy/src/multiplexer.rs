@@ -0,0 +1,378 @@
+//! sharing of the link to the mirror between download sessions
+//!
+//! Each session queues its messages here rather than writing them to the link
+//! directly. The link takes turns between the sessions with something to
+//! send, taking at most [flow::MAX_CHUNK_LENGTH] bytes of content at a time,
+//! and only as much as the mirror granted credit for when it agreed on flow
+//! control. Messages concerning the link itself jump the queue.
+//!
+//! A session that queued too much content waits for the link to catch up
+//! before reading more from the upstream server.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use hyper::body::Bytes;
+use tokio::sync::Notify;
+
+use thiserror::Error;
+use displaydoc::Display;
+
+use common::{down_stream, flow};
+
+/// The bytes of content a session may queue before waiting for the link.
+const MAX_QUEUED: usize = 4 * flow::MAX_CHUNK_LENGTH;
+
+/// The link to the mirror closed, so the session can't be sent.
+#[derive(Error, Display, Debug)]
+pub struct Closed;
+
+/// Something a session queued to send.
+enum Item {
+    Content(Bytes),
+    Opcode(down_stream::Opcode),
+}
+
+/// The messages a session has yet to send.
+struct Queue {
+    items: VecDeque<Item>,
+    /// bytes of content queued
+    queued: usize,
+    /// bytes of content the mirror will accept, unlimited without flow control
+    credit: Option<usize>,
+    /// wakes the session once content has been taken from the queue
+    drained: Arc<Notify>,
+}
+
+impl Queue {
+
+    /// take the next message to send, unless it is content the session has
+    /// no credit for
+    ///
+    /// Content queued in small pieces is sent in chunks as large as allowed.
+    fn take(&mut self) -> Option<down_stream::Opcode> {
+        match self.items.pop_front()? {
+            Item::Opcode(opcode) => return Some(opcode),
+            content => self.items.push_front(content),
+        }
+        let limit = flow::MAX_CHUNK_LENGTH.min(self.credit.unwrap_or(usize::MAX));
+        if limit == 0 {
+            return None;
+        }
+        let mut chunk = Vec::with_capacity(limit.min(self.queued));
+        while let Some(Item::Content(content)) = self.items.front_mut() {
+            let wanted = limit - chunk.len();
+            if content.len() > wanted {
+                chunk.extend_from_slice(&content.split_to(wanted));
+                break;
+            }
+            chunk.extend_from_slice(content);
+            self.items.pop_front();
+        }
+        self.queued -= chunk.len();
+        if let Some(credit) = &mut self.credit {
+            *credit -= chunk.len();
+        }
+        self.drained.notify_one();
+        Some(down_stream::Opcode::Chunk(chunk.into()))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// messages concerning the link itself
+    urgent: VecDeque<down_stream::Message>,
+    queues: HashMap<u32, Queue>,
+    /// the sessions in the order they take turns
+    turns: VecDeque<u32>,
+    closed: bool,
+}
+
+/// The messages waiting to be sent to the mirror.
+pub struct Multiplexer {
+    state: Mutex<State>,
+    /// limit the content in flight per session
+    flow_control: bool,
+    /// wakes the link once there is something to send
+    ready: Notify,
+}
+
+impl Multiplexer {
+
+    pub fn new(flow_control: bool) -> Arc<Self> {
+        Arc::new(Self{ state: Default::default(), flow_control, ready: Notify::new() })
+    }
+
+    /// begin queueing the messages of a session
+    pub fn open(&self, session_id: u32) {
+        let credit = self.flow_control.then_some(flow::INITIAL_WINDOW as usize);
+        let queue = Queue{ items: VecDeque::new(), queued: 0, credit, drained: Arc::new(Notify::new()) };
+        let mut state = self.state.lock().unwrap();
+        if state.queues.insert(session_id, queue).is_none() {
+            state.turns.push_back(session_id);
+        }
+    }
+
+    /// drop the messages a cancelled session has yet to send
+    pub fn cancel(&self, session_id: u32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(queue) = state.queues.remove(&session_id) {
+            queue.drained.notify_one();
+            state.turns.retain(|id| *id != session_id);
+        }
+    }
+
+    /// allow a session to send more content
+    pub fn grant(&self, session_id: u32, bytes: u32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(credit) = state.queues.get_mut(&session_id).and_then(|queue| queue.credit.as_mut()) {
+            *credit += bytes as usize;
+            self.ready.notify_one();
+        }
+    }
+
+    /// queue a message concerning the link itself, ahead of the sessions
+    pub fn send_urgent(&self, opcode: down_stream::Opcode) -> Result<(), Closed> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        state.urgent.push_back(down_stream::Message{ session_id: down_stream::LINK_SESSION, opcode });
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// queue a message of a session
+    pub fn send(&self, session_id: u32, opcode: down_stream::Opcode) -> Result<(), Closed> {
+        self.push(session_id, Item::Opcode(opcode)).map(|_| ())
+    }
+
+    /// queue content of a session, once the link has caught up with the
+    /// content queued before
+    pub async fn send_content(&self, session_id: u32, content: Bytes) -> Result<(), Closed> {
+        // nothing would be sent for it but an empty chunk, which could hold up
+        // the session's next message waiting for credit
+        if content.is_empty() {
+            return Ok(());
+        }
+        let mut item = Item::Content(content);
+        loop {
+            match self.push(session_id, item)? {
+                None => return Ok(()),
+                Some((rejected, drained)) => {
+                    item = rejected;
+                    drained.notified().await;
+                },
+            }
+        }
+    }
+
+    /// queue an item for a session, handing it back along with what to wait
+    /// for if the session's queue is full
+    fn push(&self, session_id: u32, item: Item) -> Result<Option<(Item, Arc<Notify>)>, Closed> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        let queue = state.queues.get_mut(&session_id).ok_or(Closed)?;
+        if let Item::Content(content) = &item {
+            if queue.queued >= MAX_QUEUED {
+                return Ok(Some((item, queue.drained.clone())));
+            }
+            queue.queued += content.len();
+        }
+        queue.items.push_back(item);
+        self.ready.notify_one();
+        Ok(None)
+    }
+
+    /// wait for the next message to send to the mirror
+    pub async fn next(&self) -> down_stream::Message {
+        loop {
+            if let Some(message) = self.take() {
+                return message;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// take the next message of the session whose turn it is
+    fn take(&self) -> Option<down_stream::Message> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(message) = state.urgent.pop_front() {
+            return Some(message);
+        }
+        for _ in 0..state.turns.len() {
+            let session_id = state.turns.pop_front()?;
+            let opcode = state.queues.get_mut(&session_id).and_then(Queue::take);
+            match opcode {
+                Some(opcode @ down_stream::Opcode::Complete(_)) => {
+                    state.queues.remove(&session_id);
+                    return Some(down_stream::Message{ session_id, opcode });
+                },
+                Some(opcode) => {
+                    state.turns.push_back(session_id);
+                    return Some(down_stream::Message{ session_id, opcode });
+                },
+                None => state.turns.push_back(session_id),
+            }
+        }
+        None
+    }
+
+    /// refuse further messages once the link is gone, waking the sessions
+    /// waiting for it
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for queue in state.queues.values() {
+            queue.drained.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use down_stream::Opcode::{Chunk, Complete, Ping};
+
+    /// a session's message, as its session id and either the length of its
+    /// content or the name of its opcode
+    fn describe(message: down_stream::Message) -> (u32, String) {
+        let what = match message.opcode {
+            Chunk(buffer) => buffer.as_ref().len().to_string(),
+            Complete(Ok(())) => "complete".into(),
+            opcode => format!("{:?}", opcode),
+        };
+        (message.session_id, what)
+    }
+
+    fn take_all(link: &Multiplexer) -> Vec<(u32, String)> {
+        std::iter::from_fn(|| link.take()).map(describe).collect()
+    }
+
+    fn sent(messages: &[(u32, &str)]) -> Vec<(u32, String)> {
+        messages.iter().map(|(session_id, what)| (*session_id, what.to_string())).collect()
+    }
+
+    fn set_credit(link: &Multiplexer, session_id: u32, credit: usize) {
+        link.state.lock().unwrap().queues.get_mut(&session_id).unwrap().credit = Some(credit);
+    }
+
+    const MAX: usize = flow::MAX_CHUNK_LENGTH;
+
+    #[tokio::test]
+    async fn sessions_take_turns() {
+        let link = Multiplexer::new(false);
+        link.open(1);
+        link.open(2);
+        link.send_content(1, vec![1; 2 * MAX].into()).await.unwrap();
+        link.send(1, Complete(Ok(()))).unwrap();
+        link.send_content(2, vec![2; 3 * MAX].into()).await.unwrap();
+        link.send(2, Complete(Ok(()))).unwrap();
+
+        let max = MAX.to_string();
+        assert_eq!(take_all(&link), sent(&[
+            (1, &max), (2, &max),
+            (1, &max), (2, &max),
+            (1, "complete"), (2, &max),
+            (2, "complete"),
+        ]));
+        assert!(link.state.lock().unwrap().queues.is_empty());
+    }
+
+    #[tokio::test]
+    async fn content_is_sent_in_chunks_of_at_most_the_largest_length() {
+        let link = Multiplexer::new(false);
+        link.open(1);
+        let pieces: Vec<Vec<u8>> = vec![vec![1; MAX + 10], vec![2; 5], vec![3; MAX]];
+        for piece in &pieces {
+            link.send_content(1, piece.clone().into()).await.unwrap();
+        }
+
+        let mut content = Vec::new();
+        let mut lengths = Vec::new();
+        while let Some(message) = link.take() {
+            match message.opcode {
+                Chunk(buffer) => {
+                    lengths.push(buffer.as_ref().len());
+                    content.extend_from_slice(buffer.as_ref());
+                },
+                opcode => panic!("unexpected {:?}", opcode),
+            }
+        }
+        assert_eq!(lengths, [MAX, MAX, 15]);
+        assert_eq!(content, pieces.concat());
+    }
+
+    #[tokio::test]
+    async fn content_waits_for_credit() {
+        let link = Multiplexer::new(true);
+        link.open(1);
+        set_credit(&link, 1, 0);
+        link.send_content(1, vec![0; 100].into()).await.unwrap();
+        link.send(1, Complete(Ok(()))).unwrap();
+
+        // the completion waits behind the content
+        assert_eq!(take_all(&link), sent(&[]));
+
+        link.grant(1, 60);
+        assert_eq!(take_all(&link), sent(&[(1, "60")]));
+
+        // and goes once the content is drained, the credit used up exactly
+        link.grant(1, 40);
+        assert_eq!(take_all(&link), sent(&[(1, "40"), (1, "complete")]));
+    }
+
+    #[tokio::test]
+    async fn messages_without_content_need_no_credit() {
+        let link = Multiplexer::new(true);
+        link.open(1);
+        set_credit(&link, 1, 0);
+        link.send_content(1, Bytes::new()).await.unwrap();
+        link.send(1, Complete(Ok(()))).unwrap();
+        assert_eq!(take_all(&link), sent(&[(1, "complete")]));
+    }
+
+    #[tokio::test]
+    async fn urgent_messages_jump_the_queue() {
+        let link = Multiplexer::new(false);
+        link.open(1);
+        link.send_content(1, vec![0; 2 * MAX].into()).await.unwrap();
+        assert_eq!(describe(link.take().unwrap()), (1, MAX.to_string()));
+
+        link.send_urgent(Ping(7)).unwrap();
+        link.send_urgent(Ping(8)).unwrap();
+        assert_eq!(take_all(&link), sent(&[
+            (down_stream::LINK_SESSION, "Ping(7)"),
+            (down_stream::LINK_SESSION, "Ping(8)"),
+            (1, &MAX.to_string()),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn cancelled_sessions_send_nothing_more() {
+        let link = Multiplexer::new(false);
+        link.open(1);
+        link.open(2);
+        link.send_content(1, vec![0; 10].into()).await.unwrap();
+        link.send_content(2, vec![0; 20].into()).await.unwrap();
+        link.cancel(1);
+        assert_eq!(take_all(&link), sent(&[(2, "20")]));
+        assert!(link.send(1, Complete(Ok(()))).is_err());
+    }
+
+    #[tokio::test]
+    async fn closing_refuses_further_messages() {
+        let link = Multiplexer::new(false);
+        link.open(1);
+        link.close();
+        assert!(link.send(1, Complete(Ok(()))).is_err());
+        assert!(link.send_content(1, vec![0; 10].into()).await.is_err());
+        assert!(link.send_urgent(Ping(1)).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! which redirects from the upstream server are followed
+//!
+//! crates.io answers download requests with a redirect to its CDN. Only
+//! redirects over HTTPS to the hosts in `CPM_CRATES_IO_REDIRECT_HOSTS` are
+//! followed, and only `CPM_CRATES_IO_MAX_REDIRECTS` of them per download, so a
+//! compromised or misconfigured upstream server can neither send the proxy to
+//! arbitrary hosts nor keep it going around in circles. A relative `Location`
+//! is resolved against the URL that was redirected before being checked.
+
+use hyper::http::{uri::Scheme, Uri};
+
+use thiserror::Error;
+use displaydoc::Display;
+
+/// A reason a redirect was not followed.
+#[derive(Error, Display, Debug)]
+pub enum Violation {
+    /// The redirect to '{0}' is not over HTTPS
+    Insecure(Uri),
+    /// The redirect to '{0}' is not to an allowed host
+    HostNotAllowed(Uri),
+    /// More than {0} redirects
+    TooMany(usize),
+}
+
+/// resolve a `Location` relative to `base`, the URL that was redirected
+pub fn resolve(base: &Uri, location: &str) -> Option<Uri> {
+    let resolved = if location.starts_with("//") {
+        format!("{}:{}", base.scheme_str()?, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", base.scheme_str()?, base.authority()?, location)
+    } else if location.starts_with('?') {
+        format!("{}://{}{}{}", base.scheme_str()?, base.authority()?, base.path(), location)
+    } else {
+        match location.parse::<Uri>() {
+            Ok(uri) if uri.scheme().is_some() => return Some(uri),
+            _ => {
+                let directory = &base.path()[..=base.path().rfind('/')?];
+                format!("{}://{}{}{}", base.scheme_str()?, base.authority()?, directory, location)
+            },
+        }
+    };
+    resolved.parse().ok()
+}
+
+/// The redirects that may be followed.
+#[derive(Debug)]
+pub struct Policy {
+    hosts: Vec<String>,
+    max_redirects: usize,
+}
+
+impl Policy {
+
+    pub fn new(hosts: &[String], max_redirects: usize) -> Self {
+        let hosts = hosts.iter().map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase()).collect();
+        Self{ hosts, max_redirects }
+    }
+
+    /// check the `count`th redirect of a download, to `location`
+    pub fn check(&self, location: &Uri, count: usize) -> Result<(), Violation> {
+        if count > self.max_redirects {
+            return Err(Violation::TooMany(self.max_redirects));
+        }
+        if location.scheme() != Some(&Scheme::HTTPS) {
+            return Err(Violation::Insecure(location.clone()));
+        }
+        let host = location.host().unwrap_or_default().trim_end_matches('.');
+        if !self.hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+            return Err(Violation::HostNotAllowed(location.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn policy(max_redirects: usize) -> Policy {
+        Policy::new(&["crates.io".into(), " Static.Crates.IO. ".into()], max_redirects)
+    }
+
+    fn check(policy: &Policy, location: &str, count: usize) -> Result<(), Violation> {
+        policy.check(&location.parse().unwrap(), count)
+    }
+
+    #[test]
+    fn follows_https_redirects_to_allowed_hosts() {
+        let policy = policy(5);
+        assert!(check(&policy, "https://static.crates.io/crates/log/log-0.4.14.crate", 1).is_ok());
+        assert!(check(&policy, "https://crates.io:443/api/v1/crates/log/0.4.14/download", 1).is_ok());
+    }
+
+    #[test]
+    fn follows_up_to_the_most_redirects() {
+        let policy = policy(2);
+        let location = "https://static.crates.io/crates/log/log-0.4.14.crate";
+        assert!(check(&policy, location, 2).is_ok());
+        assert!(matches!(check(&policy, location, 3), Err(Violation::TooMany(2))));
+        assert!(matches!(check(&Policy::new(&["crates.io".into()], 0), "https://crates.io/", 1), Err(Violation::TooMany(0))));
+    }
+
+    #[test]
+    fn refuses_redirects_not_over_https() {
+        let policy = policy(5);
+        assert!(matches!(check(&policy, "http://static.crates.io/crates/log/log-0.4.14.crate", 1), Err(Violation::Insecure(_))));
+        assert!(matches!(check(&policy, "ftp://static.crates.io/log.crate", 1), Err(Violation::Insecure(_))));
+    }
+
+    fn resolve(base: &str, location: &str) -> Option<String> {
+        super::resolve(&base.parse().unwrap(), location).map(|uri| uri.to_string())
+    }
+
+    #[test]
+    fn resolves_relative_locations() {
+        let base = "https://crates.io/api/v1/crates/log/0.4.14/download";
+        assert_eq!(resolve(base, "/crates/log/log-0.4.14.crate").as_deref(), Some("https://crates.io/crates/log/log-0.4.14.crate"));
+        assert_eq!(resolve(base, "log-0.4.14.crate").as_deref(), Some("https://crates.io/api/v1/crates/log/0.4.14/log-0.4.14.crate"));
+        assert_eq!(resolve(base, "?mirror=1").as_deref(), Some("https://crates.io/api/v1/crates/log/0.4.14/download?mirror=1"));
+        assert_eq!(resolve(base, "//static.crates.io/log.crate").as_deref(), Some("https://static.crates.io/log.crate"));
+        assert_eq!(resolve(base, "https://static.crates.io/log.crate").as_deref(), Some("https://static.crates.io/log.crate"));
+        assert_eq!(resolve("http://crates.io/download", "/log.crate").as_deref(), Some("http://crates.io/log.crate"));
+        assert_eq!(resolve(base, "/log crate"), None);
+    }
+
+    #[test]
+    fn checks_relative_locations_once_resolved() {
+        let policy = policy(5);
+        let location = super::resolve(&"https://crates.io/api/v1/crates/log/0.4.14/download".parse().unwrap(), "/crates/log/log-0.4.14.crate").unwrap();
+        assert!(policy.check(&location, 1).is_ok());
+        let location = super::resolve(&"https://crates.io/download".parse().unwrap(), "//evil.example.com/log.crate").unwrap();
+        assert!(matches!(policy.check(&location, 1), Err(Violation::HostNotAllowed(_))));
+        let location = super::resolve(&"http://crates.io/download".parse().unwrap(), "/log.crate").unwrap();
+        assert!(matches!(policy.check(&location, 1), Err(Violation::Insecure(_))));
+    }
+
+    #[test]
+    fn compares_hosts_regardless_of_case_or_a_trailing_dot() {
+        let policy = policy(5);
+        assert!(check(&policy, "https://STATIC.crates.io/log.crate", 1).is_ok());
+        assert!(check(&policy, "https://static.crates.io./log.crate", 1).is_ok());
+        assert!(check(&policy, "https://Crates.IO./log.crate", 1).is_ok());
+    }
+
+    #[test]
+    fn refuses_other_hosts() {
+        let policy = policy(5);
+        for location in &[
+            "https://evil.example.com/log.crate",
+            "https://static.crates.io.evil.example.com/log.crate",
+            "https://evilcrates.io/log.crate",
+            "https://sub.crates.io/log.crate",
+            "https://crates.io@evil.example.com/log.crate",
+            "https://127.0.0.1/log.crate",
+        ] {
+            assert!(matches!(check(&policy, location, 1), Err(Violation::HostNotAllowed(_))), "{}", location);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! reporting the state of the link
+//!
+//! The proxy keeps track of whether it is connecting to the mirror, connected,
+//! or backing off before trying again, along with when that started. If
+//! `CPM_LINK_STATUS_FILE` is set, the state is written to it as JSON whenever
+//! it changes, for monitoring. Times are in seconds since the UNIX epoch.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// The state of the link.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum State {
+    Connecting,
+    Connected,
+    /// waiting before connecting again
+    BackingOff,
+    /// waiting for the mirror to connect
+    Listening,
+    /// the proxy shut down
+    Stopped,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    state: State,
+    /// when the link entered its state
+    since: u64,
+    /// where the mirror is reached, or listened for
+    mirror: String,
+    /// the links established, when listening for the mirror
+    links: usize,
+    /// when the next attempt to connect is due, while backing off
+    next_attempt: Option<u64>,
+    /// the failed attempts since the link was last established
+    failures: u32,
+    last_error: Option<String>,
+    /// when the link was last established
+    last_connected: Option<u64>,
+    #[serde(skip)]
+    listening: bool,
+}
+
+/// Tracks the state of the link, writing it to the status file if any.
+pub struct LinkStatus {
+    report: Mutex<Report>,
+    path: Option<PathBuf>,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl Report {
+
+    fn enter(&mut self, state: State) {
+        if self.state != state {
+            self.state = state;
+            self.since = seconds(SystemTime::now());
+        }
+        if state != State::BackingOff {
+            self.next_attempt = None;
+        }
+    }
+}
+
+impl LinkStatus {
+
+    /// the status of a link to the mirror at `mirror`, or listened for on it
+    pub fn new(mirror: impl fmt::Display, listening: bool, path: Option<PathBuf>) -> Self {
+        let report = Report{
+            state: if listening { State::Listening } else { State::Connecting },
+            since: seconds(SystemTime::now()),
+            mirror: mirror.to_string(),
+            links: 0,
+            next_attempt: None,
+            failures: 0,
+            last_error: None,
+            last_connected: None,
+            listening,
+        };
+        let status = Self{ report: Mutex::new(report), path };
+        status.update(|_| ());
+        status
+    }
+
+    /// the failed attempts since the link was last established
+    pub fn failures(&self) -> u32 {
+        self.report.lock().unwrap().failures
+    }
+
+    pub fn connecting(&self) {
+        self.update(|report| report.enter(State::Connecting));
+    }
+
+    pub fn connected(&self) {
+        self.update(|report| {
+            report.enter(State::Connected);
+            report.links += 1;
+            report.failures = 0;
+            report.last_connected = Some(report.since);
+        });
+    }
+
+    /// a link was lost, or closed as the proxy shut down
+    pub fn disconnected(&self, error: Option<&dyn fmt::Display>) {
+        self.update(|report| {
+            report.links = report.links.saturating_sub(1);
+            if let Some(error) = error {
+                report.last_error = Some(error.to_string());
+            }
+            if report.links == 0 && report.listening {
+                report.enter(State::Listening);
+            }
+        });
+    }
+
+    /// the link failed or was lost, and will be attempted again after `delay`
+    pub fn backing_off(&self, error: &dyn fmt::Display, delay: Duration) {
+        self.update(|report| {
+            report.enter(State::BackingOff);
+            report.links = 0;
+            report.failures += 1;
+            report.last_error = Some(error.to_string());
+            report.next_attempt = Some(seconds(SystemTime::now() + delay));
+        });
+    }
+
+    pub fn stopped(&self) {
+        self.update(|report| {
+            report.enter(State::Stopped);
+            report.links = 0;
+        });
+    }
+
+    /// apply a change to the report, then write it out
+    fn update(&self, change: impl FnOnce(&mut Report)) {
+        let report = &mut *self.report.lock().unwrap();
+        change(report);
+        if let Some(path) = &self.path {
+            // replaced as a whole so that readers never see it half written
+            let temp_path = path.with_extension("tmp");
+            let result = serde_json::to_vec_pretty(report).map_err(std::io::Error::from)
+                .and_then(|bytes| std::fs::write(&temp_path, bytes))
+                .and_then(|_| std::fs::rename(&temp_path, path));
+            if let Err(err) = result {
+                tracing::error!("unable to write the link status to {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn state(status: &LinkStatus) -> State {
+        status.report.lock().unwrap().state
+    }
+
+    fn links(status: &LinkStatus) -> usize {
+        status.report.lock().unwrap().links
+    }
+
+    #[test]
+    fn listens_again_once_every_link_is_lost() {
+        let status = LinkStatus::new("127.0.0.1:3908", true, None);
+        assert_eq!(state(&status), State::Listening);
+        status.connected();
+        status.connected();
+        assert_eq!((state(&status), links(&status)), (State::Connected, 2));
+        status.disconnected(Some(&"reset by peer"));
+        assert_eq!((state(&status), links(&status)), (State::Connected, 1));
+        status.disconnected(None);
+        assert_eq!((state(&status), links(&status)), (State::Listening, 0));
+        assert_eq!(status.report.lock().unwrap().last_error.as_deref(), Some("reset by peer"));
+    }
+
+    #[test]
+    fn counts_failures_until_connected() {
+        let status = LinkStatus::new("mirror:3908", false, None);
+        for _ in 0..3 {
+            status.connecting();
+            status.backing_off(&"connection refused", Duration::from_secs(1));
+        }
+        assert_eq!(status.failures(), 3);
+        status.connecting();
+        status.connected();
+        assert_eq!(status.failures(), 0);
+        assert!(status.report.lock().unwrap().last_connected.is_some());
+    }
+
+    #[test]
+    fn forgets_the_next_attempt_once_due() {
+        let status = LinkStatus::new("mirror:3908", false, None);
+        status.backing_off(&"connection refused", Duration::from_secs(60));
+        assert_eq!(state(&status), State::BackingOff);
+        assert!(status.report.lock().unwrap().next_attempt.is_some());
+        status.connecting();
+        assert_eq!(state(&status), State::Connecting);
+        assert_eq!(status.report.lock().unwrap().next_attempt, None);
+    }
+}
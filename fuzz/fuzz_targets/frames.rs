@@ -0,0 +1,39 @@
+//! Feeds arbitrary bytes to a receiver of each PDU type, with and without
+//! compression, which must reject what it can't decode rather than panic or
+//! allocate beyond its limit.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde::de::DeserializeOwned;
+
+use common::{TcpReceiver, up_stream, down_stream, cpm_api, limits::FrameLimit};
+
+/// A limit small enough for the fuzzer to find frames exceeding it.
+const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+/// receive frames from `stream` until it ends or one is rejected
+fn receive<T: DeserializeOwned + FrameLimit>(stream: &[u8], compressed: bool) {
+    let mut receiver = TcpReceiver::<T>::new(std::io::Cursor::new(stream.to_vec()))
+        .with_compression(compressed)
+        .with_max_frame_length(MAX_FRAME_LENGTH);
+    futures::executor::block_on(async {
+        while let Ok(Some(_)) = receiver.next().await {}
+    });
+}
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the PDU type and whether frames are compressed
+    let (&selector, stream) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let compressed = selector & 0x80 != 0;
+    match (selector & 0x7f) % 5 {
+        0 => receive::<up_stream::Request>(stream, compressed),
+        1 => receive::<up_stream::Message>(stream, compressed),
+        2 => receive::<down_stream::Message>(stream, compressed),
+        3 => receive::<cpm_api::SendMessage>(stream, compressed),
+        _ => receive::<cpm_api::RecvMessage>(stream, compressed),
+    }
+});
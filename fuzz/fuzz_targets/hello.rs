@@ -0,0 +1,29 @@
+//! Feeds arbitrary bytes to the reader of the greeting that opens the link,
+//! which must reject what it can't decode rather than panic, and negotiates
+//! with what it accepts.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use common::hello::Hello;
+
+fuzz_target!(|data: &[u8]| {
+    futures::executor::block_on(async {
+        let hello = match Hello::read(&mut std::io::Cursor::new(data)).await {
+            Ok(hello) => hello,
+            Err(_) => return,
+        };
+
+        let _ = Hello::local("fuzz").negotiate(&hello);
+
+        // what was accepted is sent back as it was received, unless its
+        // software name grew past the limit when made valid UTF-8
+        let mut written = std::io::Cursor::new(Vec::new());
+        hello.write(&mut written).await.unwrap();
+        let read = Hello::read(&mut std::io::Cursor::new(written.into_inner())).await.unwrap();
+        if hello.software.len() <= u8::MAX as usize {
+            assert_eq!(read, hello);
+        }
+    });
+});